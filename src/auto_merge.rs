@@ -0,0 +1,75 @@
+use std::{
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Weak,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, info, warn};
+
+use crate::{
+    db::{Engine, EngineInner},
+    error::Errors,
+    merge::MergeProgress,
+    options::AutoMergeOptions,
+};
+
+/// 后台自动 merge 的线程
+pub(crate) struct AutoMergeWorker {
+    stop_sender: mpsc::Sender<()>,
+    handle: thread::JoinHandle<()>,
+    /// 退出时取消正在进行的 merge
+    progress: MergeProgress,
+}
+
+impl AutoMergeWorker {
+    /// 启动后台线程，inner 是数据库实例共享数据的弱引用
+    pub(crate) fn start(inner: Weak<EngineInner>, options: AutoMergeOptions) -> Self {
+        let (stop_sender, stop_receiver) = mpsc::channel();
+        let progress = MergeProgress::new();
+        let merge_progress = progress.clone();
+        let handle = thread::spawn(move || {
+            // 收到退出的通知，或者数据库实例已经释放时退出
+            while let Err(RecvTimeoutError::Timeout) =
+                stop_receiver.recv_timeout(options.check_interval)
+            {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                let engine = match inner.upgrade() {
+                    Some(inner) => Engine::from_inner(inner),
+                    None => break,
+                };
+                if !options.in_window(now) || !engine.reach_merge_ratio() {
+                    continue;
+                }
+
+                match engine.merge_with_progress(&merge_progress) {
+                    Ok(()) => info!("auto merge finished"),
+                    Err(Errors::MergeRatioUnreached)
+                    | Err(Errors::MergeInProgress)
+                    | Err(Errors::MergeCancelled) => {}
+                    Err(e) => warn!("auto merge failed: {}", e),
+                }
+            }
+        });
+
+        Self {
+            stop_sender,
+            handle,
+            progress,
+        }
+    }
+
+    /// 通知后台线程退出并等待，正在进行的 merge 会被取消，已经重写完成的文件仍然会生效
+    pub(crate) fn stop(self) {
+        self.progress.cancel();
+        let _ = self.stop_sender.send(());
+        if self.handle.join().is_err() {
+            error!("auto merge worker panicked");
+        }
+    }
+}
@@ -1,13 +1,14 @@
 use bytes::{BufMut, Bytes, BytesMut};
 use parking_lot::Mutex;
 use prost::{decode_length_delimiter, encode_length_delimiter};
-use std::{collections::HashMap, sync::Arc};
+use std::{cmp::Ordering, collections::HashMap, sync::Arc};
 
 use crate::{
-    data::log_record::{LogRecord, LogRecordType},
-    db::Engine,
+    data::log_record::{now_nanos, LogRecord, LogRecordPos, LogRecordType},
+    db::{decode_integer, encode_integer, Engine},
     error::{Errors, Result},
-    options::{IndexType, WriteBatchOptions},
+    iterator::Iterator,
+    options::{IndexType, IteratorMode, IteratorOptions, WriteBatchOptions},
 };
 
 const TXN_FINISHED: &[u8] = "legacy".as_bytes();
@@ -16,24 +17,109 @@ pub(crate) const NON_TRANSCATION_SEQ_NO: usize = 0;
 pub struct WriteBatch<'a> {
     /// 暂存用户写入的数据
     pending_writes: Arc<Mutex<HashMap<Vec<u8>, LogRecord>>>,
+    /// 暂存的整数累加值，提交时基于数据库中最新的值计算，和 pending_writes 中的 key 不重复
+    pending_deltas: Arc<Mutex<HashMap<Vec<u8>, i64>>>,
+    /// 保存点栈，每个保存点记录设置之后被修改的 key 原来暂存的数据
+    savepoints: Arc<Mutex<Vec<UndoLog>>>,
     engine: &'a Engine,
     options: WriteBatchOptions,
 }
 
+/// 回滚到保存点需要恢复的数据，分别是 key 原来暂存的数据和累加值，None 表示没有暂存
+type UndoLog = Vec<(Vec<u8>, Option<LogRecord>, Option<i64>)>;
+
 impl Engine {
     /// 初始化 WriteBatch
     pub fn new_write_batch(&self, options: WriteBatchOptions) -> Result<WriteBatch> {
+        self.check_seq_no_available()?;
+        Ok(WriteBatch {
+            pending_writes: Arc::new(Mutex::new(HashMap::new())),
+            pending_deltas: Arc::new(Mutex::new(HashMap::new())),
+            savepoints: Arc::new(Mutex::new(Vec::new())),
+            engine: self,
+            options,
+        })
+    }
+
+    /// B+ 树索引需要从 seq-no 文件中恢复事务序列号，文件不存在时不能使用事务
+    pub(crate) fn check_seq_no_available(&self) -> Result<()> {
         if self.options.index_type == IndexType::BPlusTree
             && !self.seq_file_exists
             && !self.is_initial
         {
             return Err(Errors::UnableToUserWriteBatch);
         }
-        Ok(WriteBatch {
-            pending_writes: Arc::new(Mutex::new(HashMap::new())),
-            engine: self,
-            options,
-        })
+        Ok(())
+    }
+
+    /// 将暂存的数据作为一个事务写入数据文件并更新内存索引，调用方需要持有写锁
+    pub(crate) fn commit_pending_writes(
+        &self,
+        pending_writes: &HashMap<Vec<u8>, LogRecord>,
+        sync_writes: bool,
+    ) -> Result<()> {
+        // 获取全局事务序列号
+        let seq_no = self
+            .seq_no
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+        let mut positions = HashMap::new();
+
+        // 开始写数据到数据文件当中
+        for (_, item) in pending_writes.iter() {
+            let mut record = LogRecord {
+                key: log_record_key_with_seq(item.key.clone(), seq_no),
+                value: item.value.clone(),
+                rec_type: item.rec_type,
+                expire: item.expire,
+                keyspace: item.keyspace.clone(),
+            };
+
+            let pos = self.append_log_record(&mut record)?;
+            positions.insert(item.key.clone(), pos);
+        }
+
+        // 写最后一条标识事务完成的数据
+        let mut finish_record = LogRecord {
+            key: log_record_key_with_seq(TXN_FINISHED.to_vec(), seq_no),
+            value: Default::default(),
+            rec_type: LogRecordType::Txnfinished,
+            expire: 0,
+            keyspace: Default::default(),
+        };
+
+        let finish_pos = self.append_log_record(&mut finish_record)?;
+        self.add_reclaim_size(&finish_pos);
+
+        // 如果配置了持久化，则 sync
+        if sync_writes {
+            self.sync()?;
+        }
+
+        // 数据全部写完之后更新内存索引，同一个事务中的数据使用相同的写入版本号
+        self.save_snapshot_versions(&[], pending_writes.keys().map(Vec::as_slice));
+        let version = self.record_writes(&[], pending_writes.keys().map(Vec::as_slice));
+        for (_, item) in pending_writes.iter() {
+            if item.rec_type == LogRecordType::Normal {
+                let record_pos = LogRecordPos {
+                    version,
+                    ..*positions.get(&item.key).unwrap()
+                };
+                if let Some(old_pos) = self.index.put(item.key.clone(), record_pos) {
+                    self.add_reclaim_size(&old_pos);
+                }
+            }
+            if item.rec_type == LogRecordType::Deleted {
+                // 删除标识本身也是可以回收的空间
+                let record_pos = positions.get(&item.key).unwrap();
+                self.add_reclaim_size(record_pos);
+                if let Some(old_pos) = self.index.delete(item.key.clone()) {
+                    self.add_reclaim_size(&old_pos);
+                }
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -49,9 +135,14 @@ impl WriteBatch<'_> {
             key: key.to_vec(),
             value: value.to_vec(),
             rec_type: LogRecordType::Normal,
+            expire: 0,
+            keyspace: Default::default(),
         };
 
         let mut pending_writes = self.pending_writes.lock();
+        let mut pending_deltas = self.pending_deltas.lock();
+        self.save_undo(&pending_writes, &pending_deltas, &key);
+        pending_deltas.remove(&key.to_vec());
         pending_writes.insert(key.to_vec(), record);
         Ok(())
     }
@@ -63,12 +154,17 @@ impl WriteBatch<'_> {
         }
 
         let mut pending_writes = self.pending_writes.lock();
+        let mut pending_deltas = self.pending_deltas.lock();
         // 如果数据不存在直接返回
         let index_pos = self.engine.index.get(key.to_vec());
         if index_pos.is_none() {
             // 如果暂存文件中有的话，删除
-            if pending_writes.contains_key(&key.to_vec()) {
+            if pending_writes.contains_key(&key.to_vec())
+                || pending_deltas.contains_key(&key.to_vec())
+            {
+                self.save_undo(&pending_writes, &pending_deltas, &key);
                 pending_writes.remove(&key.to_vec());
+                pending_deltas.remove(&key.to_vec());
             }
             return Ok(());
         }
@@ -78,83 +174,299 @@ impl WriteBatch<'_> {
             key: key.to_vec(),
             value: Default::default(),
             rec_type: LogRecordType::Deleted,
+            expire: 0,
+            keyspace: Default::default(),
         };
 
+        self.save_undo(&pending_writes, &pending_deltas, &key);
+        pending_deltas.remove(&key.to_vec());
         pending_writes.insert(key.to_vec(), record);
         Ok(())
     }
 
-    pub fn commit(&self) -> Result<()> {
-        let mut pending_writes = self.pending_writes.lock();
-        if pending_writes.len() == 0 {
-            return Ok(());
+    /// 批量操作中对整数进行累加，返回累加之后的值
+    /// 暂存了写入或删除的 key 基于暂存的数据累加，否则只暂存累加值，提交时基于数据库中最新的值计算
+    /// 这时返回的是基于数据库中当前的值计算的结果，提交之前其他的写入可能会改变最终的值
+    pub fn incr_by(&self, key: Bytes, delta: i64) -> Result<i64> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
         }
-        if pending_writes.len() > self.options.max_batch_num {
-            return Err(Errors::ExceedMaxBatchNum);
+
+        let mut pending_writes = self.pending_writes.lock();
+        let mut pending_deltas = self.pending_deltas.lock();
+        if let Some(record) = pending_writes.get(&key.to_vec()) {
+            let (current, expire) = match record.rec_type {
+                LogRecordType::Normal => (decode_integer(&record.value)?, record.expire),
+                _ => (0, 0),
+            };
+            let new_value = current.checked_add(delta).ok_or(Errors::IntegerOverflow)?;
+
+            // 暂存数据
+            let record = LogRecord {
+                key: key.to_vec(),
+                value: encode_integer(new_value).to_vec(),
+                rec_type: LogRecordType::Normal,
+                expire,
+                keyspace: Default::default(),
+            };
+            self.save_undo(&pending_writes, &pending_deltas, &key);
+            pending_writes.insert(key.to_vec(), record);
+            return Ok(new_value);
         }
 
-        // 加锁保证事务提交串行化
-        let _lock = self.engine.batch_commit_lock.lock();
+        // 暂存累加值
+        let total = pending_deltas
+            .get(&key.to_vec())
+            .map_or(Some(delta), |pending| pending.checked_add(delta))
+            .ok_or(Errors::IntegerOverflow)?;
+        let new_value = self.resolve_delta(&key, total)?;
+        self.save_undo(&pending_writes, &pending_deltas, &key);
+        pending_deltas.insert(key.to_vec(), total);
+        Ok(new_value)
+    }
 
-        // 获取全局事务序列号
-        let seq_no = self
-            .engine
-            .seq_no
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    /// 基于数据库中当前的值计算累加之后的值，key 不存在时视为 0
+    fn resolve_delta(&self, key: &Bytes, delta: i64) -> Result<i64> {
+        let current = match self.engine.get(key.clone()) {
+            Ok(value) => decode_integer(&value)?,
+            Err(Errors::KeyNotFound) => 0,
+            Err(e) => return Err(e),
+        };
+        current.checked_add(delta).ok_or(Errors::IntegerOverflow)
+    }
 
-        let mut positions = HashMap::new();
+    /// 设置保存点，之后可以通过 rollback_to_savepoint 撤销保存点之后的修改
+    pub fn set_savepoint(&self) {
+        let mut savepoints = self.savepoints.lock();
+        savepoints.push(Vec::new());
+    }
 
-        // 开始写数据到数据文件当中
-        for (_, item) in pending_writes.iter() {
-            let mut record = LogRecord {
-                key: log_record_key_with_seq(item.key.clone(), seq_no),
-                value: item.value.clone(),
-                rec_type: item.rec_type,
+    /// 撤销最近一个保存点之后暂存的修改，并移除这个保存点
+    pub fn rollback_to_savepoint(&self) -> Result<()> {
+        let mut pending_writes = self.pending_writes.lock();
+        let mut pending_deltas = self.pending_deltas.lock();
+        let mut savepoints = self.savepoints.lock();
+        let undo_log = savepoints.pop().ok_or(Errors::NoSavepoint)?;
+
+        // 按照修改的逆序恢复
+        for (key, record, delta) in undo_log.into_iter().rev() {
+            match record {
+                Some(record) => pending_writes.insert(key.clone(), record),
+                None => pending_writes.remove(&key),
+            };
+            match delta {
+                Some(delta) => pending_deltas.insert(key, delta),
+                None => pending_deltas.remove(&key),
             };
-
-            let pos = self.engine.append_log_record(&mut record)?;
-            positions.insert(item.key.clone(), pos);
         }
+        Ok(())
+    }
 
-        // 写最后一条标识事务完成的数据
-        let mut finish_record = LogRecord {
-            key: log_record_key_with_seq(TXN_FINISHED.to_vec(), seq_no),
-            value: Default::default(),
-            rec_type: LogRecordType::Txnfinished,
-        };
+    /// 清空所有暂存的数据和保存点
+    pub fn clear(&self) {
+        let mut pending_writes = self.pending_writes.lock();
+        pending_writes.clear();
+        self.pending_deltas.lock().clear();
+        self.savepoints.lock().clear();
+    }
 
-        self.engine.append_log_record(&mut finish_record)?;
+    /// 修改 key 之前记录它原来暂存的数据，没有设置保存点时不需要记录
+    fn save_undo(
+        &self,
+        pending_writes: &HashMap<Vec<u8>, LogRecord>,
+        pending_deltas: &HashMap<Vec<u8>, i64>,
+        key: &Bytes,
+    ) {
+        let mut savepoints = self.savepoints.lock();
+        if let Some(undo_log) = savepoints.last_mut() {
+            undo_log.push((
+                key.to_vec(),
+                pending_writes.get(&key.to_vec()).cloned(),
+                pending_deltas.get(&key.to_vec()).copied(),
+            ));
+        }
+    }
 
-        // 如果配置了持久化，则 sync
-        if self.options.sync_writes {
-            self.engine.sync()?;
+    /// 读取数据，优先读取批次中暂存的数据
+    pub fn get(&self, key: Bytes) -> Result<Bytes> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
         }
 
-        // 数据全部写完之后更新内存索引
-        for (_, item) in pending_writes.iter() {
-            if item.rec_type == LogRecordType::Normal {
-                let record_pos = positions.get(&item.key).unwrap();
-                if let Some(old_pos) = self.engine.index.put(item.key.clone(), *record_pos) {
-                    self.engine
-                        .reclaim_size
-                        .fetch_add(old_pos.size as usize, std::sync::atomic::Ordering::SeqCst);
-                }
-            }
-            if item.rec_type == LogRecordType::Deleted {
-                if let Some(old_pos) = self.engine.index.delete(item.key.clone()) {
-                    self.engine
-                        .reclaim_size
-                        .fetch_add(old_pos.size as usize, std::sync::atomic::Ordering::SeqCst);
+        let pending_writes = self.pending_writes.lock();
+        if let Some(record) = pending_writes.get(&key.to_vec()) {
+            return pending_value(record, now_nanos()).ok_or(Errors::KeyNotFound);
+        }
+        match self.pending_deltas.lock().get(&key.to_vec()) {
+            Some(delta) => Ok(encode_integer(self.resolve_delta(&key, *delta)?)),
+            None => self.engine.get(key),
+        }
+    }
+
+    /// 获取迭代器，暂存的写入和删除会覆盖数据库中的数据
+    /// 迭代器创建之后再暂存的数据不可见
+    pub fn iter(&self, mut options: IteratorOptions) -> WriteBatchIterator<'_> {
+        let now = now_nanos();
+        let pending_writes = self.pending_writes.lock();
+        let pending_deltas = self.pending_deltas.lock();
+        let mut pending: Vec<(Vec<u8>, Option<Bytes>)> = pending_writes
+            .iter()
+            .map(|(key, record)| (key, pending_value(record, now)))
+            .chain(pending_deltas.iter().filter_map(|(key, delta)| {
+                // 累加值基于数据库中当前的值计算，无法计算时以数据库中的数据为准
+                let value = self.resolve_delta(&Bytes::from(key.clone()), *delta);
+                value.ok().map(|value| (key, Some(encode_integer(value))))
+            }))
+            .filter(|(key, _)| key.starts_with(&options.prefix) && options.in_bounds(key))
+            .map(|(key, value)| {
+                // 不需要 value 的模式下和数据库迭代器一样返回空的 value
+                match options.mode {
+                    IteratorMode::KeyValue => (key.clone(), value),
+                    _ => (key.clone(), value.map(|_| Bytes::new())),
                 }
+            })
+            .collect();
+        pending.sort_by(|a, b| a.0.cmp(&b.0));
+        if options.reverse {
+            pending.reverse();
+        }
+
+        // 暂存的删除会覆盖数据库中的数据，所以 limit 只能在合并之后计算
+        let limit = options.limit.take();
+        WriteBatchIterator {
+            reverse: options.reverse,
+            limit,
+            engine_iter: self.engine.iter(options),
+            pending,
+            pending_idx: 0,
+            engine_item: None,
+            returned: 0,
+        }
+    }
+
+    pub fn commit(&self) -> Result<()> {
+        let mut pending_writes = self.pending_writes.lock();
+        let mut pending_deltas = self.pending_deltas.lock();
+        let pending_num = pending_writes.len() + pending_deltas.len();
+        if pending_num == 0 {
+            return Ok(());
+        }
+        if pending_num > self.options.max_batch_num {
+            return Err(Errors::ExceedMaxBatchNum);
+        }
+
+        // 加锁保证事务提交串行化
+        let _lock = self.engine.batch_commit_lock.lock();
+        if pending_deltas.is_empty() {
+            self.engine
+                .commit_pending_writes(&pending_writes, self.options.sync_writes)?;
+        } else {
+            // 持有写锁之后基于最新的值计算累加的结果
+            let mut writes = pending_writes.clone();
+            for (key, delta) in pending_deltas.iter() {
+                let (value, expire) = self
+                    .engine
+                    .incr_value_without_lock(&Bytes::from(key.clone()), *delta)?;
+                let record = LogRecord {
+                    key: key.clone(),
+                    value: encode_integer(value).to_vec(),
+                    rec_type: LogRecordType::Normal,
+                    expire,
+                    keyspace: Default::default(),
+                };
+                writes.insert(key.clone(), record);
             }
+            self.engine
+                .commit_pending_writes(&writes, self.options.sync_writes)?;
         }
+
         // 清空暂存数据
         pending_writes.clear();
+        pending_deltas.clear();
+        self.savepoints.lock().clear();
 
         Ok(())
     }
 }
 
+/// 暂存数据对应的 value，删除或已经过期的数据返回 None
+fn pending_value(record: &LogRecord, now: u64) -> Option<Bytes> {
+    if record.rec_type != LogRecordType::Normal || (record.expire > 0 && record.expire <= now) {
+        return None;
+    }
+    Some(Bytes::from(record.value.clone()))
+}
+
+/// WriteBatch 的迭代器，将暂存的数据合并到数据库迭代器的结果之上
+pub struct WriteBatchIterator<'a> {
+    reverse: bool,
+    limit: Option<usize>,
+    engine_iter: Iterator<'a>,
+    /// 按照迭代顺序排列的暂存数据，value 为 None 表示删除
+    pending: Vec<(Vec<u8>, Option<Bytes>)>,
+    pending_idx: usize,
+    /// 从数据库迭代器中预读的数据
+    engine_item: Option<(Bytes, Bytes)>,
+    /// 已经返回的数据条数
+    returned: usize,
+}
+
+impl std::iter::Iterator for WriteBatchIterator<'_> {
+    type Item = Result<(Bytes, Bytes)>;
+
+    /// Next 跳转到下一个 key，返回 None 则说明迭代完毕
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.limit.is_some_and(|limit| self.returned >= limit) {
+            return None;
+        }
+        let item = self.next_merged();
+        if item.is_some() {
+            self.returned += 1;
+        }
+        item
+    }
+}
+
+impl WriteBatchIterator<'_> {
+    fn next_merged(&mut self) -> Option<Result<(Bytes, Bytes)>> {
+        loop {
+            if self.engine_item.is_none() {
+                self.engine_item = match self.engine_iter.next() {
+                    Some(Ok(item)) => Some(item),
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => None,
+                };
+            }
+
+            let idx = self.pending_idx;
+            let order = match (self.pending.get(idx), &self.engine_item) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((pending_key, _)), Some((engine_key, _))) => match self.reverse {
+                    false => pending_key.as_slice().cmp(engine_key),
+                    true => engine_key.as_ref().cmp(pending_key),
+                },
+            };
+
+            // 数据库中的数据排在前面，直接返回
+            if order == Ordering::Greater {
+                return self.engine_item.take().map(Ok);
+            }
+            // 相同的 key 以暂存的数据为准
+            if order == Ordering::Equal {
+                self.engine_item = None;
+            }
+
+            self.pending_idx += 1;
+            let (key, value) = &self.pending[idx];
+            if let Some(value) = value {
+                return Some(Ok((Bytes::from(key.clone()), value.clone())));
+            }
+        }
+    }
+}
+
 /// 编码 seq no 和 key
 pub(crate) fn log_record_key_with_seq(key: Vec<u8>, seq_no: usize) -> Vec<u8> {
     let mut enc_key = BytesMut::new();
@@ -270,4 +582,257 @@ mod tests {
         // 删除测试的文件夹
         std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
     }
+
+    #[test]
+    fn test_write_batch_get_and_iter() {
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-batch-iter"),
+            data_file_size: 64 * 1024 * 1024,
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for key in ["aa", "bb", "cc", "dd"] {
+            let put_res = engine.put(Bytes::from(key), Bytes::from("engine"));
+            assert!(put_res.is_ok());
+        }
+
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        assert!(wb.put(Bytes::from("bb"), Bytes::from("batch")).is_ok());
+        assert!(wb.put(Bytes::from("ee"), Bytes::from("batch")).is_ok());
+        assert!(wb.put(Bytes::from("ab"), Bytes::from("batch")).is_ok());
+        assert!(wb.delete(Bytes::from("cc")).is_ok());
+
+        // 读取暂存的数据
+        assert_eq!(Bytes::from("batch"), wb.get(Bytes::from("bb")).unwrap());
+        assert_eq!(Bytes::from("engine"), wb.get(Bytes::from("aa")).unwrap());
+        assert_eq!(
+            Errors::KeyNotFound,
+            wb.get(Bytes::from("cc")).err().unwrap()
+        );
+
+        let collect = |iter: WriteBatchIterator| {
+            let mut items = Vec::new();
+            for item in iter {
+                let (key, value) = item.unwrap();
+                items.push((
+                    String::from_utf8(key.to_vec()).unwrap(),
+                    String::from_utf8(value.to_vec()).unwrap(),
+                ));
+            }
+            items
+        };
+
+        let items = collect(wb.iter(IteratorOptions::default()));
+        let expected = vec![
+            ("aa", "engine"),
+            ("ab", "batch"),
+            ("bb", "batch"),
+            ("dd", "engine"),
+            ("ee", "batch"),
+        ];
+        let to_owned = |v: Vec<(&str, &str)>| {
+            v.into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(to_owned(expected.clone()), items);
+
+        // 反向迭代
+        let items = collect(wb.iter(IteratorOptions {
+            reverse: true,
+            ..Default::default()
+        }));
+        let mut reversed = expected.clone();
+        reversed.reverse();
+        assert_eq!(to_owned(reversed), items);
+
+        // 前缀过滤
+        let items = collect(wb.iter(IteratorOptions {
+            prefix: "a".as_bytes().to_vec(),
+            ..Default::default()
+        }));
+        assert_eq!(to_owned(vec![("aa", "engine"), ("ab", "batch")]), items);
+
+        // 范围和条数限制，暂存的删除不占用条数
+        let items = collect(wb.iter(IteratorOptions {
+            lower_bound: std::ops::Bound::Excluded("bb".as_bytes().to_vec()),
+            limit: Some(1),
+            ..Default::default()
+        }));
+        assert_eq!(to_owned(vec![("dd", "engine")]), items);
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_write_batch_savepoint() {
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-batch-savepoint"),
+            data_file_size: 64 * 1024 * 1024,
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let put_res = engine.put(
+            util::rand_kv::get_test_key(3),
+            util::rand_kv::get_test_value(3),
+        );
+        assert!(put_res.is_ok());
+
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        // 没有保存点
+        assert_eq!(
+            Errors::NoSavepoint,
+            wb.rollback_to_savepoint().err().unwrap()
+        );
+
+        assert!(wb
+            .put(
+                util::rand_kv::get_test_key(1),
+                util::rand_kv::get_test_value(1)
+            )
+            .is_ok());
+        wb.set_savepoint();
+        assert!(wb
+            .put(
+                util::rand_kv::get_test_key(1),
+                util::rand_kv::get_test_value(11)
+            )
+            .is_ok());
+        assert!(wb
+            .put(
+                util::rand_kv::get_test_key(2),
+                util::rand_kv::get_test_value(2)
+            )
+            .is_ok());
+        wb.set_savepoint();
+        assert!(wb.delete(util::rand_kv::get_test_key(3)).is_ok());
+        assert!(wb.delete(util::rand_kv::get_test_key(2)).is_ok());
+
+        // 回滚到第二个保存点
+        assert!(wb.rollback_to_savepoint().is_ok());
+        assert!(wb.get(util::rand_kv::get_test_key(3)).is_ok());
+        assert_eq!(
+            util::rand_kv::get_test_value(2),
+            wb.get(util::rand_kv::get_test_key(2)).unwrap()
+        );
+
+        // 回滚到第一个保存点
+        assert!(wb.rollback_to_savepoint().is_ok());
+        assert_eq!(
+            util::rand_kv::get_test_value(1),
+            wb.get(util::rand_kv::get_test_key(1)).unwrap()
+        );
+        assert_eq!(
+            Errors::KeyNotFound,
+            wb.get(util::rand_kv::get_test_key(2)).err().unwrap()
+        );
+        assert_eq!(
+            Errors::NoSavepoint,
+            wb.rollback_to_savepoint().err().unwrap()
+        );
+
+        assert!(wb.commit().is_ok());
+        assert_eq!(
+            util::rand_kv::get_test_value(1),
+            engine.get(util::rand_kv::get_test_key(1)).unwrap()
+        );
+        assert_eq!(
+            Errors::KeyNotFound,
+            engine.get(util::rand_kv::get_test_key(2)).err().unwrap()
+        );
+
+        // 清空之后提交不会写入任何数据
+        assert!(wb
+            .put(
+                util::rand_kv::get_test_key(4),
+                util::rand_kv::get_test_value(4)
+            )
+            .is_ok());
+        wb.clear();
+        assert!(wb.commit().is_ok());
+        assert_eq!(
+            Errors::KeyNotFound,
+            engine.get(util::rand_kv::get_test_key(4)).err().unwrap()
+        );
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_write_batch_incr_by() {
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-batch-incr"),
+            data_file_size: 64 * 1024 * 1024,
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let put_res = engine.put(Bytes::from("counter-1"), Bytes::from("10"));
+        assert!(put_res.is_ok());
+
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        // 基于数据库中的值累加
+        assert_eq!(15, wb.incr_by(Bytes::from("counter-1"), 5).unwrap());
+        // 基于暂存的值累加
+        assert_eq!(17, wb.incr_by(Bytes::from("counter-1"), 2).unwrap());
+        // key 不存在
+        assert_eq!(-3, wb.incr_by(Bytes::from("counter-2"), -3).unwrap());
+
+        // 提交之前不可见
+        assert_eq!(
+            Bytes::from("10"),
+            engine.get(Bytes::from("counter-1")).unwrap()
+        );
+
+        let commit_res = wb.commit();
+        assert!(commit_res.is_ok());
+        assert_eq!(
+            Bytes::from("17"),
+            engine.get(Bytes::from("counter-1")).unwrap()
+        );
+        assert_eq!(
+            Bytes::from("-3"),
+            engine.get(Bytes::from("counter-2")).unwrap()
+        );
+
+        // 暂存之后其他的写入不会丢失，提交时基于最新的值累加
+        let wb2 = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        assert_eq!(18, wb2.incr_by(Bytes::from("counter-1"), 1).unwrap());
+        assert_eq!(20, engine.incr_by(Bytes::from("counter-1"), 3).unwrap());
+        assert_eq!(
+            Bytes::from("21"),
+            wb2.get(Bytes::from("counter-1")).unwrap()
+        );
+        assert!(wb2.commit().is_ok());
+        assert_eq!(
+            Bytes::from("21"),
+            engine.get(Bytes::from("counter-1")).unwrap()
+        );
+
+        // 回滚到保存点时累加值同样恢复
+        wb2.set_savepoint();
+        assert_eq!(23, wb2.incr_by(Bytes::from("counter-1"), 2).unwrap());
+        assert!(wb2.rollback_to_savepoint().is_ok());
+        assert_eq!(
+            Bytes::from("21"),
+            wb2.get(Bytes::from("counter-1")).unwrap()
+        );
+        assert!(wb2.commit().is_ok());
+        assert_eq!(
+            Bytes::from("21"),
+            engine.get(Bytes::from("counter-1")).unwrap()
+        );
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
 }
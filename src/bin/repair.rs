@@ -0,0 +1,76 @@
+use std::path::PathBuf;
+
+use my_data::{
+    options::{Encryption, IndexType, Options},
+    repair::repair,
+};
+
+/// 加密的密钥，64 个十六进制字符
+const ENCRYPTION_KEY_ENV: &str = "BITCASK_ENCRYPTION_KEY";
+/// 加密的算法，aes256gcm 或者 chacha20poly1305，默认为 aes256gcm
+const ENCRYPTION_ENV: &str = "BITCASK_ENCRYPTION";
+
+/// 离线修复数据目录：bitcask-repair <数据目录> <修复后的目录> [btree|skiplist|bptree]
+/// 加密的数据目录通过环境变量 BITCASK_ENCRYPTION_KEY 和 BITCASK_ENCRYPTION 指定密钥和算法
+fn main() -> anyhow::Result<()> {
+    env_logger::init();
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        anyhow::bail!(
+            "usage: {} <dir> <dest-dir> [btree|skiplist|bptree]",
+            args[0]
+        );
+    }
+    let index_type = match args.get(3).map(|s| s.as_str()) {
+        None | Some("btree") => IndexType::BTree,
+        Some("skiplist") => IndexType::SkipList,
+        Some("bptree") => IndexType::BPlusTree,
+        Some(other) => anyhow::bail!("unknown index type: {}", other),
+    };
+
+    let opts = Options {
+        dir_path: PathBuf::from(&args[1]),
+        index_type,
+        encryption: encryption_from_env()?,
+        ..Default::default()
+    };
+    let report = repair(opts, PathBuf::from(&args[2]))?;
+
+    println!("data files:        {}", report.data_files);
+    println!("records copied:    {}", report.records);
+    println!("corrupted regions: {}", report.corrupted_regions);
+    println!("bytes lost:        {}", report.lost_bytes);
+    println!("keys lost:         {}", report.lost_keys);
+    println!("corrupted files:   {:?}", report.corrupted_files);
+    Ok(())
+}
+
+/// 从环境变量中读取加密的配置，没有设置密钥时不加密
+fn encryption_from_env() -> anyhow::Result<Encryption> {
+    let hex = match std::env::var(ENCRYPTION_KEY_ENV) {
+        Ok(hex) => hex,
+        Err(_) => return Ok(Encryption::None),
+    };
+    let key = parse_key(hex.trim())?;
+    match std::env::var(ENCRYPTION_ENV).as_deref() {
+        Err(_) | Ok("aes256gcm") => Ok(Encryption::Aes256Gcm(key)),
+        Ok("chacha20poly1305") => Ok(Encryption::ChaCha20Poly1305(key)),
+        Ok(other) => anyhow::bail!("unknown encryption: {}", other),
+    }
+}
+
+/// 解析十六进制的 32 字节密钥
+fn parse_key(hex: &str) -> anyhow::Result<[u8; 32]> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        anyhow::bail!("{} must be 64 hex characters", ENCRYPTION_KEY_ENV);
+    }
+    let mut key = [0u8; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = match u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16) {
+            Ok(byte) => byte,
+            Err(_) => anyhow::bail!("{} must be 64 hex characters", ENCRYPTION_KEY_ENV),
+        };
+    }
+    Ok(key)
+}
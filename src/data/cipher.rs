@@ -0,0 +1,112 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm,
+};
+use chacha20poly1305::ChaCha20Poly1305;
+use rand::RngCore;
+
+use crate::{
+    error::{Errors, Result},
+    options::Encryption,
+};
+
+/// 每条记录使用的随机数长度
+const NONCE_SIZE: usize = 12;
+/// 认证标签的长度
+const TAG_SIZE: usize = 16;
+/// 加密之后数据增加的长度，随机数存放在密文之前，认证标签存放在密文之后
+pub(crate) const ENCRYPTION_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+
+/// 记录加解密使用的 AEAD 算法
+#[derive(Clone)]
+pub enum Cipher {
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+}
+
+impl Cipher {
+    /// 根据配置项创建，不加密时返回 None
+    pub(crate) fn new(encryption: &Encryption) -> Option<Self> {
+        match encryption {
+            Encryption::None => None,
+            Encryption::Aes256Gcm(key) => {
+                Some(Cipher::Aes256Gcm(Box::new(Aes256Gcm::new(key.into()))))
+            }
+            Encryption::ChaCha20Poly1305(key) => {
+                Some(Cipher::ChaCha20Poly1305(ChaCha20Poly1305::new(key.into())))
+            }
+        }
+    }
+
+    /// 加密 data，aad 只参与认证不加密，返回随机数 + 密文 + 认证标签
+    pub(crate) fn encrypt(&self, aad: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let payload = Payload { msg: data, aad };
+        let ciphertext = match self {
+            Cipher::Aes256Gcm(cipher) => cipher.encrypt((&nonce).into(), payload),
+            Cipher::ChaCha20Poly1305(cipher) => cipher.encrypt((&nonce).into(), payload),
+        }
+        .expect("failed to encrypt log record");
+
+        let mut buf = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+        buf.extend_from_slice(&nonce);
+        buf.extend_from_slice(&ciphertext);
+        buf
+    }
+
+    /// 解密 encrypt 返回的数据，密钥错误或者数据被篡改时返回错误
+    pub(crate) fn decrypt(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < ENCRYPTION_OVERHEAD {
+            return Err(Errors::InvalidEncryptionKey);
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_SIZE);
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        match self {
+            Cipher::Aes256Gcm(cipher) => cipher.decrypt(nonce.into(), payload),
+            Cipher::ChaCha20Poly1305(cipher) => cipher.decrypt(nonce.into(), payload),
+        }
+        .map_err(|_| Errors::InvalidEncryptionKey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cipher_encrypt_decrypt() {
+        for encryption in [
+            Encryption::Aes256Gcm([1; 32]),
+            Encryption::ChaCha20Poly1305([1; 32]),
+        ] {
+            let cipher = Cipher::new(&encryption).unwrap();
+            let enc = cipher.encrypt(b"header", b"bitcask-rs");
+            assert_eq!(enc.len(), "bitcask-rs".len() + ENCRYPTION_OVERHEAD);
+            assert_eq!(
+                b"bitcask-rs".to_vec(),
+                cipher.decrypt(b"header", &enc).unwrap()
+            );
+
+            // 附加数据不一致或者密钥错误都无法解密
+            assert_eq!(
+                Errors::InvalidEncryptionKey,
+                cipher.decrypt(b"other", &enc).err().unwrap()
+            );
+            let wrong = match encryption {
+                Encryption::Aes256Gcm(_) => Encryption::Aes256Gcm([2; 32]),
+                _ => Encryption::ChaCha20Poly1305([2; 32]),
+            };
+            let wrong_cipher = Cipher::new(&wrong).unwrap();
+            assert_eq!(
+                Errors::InvalidEncryptionKey,
+                wrong_cipher.decrypt(b"header", &enc).err().unwrap()
+            );
+        }
+        assert!(Cipher::new(&Encryption::None).is_none());
+    }
+}
@@ -1,13 +1,23 @@
 use super::log_record::{LogRecord, LogRecordPos, ReadLogRecord};
 use crate::{
-    data::log_record::{max_log_record_header_size, LogRecordType},
+    data::{
+        cipher::{Cipher, ENCRYPTION_OVERHEAD},
+        log_record::{
+            decompress_value, max_log_record_header_size, now_nanos, LogRecordType,
+            ENCRYPTION_FLAG, EXPIRE_FLAG, KEYSPACE_FLAG,
+        },
+    },
     error::{Errors, Result},
     fio::{self, new_io_manager},
-    options::IOType,
+    options::{Compression, IOType},
 };
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
 use parking_lot::RwLock;
-use prost::{decode_length_delimiter, length_delimiter_len};
+use prost::{
+    decode_length_delimiter,
+    encoding::{decode_varint, encoded_len_varint},
+    length_delimiter_len,
+};
 use std::{path::PathBuf, sync::Arc};
 
 pub const DATA_FILE_NAME_SUFFIX: &str = ".data";
@@ -15,6 +25,19 @@ pub const HINT_FILE_NAME: &str = "hint-index";
 pub const MERGE_FINISHED_FILE_NAME: &str = "merge-finished";
 pub const SEQ_FILE_NAME: &str = "seq-no";
 
+/// 数据文件头部的魔数
+const DATA_FILE_MAGIC: [u8; 4] = [0xBF, b'B', b'K', b'V'];
+/// 当前数据文件的格式版本
+pub const DATA_FILE_FORMAT_VERSION: u16 = 1;
+/// 数据文件头部的长度
+pub const DATA_FILE_HEADER_SIZE: u64 = 24;
+
+/// 批量读取时，两条数据之间的间隔不超过该值就合并成一次读取
+const READ_COALESCE_GAP: u64 = 4 * 1024;
+
+/// 判断末尾的数据是否没有完整写入时，最多向后查找的字节数
+const TORN_TAIL_WINDOW: u64 = 1024 * 1024;
+
 /// 数据文件
 pub struct DataFile {
     /// 数据文件id
@@ -25,25 +48,98 @@ pub struct DataFile {
 
     /// IO 管理接口
     io_manager: Box<dyn fio::IOManager>,
+
+    /// 记录加解密使用的算法，None 表示不加密
+    cipher: Option<Cipher>,
+
+    /// 数据文件的头部，旧版本的数据文件以及 hint 等文件没有头部
+    header: Option<DataFileHeader>,
 }
 
 impl DataFile {
     /// 创建或打开一个新的数据文件
-    pub fn new(dir_path: PathBuf, file_id: u32, io_type: IOType) -> Result<DataFile> {
+    pub fn new(
+        dir_path: PathBuf,
+        file_id: u32,
+        io_type: IOType,
+        cipher: Option<Cipher>,
+    ) -> Result<DataFile> {
         // 根据 path 和 id 构建出完整的文件名称
         let file_name = get_data_file_name(dir_path, file_id);
         // 初始化 io manager
         let io_manager = new_io_manager(file_name, io_type)?;
 
+        // 新建的文件写入头部，已经存在的文件校验头部
+        // mmap 只用于读取已有的文件，空文件按照旧版本的格式处理
+        let mut write_off = 0;
+        let header = match io_manager.size() {
+            0 if io_type == IOType::StandardFIO => {
+                let header = DataFileHeader {
+                    version: DATA_FILE_FORMAT_VERSION,
+                    file_id,
+                    created_at: now_nanos(),
+                };
+                io_manager.write(&header.encode())?;
+                write_off = DATA_FILE_HEADER_SIZE;
+                Some(header)
+            }
+            0 => None,
+            size => {
+                let mut buf = BytesMut::zeroed(size.min(DATA_FILE_HEADER_SIZE) as usize);
+                io_manager.read(&mut buf, 0)?;
+                DataFileHeader::decode(&buf)?
+            }
+        };
+
+        let data_file = DataFile {
+            file_id: Arc::new(RwLock::new(file_id)),
+            write_off: Arc::new(RwLock::new(write_off)),
+            io_manager,
+            cipher,
+            header,
+        };
+
+        // 旧版本的数据文件，第一条记录需要通过 crc 校验，避免把其他格式的文件当作数据文件
+        if data_file.header.is_none() && data_file.file_size() > 0 {
+            match data_file.read_log_record(0) {
+                Ok(_) | Err(Errors::InvalidEncryptionKey) | Err(Errors::DecompressFailed) => {}
+                Err(_) => return Err(Errors::InvalidDataFileHeader),
+            }
+        }
+        Ok(data_file)
+    }
+
+    /// 按照没有头部的格式打开数据文件，用于修复头部已经损坏的文件
+    pub fn new_headerless(
+        dir_path: PathBuf,
+        file_id: u32,
+        cipher: Option<Cipher>,
+    ) -> Result<DataFile> {
+        let file_name = get_data_file_name(dir_path, file_id);
+        let io_manager = new_io_manager(file_name, crate::options::IOType::StandardFIO)?;
+
         Ok(DataFile {
             file_id: Arc::new(RwLock::new(file_id)),
             write_off: Arc::new(RwLock::new(0)),
             io_manager,
+            cipher,
+            header: None,
         })
     }
 
+    /// 创建文件时写入头部的过程中崩溃，文件中只有部分头部
+    pub(crate) fn has_partial_header(dir_path: PathBuf, file_id: u32) -> bool {
+        match std::fs::read(get_data_file_name(dir_path, file_id)) {
+            Ok(buf) if !buf.is_empty() && (buf.len() as u64) < DATA_FILE_HEADER_SIZE => {
+                let n = buf.len().min(DATA_FILE_MAGIC.len());
+                buf[..n] == DATA_FILE_MAGIC[..n]
+            }
+            _ => false,
+        }
+    }
+
     /// 新建或打开 hint 索引文件
-    pub fn new_hint_file(dir_path: PathBuf) -> Result<DataFile> {
+    pub fn new_hint_file(dir_path: PathBuf, cipher: Option<Cipher>) -> Result<DataFile> {
         let file_name = dir_path.join(HINT_FILE_NAME);
         let io_manager = new_io_manager(file_name, crate::options::IOType::StandardFIO)?;
 
@@ -51,11 +147,13 @@ impl DataFile {
             file_id: Arc::new(RwLock::new(0)),
             write_off: Arc::new(RwLock::new(0)),
             io_manager,
+            cipher,
+            header: None,
         })
     }
 
     /// 新建或打开 merge 完成的文件
-    pub fn new_merge_fin_file(dir_path: PathBuf) -> Result<DataFile> {
+    pub fn new_merge_fin_file(dir_path: PathBuf, cipher: Option<Cipher>) -> Result<DataFile> {
         let file_name = dir_path.join(MERGE_FINISHED_FILE_NAME);
         let io_manager = new_io_manager(file_name, crate::options::IOType::StandardFIO)?;
 
@@ -63,11 +161,13 @@ impl DataFile {
             file_id: Arc::new(RwLock::new(0)),
             write_off: Arc::new(RwLock::new(0)),
             io_manager,
+            cipher,
+            header: None,
         })
     }
 
     /// 新建或打开存储事务序列号的文件
-    pub fn new_seq_no_file(dir_path: PathBuf) -> Result<DataFile> {
+    pub fn new_seq_no_file(dir_path: PathBuf, cipher: Option<Cipher>) -> Result<DataFile> {
         let file_name = dir_path.join(SEQ_FILE_NAME);
         let io_manager = new_io_manager(file_name, crate::options::IOType::StandardFIO)?;
 
@@ -75,6 +175,8 @@ impl DataFile {
             file_id: Arc::new(RwLock::new(0)),
             write_off: Arc::new(RwLock::new(0)),
             io_manager,
+            cipher,
+            header: None,
         })
     }
 
@@ -97,53 +199,166 @@ impl DataFile {
         *read_guard
     }
 
+    /// 数据文件的头部，旧版本的数据文件没有头部
+    pub fn header(&self) -> Option<DataFileHeader> {
+        self.header
+    }
+
+    /// 第一条 LogRecord 的偏移，即头部的长度
+    pub fn first_record_offset(&self) -> u64 {
+        match self.header {
+            Some(_) => DATA_FILE_HEADER_SIZE,
+            None => 0,
+        }
+    }
+
     /// 根据 offset 从数据文件中读取 LogRecord
     pub fn read_log_record(&self, offset: u64) -> Result<ReadLogRecord> {
-        // 先读取出 header 部分的数据
-        let mut header_buf = BytesMut::zeroed(max_log_record_header_size());
+        // 先读取出 header 部分的数据，文件末尾剩余的字节可能不足最大 header 长度
+        let file_size = self.io_manager.size();
+        if offset >= file_size {
+            return Err(Errors::ReadDataFileEOF);
+        }
+        let header_bytes = (max_log_record_header_size() as u64).min(file_size - offset);
+        let mut header_buf = BytesMut::zeroed(header_bytes as usize);
         self.io_manager.read(&mut header_buf, offset)?;
 
-        // 取出 type，在第一个字节
-        let rec_type = header_buf.get_u8();
-
-        // 取出 key 和 value 的长度
-        let key_size = decode_length_delimiter(&mut header_buf).unwrap();
-        let value_size = decode_length_delimiter(&mut header_buf).unwrap();
-
         // 如果 key 和 value 均为空，则说明读取到了文件的末尾，直接返回
-        if key_size == 0 && value_size == 0 {
+        let header = decode_log_record_header(&header_buf)?;
+        if header.key_size == 0 && header.value_size == 0 {
             return Err(Errors::ReadDataFileEOF);
         }
 
-        let actual_header_size =
-            length_delimiter_len(key_size) + length_delimiter_len(value_size) + 1;
+        // 数据超出了文件的末尾，说明没有完整写入
+        let record_size = (header.header_size + header.disk_body_size() + 4) as u64;
+        if offset + record_size > file_size {
+            return Err(Errors::CorruptedLogRecord);
+        }
 
-        // 读取实际的 key 和 value，最后的 4 个字节是 crc 校验值
-        let mut kv_buf = BytesMut::zeroed(key_size + value_size + 4);
+        // 读取实际的 keyspace、key 和 value，最后的 4 个字节是 crc 校验值
+        let mut kv_buf = BytesMut::zeroed(header.disk_body_size() + 4);
         self.io_manager
-            .read(&mut kv_buf, offset + actual_header_size as u64)?;
+            .read(&mut kv_buf, offset + header.header_size as u64)?;
+        let log_record = decode_log_record_body(
+            &header,
+            &header_buf[..header.header_size],
+            &kv_buf,
+            self.cipher.as_ref(),
+        )?;
 
-        // 构造 LogRecord
-        let log_record = LogRecord {
-            key: kv_buf.get(..key_size).unwrap().to_vec(),
-            value: kv_buf.get(key_size..kv_buf.len() - 4).unwrap().to_vec(),
-            rec_type: LogRecordType::from_u8(rec_type),
-        };
+        // 构造结果并返回
+        Ok(ReadLogRecord {
+            record: log_record,
+            size: (header.header_size + header.disk_body_size() + 4) as u64,
+        })
+    }
 
-        // 向前移动最后的 4 个字节，就是 crc 的值
-        kv_buf.advance(key_size + value_size);
+    /// offset 处的记录无法解析时，判断是否只是末尾没有完整写入的数据
+    /// 没有完整写入的只可能是最后一条记录，header 中的长度之后以及 TORN_TAIL_WINDOW 范围内都没有有效的记录
+    pub fn is_torn_tail(&self, offset: u64) -> bool {
+        let file_size = self.file_size();
+        if let Some((_, size)) = self.read_record_header(offset, file_size) {
+            if self.read_log_record(offset + size).is_ok() {
+                return false;
+            }
+        }
+        let end = file_size.min(offset + TORN_TAIL_WINDOW);
+        (offset + 1..end).all(|off| self.read_log_record(off).is_err())
+    }
 
-        if kv_buf.get_u32() != log_record.get_crc() {
-            return Err(Errors::InvalidLogRecordCrc);
+    /// 不校验 crc，只解析 offset 处记录的 header，返回 header 和整条记录的长度，记录需要完整地位于 end 之前
+    fn read_record_header(&self, offset: u64, end: u64) -> Option<(LogRecordHeader, u64)> {
+        let header_bytes = (max_log_record_header_size() as u64).min(end.checked_sub(offset)?);
+        let mut header_buf = BytesMut::zeroed(header_bytes as usize);
+        self.io_manager.read(&mut header_buf, offset).ok()?;
+        let header = decode_log_record_header(&header_buf).ok()?;
+        let size = (header.header_size + header.disk_body_size() + 4) as u64;
+        if offset + size > end {
+            return None;
         }
+        Some((header, size))
+    }
 
-        // 构造结果并返回
-        Ok(ReadLogRecord {
-            record: log_record,
-            size: (actual_header_size + key_size + value_size + 4) as u64,
+    /// 不校验 crc，只解析出 offset 处记录的类型、keyspace 和 key，value 为空，用于统计损坏区域中的数据
+    /// 记录需要完整地位于 end 之前，加密的记录无法解析
+    pub(crate) fn read_damaged_record(&self, offset: u64, end: u64) -> Option<ReadLogRecord> {
+        let (header, size) = self.read_record_header(offset, end)?;
+        if header.rec_type & ENCRYPTION_FLAG == ENCRYPTION_FLAG {
+            return None;
+        }
+
+        let name_size = header.keyspace_size + header.key_size;
+        let mut name_buf = vec![0; name_size];
+        self.io_manager
+            .read(&mut name_buf, offset + header.header_size as u64)
+            .ok()?;
+        Some(ReadLogRecord {
+            record: LogRecord {
+                key: name_buf.split_off(header.keyspace_size),
+                value: Vec::new(),
+                rec_type: LogRecordType::from_u8(header.rec_type),
+                expire: header.expire,
+                keyspace: name_buf,
+            },
+            size,
         })
     }
 
+    /// 读取 offset 处原始的字节数据，不做任何解析
+    pub fn read_raw(&self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        let mut buf = vec![0; size as usize];
+        self.io_manager.read(&mut buf, offset)?;
+        Ok(buf)
+    }
+
+    /// 批量读取多条 LogRecord，positions 需要按照 offset 排序
+    /// 位置相邻的数据合并成一次读取，结果和 positions 一一对应
+    pub fn read_log_records(&self, positions: &[LogRecordPos]) -> Vec<Result<LogRecord>> {
+        let mut results = Vec::with_capacity(positions.len());
+        let mut start = 0;
+        while start < positions.len() {
+            // 找出可以合并成一次读取的数据
+            let read_off = positions[start].offset;
+            let mut read_end = read_off + positions[start].size as u64;
+            let mut end = start + 1;
+            while end < positions.len() && positions[end].offset <= read_end + READ_COALESCE_GAP {
+                read_end = read_end.max(positions[end].offset + positions[end].size as u64);
+                end += 1;
+            }
+
+            let mut buf = BytesMut::zeroed((read_end - read_off) as usize);
+            match self.io_manager.read(&mut buf, read_off) {
+                Ok(_) => {
+                    for pos in &positions[start..end] {
+                        let begin = (pos.offset - read_off) as usize;
+                        let record_buf = &buf[begin..begin + pos.size as usize];
+                        let header = match decode_log_record_header(record_buf) {
+                            Ok(header) => header,
+                            Err(e) => {
+                                results.push(Err(e));
+                                continue;
+                            }
+                        };
+                        results.push(match record_buf.get(header.header_size..) {
+                            Some(kv_buf) if kv_buf.len() == header.disk_body_size() + 4 => {
+                                decode_log_record_body(
+                                    &header,
+                                    &record_buf[..header.header_size],
+                                    kv_buf,
+                                    self.cipher.as_ref(),
+                                )
+                            }
+                            _ => Err(Errors::InvalidLogRecordCrc),
+                        });
+                    }
+                }
+                Err(e) => results.extend((start..end).map(|_| Err(e.clone()))),
+            }
+            start = end;
+        }
+        results
+    }
+
     pub fn write(&self, buf: &[u8]) -> Result<usize> {
         let n_bytes = self.io_manager.write(buf)?;
         let mut write_off = self.write_off.write();
@@ -153,13 +368,20 @@ impl DataFile {
     }
 
     /// 写 hint 索引到文件当中
-    pub fn write_hint_record(&self, key: Vec<u8>, pos: LogRecordPos) -> Result<()> {
+    pub fn write_hint_record(
+        &self,
+        keyspace: Vec<u8>,
+        key: Vec<u8>,
+        pos: LogRecordPos,
+    ) -> Result<()> {
         let hint_record = LogRecord {
             key,
             value: pos.encode(),
             rec_type: LogRecordType::Normal,
+            expire: 0,
+            keyspace,
         };
-        let enc_record = hint_record.encode();
+        let enc_record = hint_record.encode_with(Compression::None, self.cipher.as_ref());
         self.write(&enc_record)?;
         Ok(())
     }
@@ -168,6 +390,13 @@ impl DataFile {
         self.io_manager.sync()
     }
 
+    /// 将数据文件截断到给定的长度，用于丢弃末尾没有完整写入的数据
+    pub fn truncate(&self, size: u64) -> Result<()> {
+        self.io_manager.truncate(size)?;
+        self.set_write_off(size);
+        Ok(())
+    }
+
     pub fn set_io_iomanager(&mut self, dir_path: PathBuf, io_type: IOType) -> Result<()> {
         self.io_manager =
             new_io_manager(get_data_file_name(dir_path, self.get_file_id()), io_type)?;
@@ -175,6 +404,68 @@ impl DataFile {
     }
 }
 
+/// 数据文件的头部信息
+///
+/// +----------+----------+----------+----------+--------------+-----------+
+/// |   magic  |  version | reserved |  file id |  created at  | crc 校验值 |
+/// +----------+----------+----------+----------+--------------+-----------+
+///    4字节       2字节      2字节       4字节        8字节         4字节
+///
+/// magic 第一个字节的低 3 位不是有效的记录类型，以此和没有头部的旧版本数据文件区分开
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DataFileHeader {
+    /// 数据文件的格式版本
+    pub version: u16,
+    /// 创建时的文件 id
+    pub file_id: u32,
+    /// 创建时间，纳秒级时间戳
+    pub created_at: u64,
+}
+
+impl DataFileHeader {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = BytesMut::with_capacity(DATA_FILE_HEADER_SIZE as usize);
+        buf.put_slice(&DATA_FILE_MAGIC);
+        buf.put_u16(self.version);
+        buf.put_u16(0);
+        buf.put_u32(self.file_id);
+        buf.put_u64(self.created_at);
+        let crc = crc32fast::hash(&buf);
+        buf.put_u32(crc);
+        buf.to_vec()
+    }
+
+    /// 解析文件开头的数据，旧版本没有头部的文件返回 None
+    fn decode(buf: &[u8]) -> Result<Option<Self>> {
+        // 第一个字节是有效的记录类型，说明是旧版本的数据文件
+        if buf[0] != DATA_FILE_MAGIC[0] && LogRecordType::is_valid(buf[0]) {
+            return Ok(None);
+        }
+        if buf.len() < DATA_FILE_HEADER_SIZE as usize || !buf.starts_with(&DATA_FILE_MAGIC) {
+            return Err(Errors::InvalidDataFileHeader);
+        }
+
+        let (mut content, mut crc_buf) = buf.split_at(DATA_FILE_HEADER_SIZE as usize - 4);
+        if crc32fast::hash(content) != crc_buf.get_u32() {
+            return Err(Errors::InvalidDataFileHeader);
+        }
+        content.advance(DATA_FILE_MAGIC.len());
+        let version = content.get_u16();
+        content.advance(2);
+        let header = DataFileHeader {
+            version,
+            file_id: content.get_u32(),
+            created_at: content.get_u64(),
+        };
+
+        // 不能读取更新版本写入的数据文件
+        if header.version > DATA_FILE_FORMAT_VERSION {
+            return Err(Errors::InvalidDataFileHeader);
+        }
+        Ok(Some(header))
+    }
+}
+
 pub fn get_data_file_name(dir_path: PathBuf, file_id: u32) -> PathBuf {
     let name = std::format!("{:09}", file_id) + DATA_FILE_NAME_SUFFIX;
     dir_path.join(name)
@@ -204,6 +495,125 @@ pub fn copy_dir(src: PathBuf, dest: PathBuf, exclude: &[&str]) -> std::io::Resul
     Ok(())
 }
 
+/// LogRecord 的 header 信息
+struct LogRecordHeader {
+    rec_type: u8,
+    expire: u64,
+    keyspace_size: usize,
+    key_size: usize,
+    value_size: usize,
+    /// header 实际占据的字节数
+    header_size: usize,
+}
+
+impl LogRecordHeader {
+    fn body_size(&self) -> usize {
+        self.keyspace_size + self.key_size + self.value_size
+    }
+
+    /// body 在磁盘上占据的字节数，加密之后会增加随机数和认证标签
+    fn disk_body_size(&self) -> usize {
+        match self.rec_type & ENCRYPTION_FLAG == ENCRYPTION_FLAG {
+            true => self.body_size() + ENCRYPTION_OVERHEAD,
+            false => self.body_size(),
+        }
+    }
+}
+
+/// 解析 LogRecord 的 header，buf 中可能包含 header 之后的数据，数据不完整时返回错误
+fn decode_log_record_header(buf: &[u8]) -> Result<LogRecordHeader> {
+    let mut header_buf = buf;
+
+    // 取出 type，在第一个字节
+    if !header_buf
+        .first()
+        .is_some_and(|v| LogRecordType::is_valid(*v))
+    {
+        return Err(Errors::CorruptedLogRecord);
+    }
+    let rec_type = header_buf.get_u8();
+
+    // 如果设置了过期时间的标识，则取出过期时间
+    let mut expire = 0;
+    if rec_type & EXPIRE_FLAG == EXPIRE_FLAG {
+        expire = decode_varint(&mut header_buf).map_err(|_| Errors::CorruptedLogRecord)?;
+    }
+
+    // 如果设置了 keyspace 的标识，则取出 keyspace 的长度
+    let mut keyspace_size = 0;
+    let has_keyspace = rec_type & KEYSPACE_FLAG == KEYSPACE_FLAG;
+    if has_keyspace {
+        keyspace_size =
+            decode_length_delimiter(&mut header_buf).map_err(|_| Errors::CorruptedLogRecord)?;
+    }
+
+    // 取出 key 和 value 的长度
+    let key_size =
+        decode_length_delimiter(&mut header_buf).map_err(|_| Errors::CorruptedLogRecord)?;
+    let value_size =
+        decode_length_delimiter(&mut header_buf).map_err(|_| Errors::CorruptedLogRecord)?;
+
+    let mut header_size = length_delimiter_len(key_size) + length_delimiter_len(value_size) + 1;
+    if expire > 0 {
+        header_size += encoded_len_varint(expire);
+    }
+    if has_keyspace {
+        header_size += length_delimiter_len(keyspace_size);
+    }
+
+    Ok(LogRecordHeader {
+        rec_type,
+        expire,
+        keyspace_size,
+        key_size,
+        value_size,
+        header_size,
+    })
+}
+
+/// 根据 header 解析 keyspace、key 和 value，并校验最后 4 个字节的 crc
+/// crc 是根据磁盘上的数据计算的，校验通过之后再进行解密和解压
+fn decode_log_record_body(
+    header: &LogRecordHeader,
+    header_buf: &[u8],
+    kv_buf: &[u8],
+    cipher: Option<&Cipher>,
+) -> Result<LogRecord> {
+    let disk_body_size = header.disk_body_size();
+
+    // 最后的 4 个字节，就是 crc 的值
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header_buf);
+    hasher.update(&kv_buf[..disk_body_size]);
+    let mut crc_buf = &kv_buf[disk_body_size..];
+    if crc_buf.get_u32() != hasher.finalize() {
+        return Err(Errors::InvalidLogRecordCrc);
+    }
+
+    // 对加密过的数据进行解密，没有配置密钥时无法读取
+    let decrypted;
+    let body = match header.rec_type & ENCRYPTION_FLAG == ENCRYPTION_FLAG {
+        true => {
+            let cipher = cipher.ok_or(Errors::InvalidEncryptionKey)?;
+            decrypted = cipher.decrypt(header_buf, &kv_buf[..disk_body_size])?;
+            decrypted.as_slice()
+        }
+        false => &kv_buf[..disk_body_size],
+    };
+
+    // 构造 LogRecord，并对压缩过的 value 进行解压
+    let keyspace_size = header.keyspace_size;
+    let key_size = header.key_size;
+    let value = body[keyspace_size + key_size..header.body_size()].to_vec();
+    Ok(LogRecord {
+        key: body[keyspace_size..keyspace_size + key_size].to_vec(),
+        value: decompress_value(header.rec_type, value)?,
+        rec_type: LogRecordType::from_u8(header.rec_type),
+        expire: header.expire,
+        keyspace: body[..keyspace_size].to_vec(),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -212,17 +622,17 @@ mod tests {
     fn test_new_data_file() {
         let dir_path = std::env::temp_dir();
 
-        let data_file_res1 = DataFile::new(dir_path.clone(), 0, IOType::MemoryMap);
+        let data_file_res1 = DataFile::new(dir_path.clone(), 0, IOType::MemoryMap, None);
         assert!(data_file_res1.is_ok());
         let data_file1 = data_file_res1.unwrap();
         assert_eq!(data_file1.get_file_id(), 0);
 
-        let data_file_res2 = DataFile::new(dir_path.clone(), 0, IOType::MemoryMap);
+        let data_file_res2 = DataFile::new(dir_path.clone(), 0, IOType::MemoryMap, None);
         assert!(data_file_res2.is_ok());
         let data_file2 = data_file_res2.unwrap();
         assert_eq!(data_file2.get_file_id(), 0);
 
-        let data_file_res3 = DataFile::new(dir_path.clone(), 660, IOType::MemoryMap);
+        let data_file_res3 = DataFile::new(dir_path.clone(), 660, IOType::MemoryMap, None);
         assert!(data_file_res3.is_ok());
         let data_file3 = data_file_res3.unwrap();
         assert_eq!(data_file3.get_file_id(), 660);
@@ -232,7 +642,7 @@ mod tests {
     fn test_data_file_write() {
         let dir_path = std::env::temp_dir();
 
-        let data_file_res1 = DataFile::new(dir_path.clone(), 100, IOType::MemoryMap);
+        let data_file_res1 = DataFile::new(dir_path.clone(), 100, IOType::MemoryMap, None);
         assert!(data_file_res1.is_ok());
         let data_file1 = data_file_res1.unwrap();
         assert_eq!(data_file1.get_file_id(), 100);
@@ -250,14 +660,14 @@ mod tests {
     fn test_data_file_sync() {
         let dir_path = std::env::temp_dir();
 
-        let data_file_res1 = DataFile::new(dir_path.clone(), 100, IOType::MemoryMap);
+        let data_file_res1 = DataFile::new(dir_path.clone(), 100, IOType::MemoryMap, None);
         assert!(data_file_res1.is_ok());
         let data_file1 = data_file_res1.unwrap();
         assert_eq!(data_file1.get_file_id(), 100);
 
         let dir_path = std::env::temp_dir();
 
-        let data_file_res1 = DataFile::new(dir_path.clone(), 200, IOType::MemoryMap);
+        let data_file_res1 = DataFile::new(dir_path.clone(), 200, IOType::MemoryMap, None);
         assert!(data_file_res1.is_ok());
         let data_file1 = data_file_res1.unwrap();
         assert_eq!(data_file1.get_file_id(), 200);
@@ -269,7 +679,7 @@ mod tests {
     #[test]
     fn test_data_file_read_log_record() {
         let dir_path = std::env::temp_dir();
-        let data_file_res1 = DataFile::new(dir_path.clone(), 600, IOType::MemoryMap);
+        let data_file_res1 = DataFile::new(dir_path.clone(), 600, IOType::MemoryMap, None);
         assert!(data_file_res1.is_ok());
         let data_file1 = data_file_res1.unwrap();
         assert_eq!(data_file1.get_file_id(), 600);
@@ -278,6 +688,8 @@ mod tests {
             key: "name".as_bytes().to_vec(),
             value: "bitcask-rs".as_bytes().to_vec(),
             rec_type: LogRecordType::Normal,
+            expire: 0,
+            keyspace: Default::default(),
         };
         let write_res1 = data_file1.write(&enc1.encode());
         assert!(write_res1.is_ok());
@@ -296,6 +708,8 @@ mod tests {
             key: "bob".as_bytes().to_vec(),
             value: "new-value".as_bytes().to_vec(),
             rec_type: LogRecordType::Normal,
+            expire: 0,
+            keyspace: Default::default(),
         };
         let write_res2 = data_file1.write(&enc2.encode());
         assert!(write_res2.is_ok());
@@ -314,6 +728,8 @@ mod tests {
             key: "jack".as_bytes().to_vec(),
             value: "he is a teacher".as_bytes().to_vec(),
             rec_type: LogRecordType::Normal,
+            expire: 0,
+            keyspace: Default::default(),
         };
         let write_res3 = data_file1.write(&enc3.encode());
         assert!(write_res3.is_ok());
@@ -1,17 +1,41 @@
 use bytes::{BufMut, BytesMut};
 use prost::{
     encode_length_delimiter,
-    encoding::{decode_varint, encode_varint},
+    encoding::{decode_varint, encode_varint, encoded_len_varint},
     length_delimiter_len,
 };
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::cipher::Cipher;
+use crate::{
+    error::{Errors, Result},
+    options::Compression,
+};
+
+/// 类型字节中标识记录带有过期时间的位
+pub(crate) const EXPIRE_FLAG: u8 = 0x08;
+/// 类型字节中标识记录属于某个 keyspace 的位
+pub(crate) const KEYSPACE_FLAG: u8 = 0x10;
+/// 类型字节中标识 value 使用 lz4 压缩的位
+pub(crate) const LZ4_FLAG: u8 = 0x20;
+/// 类型字节中标识 value 使用 zstd 压缩的位
+pub(crate) const ZSTD_FLAG: u8 = 0x40;
+/// 类型字节中标识 keyspace、key 和 value 经过加密的位
+pub(crate) const ENCRYPTION_FLAG: u8 = 0x80;
+/// 类型字节中存放记录类型的位
+const REC_TYPE_MASK: u8 = 0x07;
 
 /// LogRecord 写入到数据文件的记录
 /// 之所以叫日志，是因为数据文件中的数据是追加写入的，类似日志的格式
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct LogRecord {
     pub(crate) key: Vec<u8>,
     pub(crate) value: Vec<u8>,
     pub(crate) rec_type: LogRecordType,
+    /// 过期时间，纳秒级时间戳，0 表示永不过期
+    pub(crate) expire: u64,
+    /// 记录所属的 keyspace，为空表示默认的 keyspace
+    pub(crate) keyspace: Vec<u8>,
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -24,14 +48,27 @@ pub enum LogRecordType {
 
     /// 事务完成的标识
     Txnfinished = 2,
+
+    /// 整个 keyspace 被删除的标识
+    KeyspaceDropped = 3,
+
+    /// 范围删除的标识，key 为范围的下界，value 为范围的上界
+    RangeDeleted = 4,
 }
 
 impl LogRecordType {
+    /// 判断类型字节中是否是有效的记录类型
+    pub(crate) fn is_valid(v: u8) -> bool {
+        v & REC_TYPE_MASK <= LogRecordType::RangeDeleted as u8
+    }
+
     pub fn from_u8(v: u8) -> Self {
-        match v {
+        match v & REC_TYPE_MASK {
             0 => LogRecordType::Normal,
             1 => LogRecordType::Deleted,
             2 => LogRecordType::Txnfinished,
+            3 => LogRecordType::KeyspaceDropped,
+            4 => LogRecordType::RangeDeleted,
             _ => panic!("unknown log record type"),
         }
     }
@@ -43,8 +80,19 @@ impl LogRecordPos {
         encode_varint(self.file_id as u64, &mut buf);
         encode_varint(self.offset, &mut buf);
         encode_varint(self.size as u64, &mut buf);
+        encode_varint(self.expire, &mut buf);
+        // 不知道 value 长度的旧版本位置信息按照旧的格式编码，这时也没有写入版本号
+        if let Some(value_size) = self.value_size {
+            encode_varint(value_size as u64, &mut buf);
+            encode_varint(self.version, &mut buf);
+        }
         buf.to_vec()
     }
+
+    /// 判断该位置上的数据是否已经过期
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.expire > 0 && self.expire <= now
+    }
 }
 
 /// 数据位置索引信息， 描述数据存储到了哪个位置
@@ -56,6 +104,12 @@ pub struct LogRecordPos {
     pub(crate) offset: u64,
     /// 数据在磁盘上的占据的空间大小
     pub(crate) size: u32,
+    /// 过期时间，纳秒级时间戳，0 表示永不过期
+    pub(crate) expire: u64,
+    /// value 的长度，遍历时不需要读取数据文件就可以拿到，旧版本的位置信息中没有，为 None
+    pub(crate) value_size: Option<u32>,
+    /// 写入版本号，每次写入递增，同一个事务中的数据相同，旧版本的位置信息中没有，为 0
+    pub(crate) version: u64,
 }
 
 /// 从数据文件中读取的 log_record 信息，包含其 size
@@ -74,35 +128,85 @@ pub struct TransactionRecord {
 impl LogRecord {
     /// encode 对 LogRecord 进行编码，返回字节数组及长度
     ///
-    /// +-------------+--------------+-------------+--------------+-------------+-------------+
-    /// |  type 类型   |    key size |   value size |      key    |      value   |  crc 校验值|
-    /// +-------------+-------------+--------------+--------------+-------------+-------------+
-    ///  1字节        变长（最大5）   变长（最大5）        变长           变长           4字节
+    /// +-----------+--------------+---------------+-------------+--------------+------------+---------+---------+-----------+
+    /// |  type 类型 |   expire     | keyspace size |   key size  |  value size  |  keyspace  |   key   |  value  | crc 校验值 |
+    /// +-----------+--------------+---------------+-------------+--------------+------------+---------+---------+-----------+
+    ///  1字节       变长（最大10）   变长（最大5）     变长（最大5）   变长（最大5）     变长         变长       变长       4字节
+    ///
+    /// type 的低 3 位存放记录类型，高位存放标识位，只有设置了 EXPIRE_FLAG 时才会写入 expire 字段，
+    /// 只有设置了 KEYSPACE_FLAG 时才会写入 keyspace 相关的字段，所以默认的记录和旧格式保持一致
+    /// value 被压缩时会设置 LZ4_FLAG 或 ZSTD_FLAG，此时 value size 是压缩之后的长度
+    /// 加密时会设置 ENCRYPTION_FLAG，keyspace、key 和 value 整体加密，密文前后分别是随机数和认证标签
     pub fn encode(&self) -> Vec<u8> {
         let (enc_buf, _) = self.encode_and_get_crc();
         enc_buf
     }
 
+    /// 按照给定的压缩算法和加密方式进行编码
+    /// 只压缩正常的数据，压缩之后没有变小则不压缩；加密时 keyspace、key 和 value 整体加密，header 参与认证
+    pub(crate) fn encode_with(&self, compression: Compression, cipher: Option<&Cipher>) -> Vec<u8> {
+        let (enc_buf, _) = self.encode_with_flags(compression, cipher);
+        enc_buf
+    }
+
     pub fn get_crc(&self) -> u32 {
         let (_, crc_value) = self.encode_and_get_crc();
         crc_value
     }
 
     pub fn encode_and_get_crc(&self) -> (Vec<u8>, u32) {
+        self.encode_with_flags(Compression::None, None)
+    }
+
+    fn encode_with_flags(
+        &self,
+        compression: Compression,
+        cipher: Option<&Cipher>,
+    ) -> (Vec<u8>, u32) {
+        // 压缩 value，并设置对应的标识
+        let compressed = self.compress_value(compression);
+        let (value, mut flags) = match &compressed {
+            Some((value, flag)) => (value.as_slice(), *flag),
+            None => (self.value.as_slice(), 0),
+        };
+        if cipher.is_some() {
+            flags |= ENCRYPTION_FLAG;
+        }
+
         // 初始化字节数组，存放编码数据
         let mut buf = BytesMut::new();
         buf.reserve(self.encoded_length());
 
         // 第一个字节存放 Type 类型
-        buf.put_u8(self.rec_type as u8);
+        buf.put_u8(self.type_byte() | flags);
+
+        // 如果设置了过期时间，则存储过期时间
+        if self.expire > 0 {
+            encode_varint(self.expire, &mut buf);
+        }
+
+        // 如果属于某个 keyspace，则存储 keyspace 的长度
+        if !self.keyspace.is_empty() {
+            encode_length_delimiter(self.keyspace.len(), &mut buf).unwrap();
+        }
 
         // 再存储 key 和 value 的长度
         encode_length_delimiter(self.key.len(), &mut buf).unwrap();
-        encode_length_delimiter(self.value.len(), &mut buf).unwrap();
+        encode_length_delimiter(value.len(), &mut buf).unwrap();
 
-        // 存储 key 和 value
-        buf.extend_from_slice(&self.key);
-        buf.extend_from_slice(&self.value);
+        // 存储 keyspace、key 和 value，加密时使用 header 作为附加认证数据
+        match cipher {
+            Some(cipher) => {
+                let body = [self.keyspace.as_slice(), &self.key, value].concat();
+                let enc_body = cipher.encrypt(&buf, &body);
+                buf.extend_from_slice(&enc_body);
+            }
+            None => {
+                buf.extend_from_slice(&self.keyspace);
+                buf.extend_from_slice(&self.key);
+                buf.extend_from_slice(value);
+            }
+        }
 
         // 计算并存储 CRC 校验值
         let mut hasher = crc32fast::Hasher::new();
@@ -113,9 +217,47 @@ impl LogRecord {
         (buf.to_vec(), crc)
     }
 
+    /// 按照给定的压缩算法压缩 value，返回压缩之后的数据和对应的标识，不需要压缩时返回 None
+    fn compress_value(&self, compression: Compression) -> Option<(Vec<u8>, u8)> {
+        if self.rec_type != LogRecordType::Normal || self.value.is_empty() {
+            return None;
+        }
+        let (value, flag) = match compression {
+            Compression::None => return None,
+            Compression::Lz4 => (lz4_flex::compress_prepend_size(&self.value), LZ4_FLAG),
+            Compression::Zstd => (zstd::encode_all(self.value.as_slice(), 0).ok()?, ZSTD_FLAG),
+        };
+        if value.len() >= self.value.len() {
+            return None;
+        }
+        Some((value, flag))
+    }
+
+    /// 类型字节，包含记录类型及标识位
+    fn type_byte(&self) -> u8 {
+        let mut type_byte = self.rec_type as u8;
+        if self.expire > 0 {
+            type_byte |= EXPIRE_FLAG;
+        }
+        if !self.keyspace.is_empty() {
+            type_byte |= KEYSPACE_FLAG;
+        }
+        type_byte
+    }
+
     /// LogRecord 编码后的长度
     fn encoded_length(&self) -> usize {
+        let expire_len = match self.expire > 0 {
+            true => encoded_len_varint(self.expire),
+            false => 0,
+        };
+        let keyspace_len = match self.keyspace.is_empty() {
+            true => 0,
+            false => length_delimiter_len(self.keyspace.len()) + self.keyspace.len(),
+        };
         std::mem::size_of::<u8>()
+            + expire_len
+            + keyspace_len
             + length_delimiter_len(self.key.len())
             + length_delimiter_len(self.value.len())
             + self.key.len()
@@ -124,10 +266,35 @@ impl LogRecord {
     }
 }
 
+/// 根据类型字节中的压缩标识解压 value
+pub(crate) fn decompress_value(type_byte: u8, value: Vec<u8>) -> Result<Vec<u8>> {
+    if type_byte & LZ4_FLAG == LZ4_FLAG {
+        return lz4_flex::decompress_size_prepended(&value).map_err(|_| Errors::DecompressFailed);
+    }
+    if type_byte & ZSTD_FLAG == ZSTD_FLAG {
+        return zstd::decode_all(value.as_slice()).map_err(|_| Errors::DecompressFailed);
+    }
+    Ok(value)
+}
+
 /// 获得 LogRecord header 部分的最大长度
 pub fn max_log_record_header_size() -> usize {
     use prost::length_delimiter_len;
-    size_of::<u8>() + length_delimiter_len(u32::MAX as usize) * 2
+    size_of::<u8>() + encoded_len_varint(u64::MAX) + length_delimiter_len(u32::MAX as usize) * 3
+}
+
+/// 获取当前的纳秒级时间戳，用于判断数据是否过期
+pub(crate) fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos() as u64
+}
+
+/// 计算 ttl 之后的过期时间戳，超出范围时取最大的时间戳
+pub(crate) fn expire_after(ttl: Duration) -> u64 {
+    let now = now_nanos();
+    u64::try_from(ttl.as_nanos()).map_or(u64::MAX, |ttl| now.saturating_add(ttl))
 }
 
 /// 解码 LogRecordPos
@@ -147,10 +314,37 @@ pub fn decode_log_record_pos(pos: Vec<u8>) -> LogRecordPos {
         Ok(size) => size,
         Err(e) => panic!("deocde log record pos err: {}", e),
     };
+    // 旧版本的位置信息中没有过期时间
+    let mut expire = 0;
+    if !buf.is_empty() {
+        expire = match decode_varint(&mut buf) {
+            Ok(expire) => expire,
+            Err(e) => panic!("deocde log record pos err: {}", e),
+        };
+    }
+    // 旧版本的位置信息中没有 value 的长度
+    let mut value_size = None;
+    if !buf.is_empty() {
+        value_size = match decode_varint(&mut buf) {
+            Ok(value_size) => Some(value_size as u32),
+            Err(e) => panic!("deocde log record pos err: {}", e),
+        };
+    }
+    // 旧版本的位置信息中没有写入版本号
+    let mut version = 0;
+    if !buf.is_empty() {
+        version = match decode_varint(&mut buf) {
+            Ok(version) => version,
+            Err(e) => panic!("deocde log record pos err: {}", e),
+        };
+    }
     LogRecordPos {
         file_id: fid as u32,
         offset,
         size: size as u32,
+        expire,
+        value_size,
+        version,
     }
 }
 
@@ -165,6 +359,8 @@ mod tests {
             key: "name".as_bytes().to_vec(),
             value: "bitcask-rs".as_bytes().to_vec(),
             rec_type: LogRecordType::Normal,
+            expire: 0,
+            keyspace: Default::default(),
         };
         let _ = rec1.encode();
 
@@ -173,6 +369,8 @@ mod tests {
             key: "name".as_bytes().to_vec(),
             value: Default::default(),
             rec_type: LogRecordType::Normal,
+            expire: 0,
+            keyspace: Default::default(),
         };
         let enc2 = rec2.encode();
         assert!(enc2.len() > 5);
@@ -182,7 +380,97 @@ mod tests {
             key: "name".as_bytes().to_vec(),
             value: "bitcask-rs".as_bytes().to_vec(),
             rec_type: LogRecordType::Deleted,
+            expire: 0,
+            keyspace: Default::default(),
         };
         let _ = rec3.encode();
+
+        // 带有过期时间的情况
+        let rec4 = LogRecord {
+            key: "name".as_bytes().to_vec(),
+            value: "bitcask-rs".as_bytes().to_vec(),
+            rec_type: LogRecordType::Normal,
+            expire: now_nanos(),
+            keyspace: Default::default(),
+        };
+        let enc4 = rec4.encode();
+        assert_eq!(enc4[0] & EXPIRE_FLAG, EXPIRE_FLAG);
+        assert_eq!(LogRecordType::from_u8(enc4[0]), LogRecordType::Normal);
+        assert!(enc4.len() > rec1.encode().len());
+
+        // 属于某个 keyspace 的情况
+        let rec5 = LogRecord {
+            key: "name".as_bytes().to_vec(),
+            value: "bitcask-rs".as_bytes().to_vec(),
+            rec_type: LogRecordType::Normal,
+            expire: 0,
+            keyspace: "users".as_bytes().to_vec(),
+        };
+        let enc5 = rec5.encode();
+        assert_eq!(enc5[0] & KEYSPACE_FLAG, KEYSPACE_FLAG);
+        assert_eq!(LogRecordType::from_u8(enc5[0]), LogRecordType::Normal);
+        assert_eq!(enc5.len(), rec1.encode().len() + 1 + 5);
+    }
+
+    #[test]
+    fn test_log_record_compression() {
+        let rec = LogRecord {
+            key: "name".as_bytes().to_vec(),
+            value: "{\"name\":\"bitcask-rs\"}".repeat(100).into_bytes(),
+            rec_type: LogRecordType::Normal,
+            expire: 0,
+            keyspace: Vec::new(),
+        };
+        for (compression, flag) in [(Compression::Lz4, LZ4_FLAG), (Compression::Zstd, ZSTD_FLAG)] {
+            let enc = rec.encode_with(compression, None);
+            assert_eq!(enc[0] & flag, flag);
+            assert_eq!(LogRecordType::from_u8(enc[0]), LogRecordType::Normal);
+            assert!(enc.len() < rec.encode().len());
+        }
+        assert_eq!(rec.encode(), rec.encode_with(Compression::None, None));
+
+        // 压缩之后没有变小的数据保持原样
+        let rec2 = LogRecord {
+            value: "a".as_bytes().to_vec(),
+            ..rec.clone()
+        };
+        assert_eq!(rec2.encode(), rec2.encode_with(Compression::Zstd, None));
+
+        let compressed = lz4_flex::compress_prepend_size(&rec.value);
+        assert_eq!(rec.value, decompress_value(LZ4_FLAG, compressed).unwrap());
+        assert_eq!(
+            Errors::DecompressFailed,
+            decompress_value(ZSTD_FLAG, vec![1, 2, 3]).err().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_log_record_pos_encode() {
+        let pos = LogRecordPos {
+            file_id: 3,
+            offset: 100,
+            size: 20,
+            expire: 0,
+            value_size: Some(10),
+            version: 5,
+        };
+        let dec = decode_log_record_pos(pos.encode());
+        assert_eq!(3, dec.file_id);
+        assert_eq!(100, dec.offset);
+        assert_eq!(20, dec.size);
+        assert_eq!(Some(10), dec.value_size);
+        assert_eq!(5, dec.version);
+
+        // 旧版本的位置信息中没有 value 的长度，解码之后为 None，再次编码保持不变
+        let legacy = LogRecordPos {
+            value_size: None,
+            version: 0,
+            ..pos
+        }
+        .encode();
+        let dec = decode_log_record_pos(legacy.clone());
+        assert_eq!(None, dec.value_size);
+        assert_eq!(0, dec.version);
+        assert_eq!(legacy, dec.encode());
     }
 }
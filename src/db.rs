@@ -1,29 +1,55 @@
 use crate::{
+    auto_merge::AutoMergeWorker,
     batch::{log_record_key_with_seq, parse_log_record_key, NON_TRANSCATION_SEQ_NO},
     data::{
-        data_file::{DataFile, DATA_FILE_NAME_SUFFIX, MERGE_FINISHED_FILE_NAME, SEQ_FILE_NAME},
-        log_record::{LogRecord, LogRecordPos, LogRecordType, TransactionRecord},
+        cipher::Cipher,
+        data_file::{
+            get_data_file_name, DataFile, DATA_FILE_NAME_SUFFIX, MERGE_FINISHED_FILE_NAME,
+            SEQ_FILE_NAME,
+        },
+        log_record::{
+            expire_after, now_nanos, LogRecord, LogRecordPos, LogRecordType, TransactionRecord,
+        },
     },
     error::{Errors, Result},
     index,
-    merge::load_merge_files,
-    options::{IOType, IndexType, Options},
+    merge::{load_merge_files, KeptTombstones, RetiredFile},
+    options::{Compression, IOType, IndexType, IteratorOptions, Options},
+    snapshot::SnapshotVersions,
+    transaction::TxnTracker,
 };
 use bytes::Bytes;
 use fs2::FileExt;
 use log::warn;
 use parking_lot::{Mutex, RwLock};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
     fs::{self, File},
-    path::PathBuf,
+    ops::{Bound, Deref},
+    path::{Path, PathBuf},
     sync::{atomic::AtomicUsize, Arc},
+    time::Duration,
 };
 
 const INITIAL_FILE_ID: u32 = 0;
 const SEQ_NO_KEY: &str = "seq.no";
+const WRITE_VERSION_KEY: &str = "write.version";
 pub(crate) const FILE_LOCK_NAME: &str = "flock";
 
+/// key 对应的元数据，只从内存索引中获取，不需要读取数据文件
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Metadata {
+    /// value 的长度
+    pub value_size: u32,
+    /// 数据所在的文件 id
+    pub file_id: u32,
+    /// 数据在文件中的偏移
+    pub offset: u64,
+    /// 写入序列号，每次写入递增，序列号越大写入的越晚，同一个 WriteBatch 或事务中的数据相同
+    /// 内存索引在 merge 之后重启时会重新编号，但是先后顺序不变，旧版本的 B+ 树索引中没有，为 0
+    pub write_seq: u64,
+}
+
 /// 存储引擎相关统计信息
 #[derive(Debug)]
 pub struct Stat {
@@ -39,36 +65,83 @@ pub struct Stat {
 
 /// bitcask 存储引擎实例结构体
 pub struct Engine {
-    pub(crate) options: Arc<Options>,
+    /// 数据库实例的共享数据，后台线程持有弱引用
+    inner: Arc<EngineInner>,
+    /// 后台自动 merge 的线程
+    auto_merge_worker: Mutex<Option<AutoMergeWorker>>,
+}
+
+/// 存储引擎实例和后台线程共享的数据，最后一个引用释放时关闭数据库
+pub struct EngineInner {
+    pub(crate) options: Options,
     /// 当前活跃数据文件
-    pub(crate) active_file: Arc<RwLock<DataFile>>,
+    pub(crate) active_file: RwLock<DataFile>,
     /// 旧的数据文件
-    pub(crate) older_files: Arc<RwLock<HashMap<u32, DataFile>>>,
+    pub(crate) older_files: RwLock<HashMap<u32, DataFile>>,
+    /// 记录加解密使用的算法，None 表示不加密
+    pub(crate) cipher: Option<Cipher>,
     /// 数据内存索引
-    pub(crate) index: Box<dyn index::Indexer>,
+    pub(crate) index: Arc<dyn index::Indexer>,
+    /// 各个 keyspace 的内存索引
+    pub(crate) keyspaces: RwLock<HashMap<String, Arc<dyn index::Indexer>>>,
     /// 数据库启动时的文件 id，只用于加载索引时使用，不能在其他的地方更新或使用
     file_ids: Vec<u32>,
-    /// 事务提交保证串行化
+    /// 写入串行化锁，put、delete、条件写以及事务提交都需要持有，保证读取和写入之间的原子性
     pub(crate) batch_commit_lock: Mutex<()>,
     /// 事务序列号，全局递增
-    pub(crate) seq_no: Arc<AtomicUsize>,
+    pub(crate) seq_no: AtomicUsize,
     /// 防止多个线程同时 merge
     pub(crate) merging_lock: Mutex<()>,
     /// 事务序列号是否存在
     pub(crate) seq_file_exists: bool,
     /// 是否第一词初始化该目录
     pub(crate) is_initial: bool,
-    /// 文件锁，保证只能在数据目录上打开一个实例
-    lock_file: File,
+    /// 文件锁，保证只能在数据目录上打开一个实例，只读打开时锁文件可能不存在
+    lock_file: Option<File>,
     /// 累计写入了多少字节
-    bytes_write: Arc<AtomicUsize>,
+    bytes_write: AtomicUsize,
     /// 累计有多少空间可以 merge
-    pub(crate) reclaim_size: Arc<AtomicUsize>,
+    pub(crate) reclaim_size: AtomicUsize,
+    /// 每个数据文件中有多少空间可以 merge，和 reclaim_size 同时更新
+    pub(crate) file_reclaim_sizes: RwLock<HashMap<u32, usize>>,
+    /// merge 时保留的标识，更早的文件 merge 之后计入可以回收的空间
+    pub(crate) kept_tombstones: Mutex<HashMap<u32, KeptTombstones>>,
+    /// merge 在线替换数据文件的次数，读取期间次数发生变化时需要重新查找索引
+    pub(crate) merge_generation: AtomicUsize,
+    /// merge 替换掉的旧数据文件，替换之前创建的快照仍然需要从中读取数据
+    pub(crate) retired_files: RwLock<Vec<RetiredFile>>,
+    /// 存活的快照创建时的 merge 次数，以及对应的快照数量
+    pub(crate) snapshot_generations: Mutex<BTreeMap<usize, usize>>,
+    /// 存活的快照中保存的修改之前的位置，修改默认 keyspace 的索引之前需要更新
+    pub(crate) snapshots: RwLock<Vec<Arc<SnapshotVersions>>>,
+    /// 进行中的事务以及事务开始之后被修改过的 key，用于检测事务冲突
+    pub(crate) txn_tracker: Mutex<TxnTracker>,
+    /// 是否以只读的方式打开，不会修改数据目录中的任何文件
+    read_only: bool,
+}
+
+impl Deref for Engine {
+    type Target = EngineInner;
+
+    fn deref(&self) -> &EngineInner {
+        &self.inner
+    }
 }
 
 impl Engine {
     /// 打开 bitcask 存储引擎实例
     pub fn open(opts: Options) -> Result<Self> {
+        Self::open_with(opts, false)
+    }
+
+    /// 以只读的方式打开数据目录，用于离线校验
+    /// 不应用 merge 的结果，不截断末尾没有完整写入的数据，关闭时也不写入事务序列号
+    /// 不删除事务序列号文件，也不创建锁文件和 B+ 树的索引文件
+    pub(crate) fn open_read_only(opts: Options) -> Result<Self> {
+        Self::open_with(opts, true)
+    }
+
+    fn open_with(opts: Options, read_only: bool) -> Result<Self> {
         // 校验用户传递过来的配置项
         check_options(&opts)?;
 
@@ -79,6 +152,9 @@ impl Engine {
         let dir_path = options.dir_path.clone();
 
         if !dir_path.is_dir() {
+            if read_only {
+                return Err(Errors::FailedReadDatabaseDir);
+            }
             is_initial = true;
             if let Err(e) = fs::create_dir_all(dir_path.clone()) {
                 warn!("create database directory err: {}", e);
@@ -87,17 +163,7 @@ impl Engine {
         }
 
         // 判断数据目录是否已经被使用了
-        let lock_file = fs::OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .truncate(false)
-            .open(dir_path.join(FILE_LOCK_NAME))
-            .unwrap();
-
-        if lock_file.try_lock_exclusive().is_err() {
-            return Err(Errors::DatabaseIsUsing);
-        }
+        let lock_file = lock_data_dir(&dir_path, read_only)?;
 
         let entries = fs::read_dir(dir_path.clone()).unwrap();
         if entries.count() == 0 {
@@ -105,10 +171,18 @@ impl Engine {
         }
 
         // 加载 merge 数据目录
-        load_merge_files(dir_path.clone())?;
+        let cipher = Cipher::new(&options.encryption);
+        if !read_only {
+            load_merge_files(dir_path.clone(), cipher.clone())?;
+        }
 
         // 加载数据文件
-        let mut data_files = load_data_files(dir_path.clone(), options.mmap_at_startup)?;
+        let mut data_files = load_data_files(
+            dir_path.clone(),
+            options.mmap_at_startup,
+            cipher.clone(),
+            read_only,
+        )?;
 
         // 设置 file id 信息
         let mut file_ids = Vec::new();
@@ -138,24 +212,36 @@ impl Engine {
                 dir_path.clone(),
                 INITIAL_FILE_ID,
                 crate::options::IOType::StandardFIO,
+                cipher.clone(),
             )?,
         };
 
-        let mut engine = Self {
-            options: Arc::new(opts),
-            active_file: Arc::new(RwLock::new(active_file)),
-            older_files: Arc::new(RwLock::new(older_files)),
-            index: index::new_indexer(options.index_type, options.dir_path),
+        let inner = EngineInner {
+            options: opts,
+            active_file: RwLock::new(active_file),
+            older_files: RwLock::new(older_files),
+            cipher,
+            index: open_index(&options, read_only),
+            keyspaces: RwLock::new(HashMap::new()),
             file_ids,
             batch_commit_lock: Mutex::new(()),
-            seq_no: Arc::new(AtomicUsize::new(1)),
+            seq_no: AtomicUsize::new(1),
             merging_lock: Mutex::new(()),
             seq_file_exists: false,
             is_initial,
             lock_file,
-            bytes_write: Arc::new(AtomicUsize::new(0)),
-            reclaim_size: Arc::new(AtomicUsize::new(0)),
+            bytes_write: AtomicUsize::new(0),
+            reclaim_size: AtomicUsize::new(0),
+            file_reclaim_sizes: RwLock::new(HashMap::new()),
+            kept_tombstones: Mutex::new(HashMap::new()),
+            merge_generation: AtomicUsize::new(0),
+            retired_files: RwLock::new(Vec::new()),
+            snapshot_generations: Mutex::new(BTreeMap::new()),
+            snapshots: RwLock::new(Vec::new()),
+            txn_tracker: Mutex::new(TxnTracker::default()),
+            read_only,
         };
+        let mut engine = Engine::from_inner(Arc::new(inner));
 
         // B+ 树不需要从数据文件中加载索引
         if engine.options.index_type != IndexType::BPlusTree {
@@ -177,47 +263,74 @@ impl Engine {
                 engine.reset_io_type()?;
             }
         } else {
+            // 打开已经存在的 keyspace 索引
+            for keyspace in index::bptree::list_keyspaces(options.dir_path.clone()) {
+                if !read_only {
+                    engine.keyspace_index(&keyspace);
+                } else if let Some(bptree) =
+                    index::bptree::BPlusTree::open_existing(options.dir_path.clone(), &keyspace)
+                {
+                    engine.keyspaces.write().insert(keyspace, Arc::new(bptree));
+                }
+            }
+
             // 加载事务序列号
-            let (exists, seq_no) = engine.load_seq_no();
+            let (exists, seq_no) = engine.load_seq_no()?;
             if exists {
                 engine
                     .seq_no
                     .store(seq_no, std::sync::atomic::Ordering::SeqCst);
-                engine.seq_file_exists = exists;
+                // 打开期间还没有其他的引用
+                Arc::get_mut(&mut engine.inner).unwrap().seq_file_exists = exists;
+            }
+            // 没有正常关闭时从索引中找到最大的写入版本号
+            if engine.txn_tracker.lock().version() == 0 {
+                let version = engine.max_index_version();
+                engine.txn_tracker.lock().advance_to(version);
             }
 
-            // 设置当前活跃文件的偏移
+            // 设置当前活跃文件的偏移，末尾没有完整写入的数据直接截断
             let active_file = engine.active_file.write();
-            active_file.set_write_off(active_file.file_size());
+            let mut offset = active_file.first_record_offset();
+            loop {
+                match active_file.read_log_record(offset) {
+                    Ok(result) => offset += result.size,
+                    Err(Errors::ReadDataFileEOF) => break,
+                    Err(e) if is_torn_tail(&active_file, offset, &e) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            match read_only {
+                true => active_file.set_write_off(offset),
+                false => truncate_torn_tail(&active_file, offset)?,
+            }
+        }
+
+        // 启动后台自动 merge 的线程
+        if let Some(auto_merge) = engine.options.auto_merge.clone().filter(|_| !read_only) {
+            let worker = AutoMergeWorker::start(Arc::downgrade(&engine.inner), auto_merge);
+            *engine.auto_merge_worker.lock() = Some(worker);
         }
 
         Ok(engine)
     }
 
+    /// 使用共享的数据构造实例，不会启动后台线程
+    pub(crate) fn from_inner(inner: Arc<EngineInner>) -> Self {
+        Engine {
+            inner,
+            auto_merge_worker: Mutex::new(None),
+        }
+    }
+
     /// 关闭数据库，释放相关资源
     pub fn close(&self) -> Result<()> {
-        // 如果语句目录不存在则返回
-        if !self.options.dir_path.is_dir() {
-            return Ok(());
+        // 先停止后台自动 merge 的线程
+        if let Some(worker) = self.auto_merge_worker.lock().take() {
+            worker.stop();
         }
-        // 记录当前事务序列号
-        let seq_no_file = DataFile::new_seq_no_file(self.options.dir_path.clone())?;
-        let seq_no = self.seq_no.load(std::sync::atomic::Ordering::SeqCst);
-        let record = LogRecord {
-            key: SEQ_NO_KEY.as_bytes().to_vec(),
-            value: seq_no.to_string().into_bytes(),
-            rec_type: LogRecordType::Normal,
-        };
-        seq_no_file.write(&record.encode())?;
-        seq_no_file.sync()?;
-
-        let read_guard = self.active_file.read();
-        read_guard.sync()?;
-
-        // 释放文件锁
-        self.lock_file.unlock().unwrap();
 
-        Ok(())
+        self.inner.close()
     }
 
     /// 持久化当前活跃文件
@@ -252,39 +365,210 @@ impl Engine {
 
     /// 存储 key/value 数据，key 不能为空
     pub fn put(&self, key: Bytes, value: Bytes) -> Result<()> {
+        self.put_with_expire(key, value, 0)
+    }
+
+    /// 存储 key/value 数据，并设置过期时间，过期之后数据不可见
+    pub fn put_with_ttl(&self, key: Bytes, value: Bytes, ttl: Duration) -> Result<()> {
+        let expire = expire_after(ttl);
+        self.put_with_expire(key, value, expire)
+    }
+
+    /// 存储 key/value 数据，expire 为纳秒级的过期时间戳，0 表示永不过期
+    fn put_with_expire(&self, key: Bytes, value: Bytes, expire: u64) -> Result<()> {
+        // 判断 key 的有效性
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        let _lock = self.batch_commit_lock.lock();
+        self.put_without_lock(&key, value, expire)
+    }
+
+    /// 根据 key 删除对应的数据
+    pub fn delete(&self, key: Bytes) -> Result<()> {
         // 判断 key 的有效性
         if key.is_empty() {
             return Err(Errors::KeyIsEmpty);
         }
 
+        let _lock = self.batch_commit_lock.lock();
+        self.delete_without_lock(&key)
+    }
+
+    /// 删除 [start, end) 范围内的所有数据，只需要写入一条范围删除的记录
+    pub fn delete_range(&self, start: Bytes, end: Bytes) -> Result<()> {
+        // 判断 key 的有效性
+        if start.is_empty() || end.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        let _lock = self.batch_commit_lock.lock();
+        self.delete_range_from_index(self.index.as_ref(), &[], &start, &end)
+    }
+
+    /// 写入范围删除标识并删除 keyspace 对应的内存索引中的数据，调用方需要持有写锁
+    pub(crate) fn delete_range_from_index(
+        &self,
+        index: &dyn index::Indexer,
+        keyspace: &[u8],
+        start: &Bytes,
+        end: &Bytes,
+    ) -> Result<()> {
+        // 范围内没有数据的话直接返回
+        let keys = index_keys_in_range(index, start, Some(end));
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        // 构建 LogRecord，key 为范围的下界，value 为范围的上界
+        let mut record = LogRecord {
+            key: log_record_key_with_seq(start.to_vec(), NON_TRANSCATION_SEQ_NO),
+            value: end.to_vec(),
+            rec_type: LogRecordType::RangeDeleted,
+            expire: 0,
+            keyspace: keyspace.to_vec(),
+        };
+
+        // 写入到数据文件当中
+        let pos = self.append_log_record(&mut record)?;
+
+        // 删除内存索引中范围内的 key
+        self.save_snapshot_versions(keyspace, keys.iter().map(Vec::as_slice));
+        self.record_writes(keyspace, keys.iter().map(Vec::as_slice));
+        for old_pos in remove_index_keys(index, keys) {
+            self.add_reclaim_size(&old_pos);
+        }
+        self.add_reclaim_size(&pos);
+
+        Ok(())
+    }
+
+    /// 比较并交换，只有当前的值和 expected 相等时才写入 new，返回是否写入成功
+    /// expected 为 None 表示 key 不存在，new 为 None 表示删除 key
+    pub fn compare_and_swap(
+        &self,
+        key: Bytes,
+        expected: Option<Bytes>,
+        new: Option<Bytes>,
+    ) -> Result<bool> {
+        // 判断 key 的有效性
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        // 持有写锁，保证比较和写入之间没有其他的写操作
+        let _lock = self.batch_commit_lock.lock();
+        let current = self.get_without_lock(&key)?;
+        if current != expected {
+            return Ok(false);
+        }
+
+        match new {
+            Some(value) => self.put_without_lock(&key, value, 0)?,
+            None => self.delete_without_lock(&key)?,
+        }
+        Ok(true)
+    }
+
+    /// 只有 key 不存在时才写入数据，返回是否写入成功
+    pub fn put_if_absent(&self, key: Bytes, value: Bytes) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// 将 key 对应的整数加上 delta 并返回新的值，key 不存在时视为 0
+    /// 整数以十进制字符串的形式存储，原有的过期时间会被保留
+    pub fn incr_by(&self, key: Bytes, delta: i64) -> Result<i64> {
+        // 判断 key 的有效性
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        let _lock = self.batch_commit_lock.lock();
+        let (new_value, expire) = self.incr_value_without_lock(&key, delta)?;
+        self.put_without_lock(&key, encode_integer(new_value), expire)?;
+        Ok(new_value)
+    }
+
+    /// 计算 key 当前的整数值加上 delta 之后的值，同时返回原有的过期时间，调用方需要持有写锁
+    pub(crate) fn incr_value_without_lock(&self, key: &Bytes, delta: i64) -> Result<(i64, u64)> {
+        let (current, expire) = match self.get_without_lock(key)? {
+            Some(value) => {
+                let expire = self.index.get(key.to_vec()).map_or(0, |pos| pos.expire);
+                (decode_integer(&value)?, expire)
+            }
+            None => (0, 0),
+        };
+        let new_value = current.checked_add(delta).ok_or(Errors::IntegerOverflow)?;
+        Ok((new_value, expire))
+    }
+
+    /// 将 key 对应的整数减去 delta 并返回新的值，key 不存在时视为 0
+    pub fn decr_by(&self, key: Bytes, delta: i64) -> Result<i64> {
+        let delta = delta.checked_neg().ok_or(Errors::IntegerOverflow)?;
+        self.incr_by(key, delta)
+    }
+
+    /// 获取 key 对应的数据，key 不存在时返回 None，调用方需要持有写锁
+    pub(crate) fn get_without_lock(&self, key: &Bytes) -> Result<Option<Bytes>> {
+        let pos = self.index.get(key.to_vec());
+        match self.get_value_by_position(pos.as_ref()) {
+            Ok(value) => Ok(Some(value)),
+            Err(Errors::KeyNotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 写入数据并更新内存索引，调用方需要持有写锁
+    pub(crate) fn put_without_lock(&self, key: &Bytes, value: Bytes, expire: u64) -> Result<()> {
+        self.put_to_index(self.index.as_ref(), &[], key, value, expire)
+    }
+
+    /// 写入数据并更新 keyspace 对应的内存索引，调用方需要持有写锁
+    pub(crate) fn put_to_index(
+        &self,
+        index: &dyn index::Indexer,
+        keyspace: &[u8],
+        key: &Bytes,
+        value: Bytes,
+        expire: u64,
+    ) -> Result<()> {
         // 构造 LogRecord
         let mut record = LogRecord {
             key: log_record_key_with_seq(key.to_vec(), NON_TRANSCATION_SEQ_NO),
             value: value.to_vec(),
             rec_type: LogRecordType::Normal,
+            expire,
+            keyspace: keyspace.to_vec(),
         };
 
         // 追加写活跃文件到数据文件中
-        let log_record_pos = self.append_log_record(&mut record)?;
+        let mut log_record_pos = self.append_log_record(&mut record)?;
+        log_record_pos.version = self.record_writes(keyspace, [key.as_ref()]);
 
         // 更新内存索引
-        if let Some(old_pos) = self.index.put(key.to_vec(), log_record_pos) {
-            self.reclaim_size
-                .fetch_add(old_pos.size as usize, std::sync::atomic::Ordering::SeqCst);
+        self.save_snapshot_versions(keyspace, [key.as_ref()]);
+        if let Some(old_pos) = index.put(key.to_vec(), log_record_pos) {
+            self.add_reclaim_size(&old_pos);
         }
 
         Ok(())
     }
 
-    /// 根据 key 删除对应的数据
-    pub fn delete(&self, key: Bytes) -> Result<()> {
-        // 判断 key 的有效性
-        if key.is_empty() {
-            return Err(Errors::KeyIsEmpty);
-        }
+    /// 写入删除标识并删除内存索引，调用方需要持有写锁
+    pub(crate) fn delete_without_lock(&self, key: &Bytes) -> Result<()> {
+        self.delete_from_index(self.index.as_ref(), &[], key)
+    }
 
+    /// 写入删除标识并删除 keyspace 对应的内存索引，调用方需要持有写锁
+    pub(crate) fn delete_from_index(
+        &self,
+        index: &dyn index::Indexer,
+        keyspace: &[u8],
+        key: &Bytes,
+    ) -> Result<()> {
         // 从内存索引当中取出对应的数据，不存在的话直接返回
-        let pos = self.index.get(key.to_vec());
+        let pos = index.get(key.to_vec());
 
         if pos.is_none() {
             return Ok(());
@@ -295,21 +579,24 @@ impl Engine {
             key: log_record_key_with_seq(key.to_vec(), NON_TRANSCATION_SEQ_NO),
             value: Default::default(),
             rec_type: LogRecordType::Deleted,
+            expire: 0,
+            keyspace: keyspace.to_vec(),
         };
 
         // 写入到数据文件当中
         let pos = self.append_log_record(&mut record)?;
-        self.reclaim_size
-            .fetch_add(pos.size as usize, std::sync::atomic::Ordering::SeqCst);
+        self.add_reclaim_size(&pos);
 
         // 删除内存索引中对应的 key
-        if let Some(old_pos) = self.index.delete(key.to_vec()) {
-            self.reclaim_size
-                .fetch_add(old_pos.size as usize, std::sync::atomic::Ordering::SeqCst);
+        self.save_snapshot_versions(keyspace, [key.as_ref()]);
+        if let Some(old_pos) = index.delete(key.to_vec()) {
+            self.add_reclaim_size(&old_pos);
         }
+        self.record_writes(keyspace, [key.as_ref()]);
 
         Ok(())
     }
+
     /// 根据 key 获取对应的数据
     pub fn get(&self, key: Bytes) -> Result<Bytes> {
         // 判断 key 的有效性
@@ -318,32 +605,155 @@ impl Engine {
         }
 
         // 从内存索引中获取 key 对应的数据信息
-        let pos = self.index.get(key.to_vec());
+        self.read_consistent(|| {
+            let pos = self.index.get(key.to_vec());
+            self.get_value_by_position(pos.as_ref())
+        })
+    }
+
+    /// 判断 key 是否存在，只查询内存索引，已经过期的 key 视为不存在
+    pub fn exists(&self, key: Bytes) -> Result<bool> {
+        match self.metadata(key) {
+            Ok(_) => Ok(true),
+            Err(Errors::KeyNotFound) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 获取 key 对应的元数据，一般只查询内存索引
+    /// 旧版本的索引中没有 value 的长度，这时需要读取数据文件
+    pub fn metadata(&self, key: Bytes) -> Result<Metadata> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        let pos = match self.index.get(key.to_vec()) {
+            Some(pos) if !pos.is_expired(now_nanos()) => pos,
+            _ => return Err(Errors::KeyNotFound),
+        };
+        let value_size = match pos.value_size {
+            Some(value_size) => value_size,
+            None => self.get(key)?.len() as u32,
+        };
+        Ok(Metadata {
+            value_size,
+            file_id: pos.file_id,
+            offset: pos.offset,
+            write_seq: pos.version,
+        })
+    }
+
+    /// 批量获取多个 key 对应的数据，结果和 keys 一一对应
+    /// 先查出所有的索引信息，再按照文件分组、按 offset 排序，相邻的数据合并成一次读取
+    pub fn multi_get(&self, keys: &[Bytes]) -> Vec<Result<Bytes>> {
+        let now = now_nanos();
+        // 持有数据文件的读锁，查找索引和读取数据期间 merge 不会替换数据文件
+        let active_file = self.active_file.read();
+        let older_files = self.older_files.read();
+        let mut results: Vec<Result<Bytes>> =
+            keys.iter().map(|_| Err(Errors::KeyNotFound)).collect();
+
+        // 按照文件 id 对索引信息进行分组
+        let mut groups: HashMap<u32, Vec<(usize, LogRecordPos)>> = HashMap::new();
+        for (i, key) in keys.iter().enumerate() {
+            if key.is_empty() {
+                results[i] = Err(Errors::KeyIsEmpty);
+                continue;
+            }
+            if let Some(pos) = self.index.get(key.to_vec()) {
+                if !pos.is_expired(now) {
+                    groups.entry(pos.file_id).or_default().push((i, pos));
+                }
+            }
+        }
 
-        self.get_value_by_position(pos.as_ref())
+        for (file_id, mut items) in groups {
+            let data_file = match active_file.get_file_id() == file_id {
+                true => Some(&*active_file),
+                false => older_files.get(&file_id),
+            };
+            let data_file = match data_file {
+                Some(data_file) => data_file,
+                None => {
+                    for (i, _) in items {
+                        results[i] = Err(Errors::DataFileNotFound);
+                    }
+                    continue;
+                }
+            };
+
+            items.sort_by_key(|(_, pos)| pos.offset);
+            let positions: Vec<LogRecordPos> = items.iter().map(|(_, pos)| *pos).collect();
+            let records = data_file.read_log_records(&positions);
+            for ((i, _), record) in items.into_iter().zip(records) {
+                results[i] = record.and_then(|record| match record.rec_type {
+                    LogRecordType::Deleted => Err(Errors::KeyNotFound),
+                    _ => Ok(record.value.into()),
+                });
+            }
+        }
+        results
     }
 
     /// 根据索引信息获取 value
     pub(crate) fn get_value_by_position(&self, pos: Option<&LogRecordPos>) -> Result<Bytes> {
+        self.get_value_at(pos, now_nanos(), None)
+    }
+
+    /// 执行从索引中查找位置并读取数据的操作，读取期间 merge 替换了数据文件时重新执行
+    pub(crate) fn read_consistent<R>(&self, f: impl Fn() -> R) -> R {
+        loop {
+            let generation = self.merge_generation();
+            let result = f();
+            if generation == self.merge_generation() {
+                return result;
+            }
+        }
+    }
+
+    /// merge 在线替换数据文件的次数
+    pub(crate) fn merge_generation(&self) -> usize {
+        self.merge_generation
+            .load(std::sync::atomic::Ordering::SeqCst)
+    }
+
+    /// 根据索引信息获取 value，now 为判断数据是否过期的时间点
+    /// generation 为快照创建时 merge 替换数据文件的次数，之后被替换掉的文件从旧的文件中读取
+    pub(crate) fn get_value_at(
+        &self,
+        pos: Option<&LogRecordPos>,
+        now: u64,
+        generation: Option<usize>,
+    ) -> Result<Bytes> {
         // 从对应的数据文件中获取对应的 LogRecord
         if let Some(log_record_pos) = pos {
+            // 数据已经过期，视为不存在
+            if log_record_pos.is_expired(now) {
+                return Err(Errors::KeyNotFound);
+            }
+
             let active_file = self.active_file.read();
             let older_files = self.older_files.read();
-
-            // 查看活跃文件中是否是对应的数据文件
-            let log_record = match active_file.get_file_id() == log_record_pos.file_id {
-                true => active_file.read_log_record(log_record_pos.offset)?.record,
-
+            let retired_files = self.retired_files.read();
+
+            // 快照创建之后被 merge 替换掉的文件，从最早替换掉的旧文件中读取
+            let retired_file = generation.and_then(|generation| {
+                retired_files.iter().find(|retired| {
+                    retired.file_id == log_record_pos.file_id && retired.generation > generation
+                })
+            });
+
+            let data_file = match retired_file {
+                Some(retired) => Some(&retired.file),
+                // 查看活跃文件中是否是对应的数据文件
+                None if active_file.get_file_id() == log_record_pos.file_id => Some(&*active_file),
                 // 如果找不到，就去旧的活跃文件中去找
-                false => {
-                    let data_file = older_files.get(&log_record_pos.file_id);
-                    if let Some(data_file) = data_file {
-                        data_file.read_log_record(log_record_pos.offset)?.record
-                    } else {
-                        // 找不到对应的数据文件，返回错误
-                        return Err(Errors::DataFileNotFound);
-                    }
-                }
+                None => older_files.get(&log_record_pos.file_id),
+            };
+            let log_record = match data_file {
+                Some(data_file) => data_file.read_log_record(log_record_pos.offset)?.record,
+                // 找不到对应的数据文件，返回错误
+                None => return Err(Errors::DataFileNotFound),
             };
 
             // 判断 LogRecord 的类型
@@ -362,7 +772,7 @@ impl Engine {
     pub(crate) fn append_log_record(&self, log_record: &mut LogRecord) -> Result<LogRecordPos> {
         let dir_path = self.options.dir_path.clone();
         // 输入数据进行编码
-        let enc_record = log_record.encode();
+        let enc_record = log_record.encode_with(self.options.compression, self.cipher.as_ref());
         let record_len: u64 = enc_record.len() as u64;
 
         // 获取当前活跃文件
@@ -381,6 +791,7 @@ impl Engine {
                 dir_path.clone(),
                 current_fid,
                 crate::options::IOType::StandardFIO,
+                self.cipher.clone(),
             )?;
             older_files.insert(current_fid, old_file);
 
@@ -389,6 +800,7 @@ impl Engine {
                 dir_path.clone(),
                 current_fid + 1,
                 crate::options::IOType::StandardFIO,
+                self.cipher.clone(),
             )?;
             *active_file = new_file;
         }
@@ -421,6 +833,9 @@ impl Engine {
             file_id: active_file.get_file_id(),
             offset: write_off,
             size: enc_record.len() as u32,
+            expire: log_record.expire,
+            value_size: Some(log_record.value.len() as u32),
+            version: 0,
         })
     }
 
@@ -438,7 +853,8 @@ impl Engine {
         let mut non_merge_fid = 0;
         let merge_fin_file = self.options.dir_path.join(MERGE_FINISHED_FILE_NAME);
         if merge_fin_file.is_file() {
-            let merge_fin_file = DataFile::new_merge_fin_file(self.options.dir_path.clone())?;
+            let merge_fin_file =
+                DataFile::new_merge_fin_file(self.options.dir_path.clone(), self.cipher.clone())?;
             let merge_fin_record = merge_fin_file.read_log_record(0)?;
             let v = String::from_utf8(merge_fin_record.record.value).unwrap();
 
@@ -458,7 +874,11 @@ impl Engine {
             if has_merge && *file_id < non_merge_fid {
                 continue;
             }
-            let mut offset = 0;
+            // 跳过数据文件的头部
+            let mut offset = match *file_id == active_file.get_file_id() {
+                true => active_file.first_record_offset(),
+                false => older_files.get(file_id).unwrap().first_record_offset(),
+            };
             loop {
                 let log_record_res = match *file_id == active_file.get_file_id() {
                     true => active_file.read_log_record(offset),
@@ -473,38 +893,79 @@ impl Engine {
                     Err(e) => {
                         if e == Errors::ReadDataFileEOF {
                             break;
-                        } else {
-                            return Err(e);
                         }
+                        // 最新的数据文件末尾可能有没有完整写入的数据，之后截断
+                        let is_last_file = i == self.file_ids.len() - 1;
+                        if is_last_file && is_torn_tail(&active_file, offset, &e) {
+                            break;
+                        }
+                        return Err(e);
                     }
                 };
 
-                // 构建内存索引
-                let log_record_pos = LogRecordPos {
+                // 解析 key，拿到实际的 key 和 seq no
+                let (real_key, seq_no) = parse_log_record_key(log_record.key);
+
+                // 构建内存索引，事务中的数据在读取到完成标识时分配写入版本号
+                let mut log_record_pos = LogRecordPos {
                     file_id: *file_id,
                     offset,
                     size: size as u32,
+                    expire: log_record.expire,
+                    value_size: Some(log_record.value.len() as u32),
+                    version: 0,
                 };
+                if seq_no == NON_TRANSCATION_SEQ_NO {
+                    log_record_pos.version = self.txn_tracker.lock().next_version();
+                }
 
-                // 解析 key，拿到实际的 key 和 seq no
-                let (real_key, seq_no) = parse_log_record_key(log_record.key);
-
+                // keyspace 被删除，删除对应的内存索引
+                if log_record.rec_type == LogRecordType::KeyspaceDropped {
+                    let removed =
+                        self.remove_keyspace_index(&String::from_utf8_lossy(&log_record.keyspace));
+                    for old_pos in removed {
+                        self.add_reclaim_size(&old_pos);
+                    }
+                    self.add_reclaim_size(&log_record_pos);
+                }
+                // 范围删除，value 中存放的是范围的上界
+                else if log_record.rec_type == LogRecordType::RangeDeleted {
+                    let removed = self.with_index(&log_record.keyspace, |index| {
+                        let keys = index_keys_in_range(index, &real_key, Some(&log_record.value));
+                        remove_index_keys(index, keys)
+                    });
+                    for old_pos in removed {
+                        self.add_reclaim_size(&old_pos);
+                    }
+                    self.add_reclaim_size(&log_record_pos);
+                }
                 // 非事务提交的情况，直接更新内存索引
-                if seq_no == NON_TRANSCATION_SEQ_NO {
-                    self.update_index(real_key, log_record.rec_type, log_record_pos);
+                else if seq_no == NON_TRANSCATION_SEQ_NO {
+                    self.update_index(
+                        &log_record.keyspace,
+                        real_key,
+                        log_record.rec_type,
+                        log_record_pos,
+                    );
                 }
                 // 事务有提交的标识，更新内存索引
                 else if log_record.rec_type == LogRecordType::Txnfinished {
-                    let records: &Vec<TransactionRecord> =
-                        transaction_records.get(&seq_no).unwrap();
+                    // 事务的数据可能已经被 merge 重写或者清理，只剩下完成标识
+                    let records: Vec<TransactionRecord> =
+                        transaction_records.remove(&seq_no).unwrap_or_default();
+                    let version = self.txn_tracker.lock().next_version();
                     for txn_record in records.iter() {
                         self.update_index(
+                            &txn_record.record.keyspace,
                             txn_record.record.key.clone(),
                             txn_record.record.rec_type,
-                            txn_record.pos,
+                            LogRecordPos {
+                                version,
+                                ..txn_record.pos
+                            },
                         );
                     }
-                    transaction_records.remove(&seq_no);
+                    self.add_reclaim_size(&log_record_pos);
                 } else {
                     log_record.key = real_key;
                     transaction_records
@@ -525,51 +986,166 @@ impl Engine {
                 offset += size;
             }
 
-            // 设置活跃文件的 offset
+            // 没有提交的事务数据都是可以回收的空间
+            if i == self.file_ids.len() - 1 {
+                for txn_record in transaction_records.values().flatten() {
+                    self.add_reclaim_size(&txn_record.pos);
+                }
+            }
+
+            // 设置活跃文件的 offset，末尾没有完整写入的数据直接截断，只读打开时不截断
             if i == self.file_ids.len() - 1 {
-                active_file.set_write_off(offset);
+                match self.read_only {
+                    true => active_file.set_write_off(offset),
+                    false => truncate_torn_tail(&active_file, offset)?,
+                }
             }
         }
         Ok(current_seq_no)
     }
 
+    /// 记录 pos 对应的数据可以回收，同时更新所在数据文件的统计
+    pub(crate) fn add_reclaim_size(&self, pos: &LogRecordPos) {
+        self.reclaim_size
+            .fetch_add(pos.size as usize, std::sync::atomic::Ordering::SeqCst);
+        *self
+            .file_reclaim_sizes
+            .write()
+            .entry(pos.file_id)
+            .or_insert(0) += pos.size as usize;
+    }
+
     /// 加载索引时更新数据
-    fn update_index(&self, key: Vec<u8>, rec_type: LogRecordType, pos: LogRecordPos) {
-        if rec_type == LogRecordType::Normal {
-            if let Some(old_pos) = self.index.put(key.clone(), pos) {
-                self.reclaim_size
-                    .fetch_add(old_pos.size as usize, std::sync::atomic::Ordering::SeqCst);
+    fn update_index(
+        &self,
+        keyspace: &[u8],
+        key: Vec<u8>,
+        rec_type: LogRecordType,
+        pos: LogRecordPos,
+    ) {
+        // 已经过期的数据和被删除的数据一样处理
+        let rec_type = match rec_type == LogRecordType::Normal && pos.is_expired(now_nanos()) {
+            true => LogRecordType::Deleted,
+            false => rec_type,
+        };
+        let old_pos = self.with_index(keyspace, |index| match rec_type {
+            LogRecordType::Normal => index.put(key, pos),
+            LogRecordType::Deleted => {
+                self.add_reclaim_size(&pos);
+                index.delete(key)
             }
+            _ => None,
+        });
+        if let Some(old_pos) = old_pos {
+            self.add_reclaim_size(&old_pos);
+        }
+    }
+
+    /// 获取 keyspace 对应的内存索引，不存在则创建
+    pub(crate) fn keyspace_index(&self, name: &str) -> Arc<dyn index::Indexer> {
+        if let Some(index) = self.keyspaces.read().get(name) {
+            return index.clone();
+        }
+
+        let mut keyspaces = self.keyspaces.write();
+        keyspaces
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                index::new_keyspace_indexer(
+                    self.options.index_type.clone(),
+                    self.options.dir_path.clone(),
+                    name,
+                )
+                .into()
+            })
+            .clone()
+    }
+
+    /// 删除 keyspace 的内存索引，返回被清除的数据的位置信息，B+ 树的索引文件一起删除
+    pub(crate) fn remove_keyspace_index(&self, name: &str) -> Vec<LogRecordPos> {
+        let index = match self.keyspaces.write().remove(name) {
+            Some(index) => index,
+            None => return Vec::new(),
+        };
+        let removed = clear_index(index.as_ref());
+        if self.options.index_type == IndexType::BPlusTree {
+            index::bptree::remove_keyspace_file(self.options.dir_path.clone(), name);
+        }
+        removed
+    }
+
+    /// 在 keyspace 对应的内存索引上执行操作，keyspace 为空时使用默认的索引
+    pub(crate) fn with_index<R>(
+        &self,
+        keyspace: &[u8],
+        f: impl FnOnce(&dyn index::Indexer) -> R,
+    ) -> R {
+        if keyspace.is_empty() {
+            return f(self.index.as_ref());
         }
-        if rec_type == LogRecordType::Deleted {
-            let mut size = pos.size;
-            if let Some(old_pos) = self.index.delete(key) {
-                size += old_pos.size;
+        let index = self.keyspace_index(&String::from_utf8_lossy(keyspace));
+        f(index.as_ref())
+    }
+
+    /// 在已经存在的 keyspace 的内存索引上执行操作，keyspace 不存在或者已经被删除时返回 None
+    pub(crate) fn with_existing_index<R>(
+        &self,
+        keyspace: &[u8],
+        f: impl FnOnce(&dyn index::Indexer) -> R,
+    ) -> Option<R> {
+        if keyspace.is_empty() {
+            return Some(f(self.index.as_ref()));
+        }
+        let index = self
+            .keyspaces
+            .read()
+            .get(String::from_utf8_lossy(keyspace).as_ref())
+            .cloned()?;
+        Some(f(index.as_ref()))
+    }
+
+    /// 遍历全部索引，找到已经分配出去的最大写入版本号
+    fn max_index_version(&self) -> u64 {
+        let mut indexes = vec![self.index.clone()];
+        indexes.extend(self.keyspaces.read().values().cloned());
+        let mut version = 0;
+        for index in indexes {
+            let mut iter = index.iterator(IteratorOptions::default());
+            while let Some((_, pos)) = iter.next() {
+                version = version.max(pos.version);
             }
-            self.reclaim_size
-                .fetch_add(size as usize, std::sync::atomic::Ordering::SeqCst);
         }
+        version
     }
 
-    fn load_seq_no(&self) -> (bool, usize) {
+    /// 加载事务序列号，密钥错误时返回错误
+    fn load_seq_no(&self) -> Result<(bool, usize)> {
         let file_name = self.options.dir_path.join(SEQ_FILE_NAME);
         if !file_name.is_file() {
-            return (false, 0);
+            return Ok((false, 0));
         }
 
-        let seq_no_file = DataFile::new_seq_no_file(self.options.dir_path.clone()).unwrap();
-        let record = match seq_no_file.read_log_record(0) {
-            Ok(res) => res.record,
-            Err(e) => panic!("failed to read seq no: {}", e),
-        };
+        let seq_no_file =
+            DataFile::new_seq_no_file(self.options.dir_path.clone(), self.cipher.clone())?;
+        let result = seq_no_file.read_log_record(0)?;
 
-        let v = String::from_utf8(record.value).unwrap();
+        let v = String::from_utf8(result.record.value).unwrap();
         let seq_no = v.parse::<usize>().unwrap();
 
-        // 加载后直接删除掉，避免追加写入
-        fs::remove_file(file_name).unwrap();
+        // 紧接着的是写入版本号，旧版本的文件中没有
+        if let Ok(version) = seq_no_file.read_log_record(result.size) {
+            let v = String::from_utf8(version.record.value).unwrap();
+            self.txn_tracker
+                .lock()
+                .advance_to(v.parse::<u64>().unwrap());
+        }
+
+        // 加载后直接删除掉，避免追加写入，只读打开时不修改数据目录
+        if !self.read_only {
+            fs::remove_file(file_name).unwrap();
+        }
 
-        (true, seq_no)
+        Ok((true, seq_no))
     }
 
     fn reset_io_type(&self) -> Result<()> {
@@ -583,7 +1159,65 @@ impl Engine {
     }
 }
 
+impl EngineInner {
+    /// 记录当前事务序列号并持久化活跃文件，释放文件锁
+    fn close(&self) -> Result<()> {
+        // 如果语句目录不存在则返回
+        if !self.options.dir_path.is_dir() {
+            return Ok(());
+        }
+        // 只读打开时不写入任何文件
+        if self.read_only {
+            if let Some(lock_file) = &self.lock_file {
+                lock_file.unlock().unwrap();
+            }
+            return Ok(());
+        }
+        // 记录当前事务序列号
+        let seq_no_file =
+            DataFile::new_seq_no_file(self.options.dir_path.clone(), self.cipher.clone())?;
+        let seq_no = self.seq_no.load(std::sync::atomic::Ordering::SeqCst);
+        let record = LogRecord {
+            key: SEQ_NO_KEY.as_bytes().to_vec(),
+            value: seq_no.to_string().into_bytes(),
+            rec_type: LogRecordType::Normal,
+            expire: 0,
+            keyspace: Default::default(),
+        };
+        seq_no_file.write(&record.encode_with(Compression::None, self.cipher.as_ref()))?;
+        // 记录当前写入版本号，B+ 树索引重启之后继续递增
+        let record = LogRecord {
+            key: WRITE_VERSION_KEY.as_bytes().to_vec(),
+            value: self.txn_tracker.lock().version().to_string().into_bytes(),
+            rec_type: LogRecordType::Normal,
+            expire: 0,
+            keyspace: Default::default(),
+        };
+        seq_no_file.write(&record.encode_with(Compression::None, self.cipher.as_ref()))?;
+        seq_no_file.sync()?;
+
+        let read_guard = self.active_file.read();
+        read_guard.sync()?;
+
+        // 释放文件锁
+        if let Some(lock_file) = &self.lock_file {
+            lock_file.unlock().unwrap();
+        }
+
+        Ok(())
+    }
+}
+
 impl Drop for Engine {
+    fn drop(&mut self) {
+        // 停止后台线程之后，数据库实例持有的是最后一个引用
+        if let Some(worker) = self.auto_merge_worker.lock().take() {
+            worker.stop();
+        }
+    }
+}
+
+impl Drop for EngineInner {
     fn drop(&mut self) {
         if let Err(e) = self.close() {
             log::error!("error while close engine: {}", e);
@@ -591,8 +1225,81 @@ impl Drop for Engine {
     }
 }
 
+/// 清空内存索引，返回被清除的数据的位置信息
+pub(crate) fn clear_index(index: &dyn index::Indexer) -> Vec<LogRecordPos> {
+    remove_index_keys(index, index_keys_in_range(index, &[], None))
+}
+
+/// 返回内存索引中 [start, end) 范围内的 key，end 为 None 表示没有上界
+pub(crate) fn index_keys_in_range(
+    index: &dyn index::Indexer,
+    start: &[u8],
+    end: Option<&[u8]>,
+) -> Vec<Vec<u8>> {
+    let options = IteratorOptions {
+        lower_bound: Bound::Included(start.to_vec()),
+        upper_bound: end.map_or(Bound::Unbounded, |end| Bound::Excluded(end.to_vec())),
+        ..Default::default()
+    };
+    let mut keys = Vec::new();
+    let mut index_iter = index.iterator(options);
+    while let Some((key, _)) = index_iter.next() {
+        keys.push(key.clone());
+    }
+    keys
+}
+
+/// 从内存索引中删除 key，返回被删除的数据的位置信息
+pub(crate) fn remove_index_keys(
+    index: &dyn index::Indexer,
+    keys: Vec<Vec<u8>>,
+) -> Vec<LogRecordPos> {
+    keys.into_iter()
+        .filter_map(|key| index.delete(key))
+        .collect()
+}
+
+/// 获取数据目录的文件锁，保证只能在数据目录上打开一个实例
+/// 只读时不创建锁文件，锁文件不存在说明没有实例打开过这个目录
+pub(crate) fn lock_data_dir(dir_path: &Path, read_only: bool) -> Result<Option<File>> {
+    let lock_path = dir_path.join(FILE_LOCK_NAME);
+    if read_only && !lock_path.is_file() {
+        return Ok(None);
+    }
+    let lock_file = match fs::OpenOptions::new()
+        .read(true)
+        .write(!read_only)
+        .create(!read_only)
+        .truncate(false)
+        .open(lock_path)
+    {
+        Ok(file) => file,
+        Err(_) => return Err(Errors::FailedReadDatabaseDir),
+    };
+    if lock_file.try_lock_exclusive().is_err() {
+        return Err(Errors::DatabaseIsUsing);
+    }
+    Ok(Some(lock_file))
+}
+
+/// 打开数据库的内存索引，只读时不创建 B+ 树的索引文件，文件不存在时使用空的索引
+fn open_index(options: &Options, read_only: bool) -> Arc<dyn index::Indexer> {
+    if read_only && options.index_type == IndexType::BPlusTree {
+        return match index::bptree::BPlusTree::open_existing(options.dir_path.clone(), "") {
+            Some(bptree) => Arc::new(bptree),
+            None => Arc::new(index::btree::BTree::new()),
+        };
+    }
+    index::new_indexer(options.index_type.clone(), options.dir_path.clone()).into()
+}
+
 /// 从数据目录中加载数据文件
-fn load_data_files(dir_path: PathBuf, use_mmap: bool) -> Result<Vec<DataFile>> {
+fn load_data_files(
+    dir_path: PathBuf,
+    use_mmap: bool,
+    cipher: Option<Cipher>,
+    read_only: bool,
+) -> Result<Vec<DataFile>> {
     // 读取数据目录
     let dir = fs::read_dir(dir_path.clone());
 
@@ -631,12 +1338,37 @@ fn load_data_files(dir_path: PathBuf, use_mmap: bool) -> Result<Vec<DataFile>> {
         file_ids.sort();
 
         // 遍历所有文件 id，依次打开对应的数据文件
+        let last_file_id = *file_ids.last().unwrap();
         for file_id in file_ids {
             let mut io_type = IOType::StandardFIO;
             if use_mmap {
                 io_type = IOType::MemoryMap;
             }
-            let data_file = DataFile::new(dir_path.clone(), file_id, io_type)?;
+
+            // 活跃文件只写入了部分头部时，和末尾没有完整写入的数据一样处理
+            // 只读打开时按照没有头部的文件打开，其中的数据都是无效的
+            if file_id == last_file_id && DataFile::has_partial_header(dir_path.clone(), file_id) {
+                warn!("data file {} has an incomplete header", file_id);
+                if read_only {
+                    data_files.push(DataFile::new_headerless(
+                        dir_path.clone(),
+                        file_id,
+                        cipher.clone(),
+                    )?);
+                    continue;
+                }
+                fs::remove_file(get_data_file_name(dir_path.clone(), file_id)).unwrap();
+                io_type = IOType::StandardFIO;
+            }
+
+            let data_file = DataFile::new(dir_path.clone(), file_id, io_type, cipher.clone())?;
+
+            // 头部中记录的文件 id 需要和文件名一致
+            if let Some(header) = data_file.header() {
+                if header.file_id != file_id {
+                    return Err(Errors::InvalidDataFileHeader);
+                }
+            }
             data_files.push(data_file);
         }
 
@@ -646,6 +1378,41 @@ fn load_data_files(dir_path: PathBuf, use_mmap: bool) -> Result<Vec<DataFile>> {
     Err(Errors::FailedReadDatabaseDir)
 }
 
+/// offset 处读取记录出错时，判断是否是数据文件末尾没有完整写入的数据
+/// 文件中间的数据损坏时之后还有有效的记录，不能直接截断
+fn is_torn_tail(data_file: &DataFile, offset: u64, err: &Errors) -> bool {
+    matches!(
+        err,
+        Errors::CorruptedLogRecord | Errors::InvalidLogRecordCrc
+    ) && data_file.is_torn_tail(offset)
+}
+
+/// 截断活跃文件 offset 之后没有完整写入的数据，并设置写入的偏移
+fn truncate_torn_tail(active_file: &DataFile, offset: u64) -> Result<()> {
+    let file_size = active_file.file_size();
+    if offset < file_size {
+        warn!(
+            "truncate {} bytes of incomplete data at the tail of data file {}",
+            file_size - offset,
+            active_file.get_file_id()
+        );
+        active_file.truncate(offset)?;
+    }
+    active_file.set_write_off(offset);
+    Ok(())
+}
+
+/// 解析以十进制字符串存储的整数
+pub(crate) fn decode_integer(value: &[u8]) -> Result<i64> {
+    let v = std::str::from_utf8(value).map_err(|_| Errors::ValueIsNotInteger)?;
+    v.parse::<i64>().map_err(|_| Errors::ValueIsNotInteger)
+}
+
+/// 将整数编码为十进制字符串
+pub(crate) fn encode_integer(value: i64) -> Bytes {
+    Bytes::from(value.to_string())
+}
+
 /// 校验用户传递过来的配置项
 fn check_options(opts: &Options) -> Result<()> {
     let dir_path = opts.dir_path.to_str();
@@ -666,5 +1433,18 @@ fn check_options(opts: &Options) -> Result<()> {
         return Err(Errors::InvalidMergeRatio);
     }
 
+    if let Some(auto_merge) = &opts.auto_merge {
+        let invalid_window = auto_merge
+            .window
+            .is_some_and(|(start, end)| start >= 24 || end >= 24);
+        if auto_merge.check_interval.is_zero() || invalid_window {
+            return Err(Errors::InvalidAutoMergeOptions);
+        }
+    }
+
+    if opts.merge_rate_limit == Some(0) {
+        return Err(Errors::InvalidMergeRateLimit);
+    }
+
     Ok(())
 }
@@ -1,11 +1,16 @@
 use crate::{
+    batch::{log_record_key_with_seq, NON_TRANSCATION_SEQ_NO},
+    data::{
+        data_file::{get_data_file_name, DATA_FILE_HEADER_SIZE, SEQ_FILE_NAME},
+        log_record::{LogRecord, LogRecordType},
+    },
     db::Engine,
     error::Errors,
-    options::Options,
+    options::{AutoMergeOptions, Compression, Encryption, IndexType, Options, WriteBatchOptions},
     util::rand_kv::{get_test_key, get_test_value},
 };
 use bytes::Bytes;
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc, thread, time::Duration};
 
 #[test]
 fn my_test_engine_put() {
@@ -279,3 +284,808 @@ fn test_engine_backup() {
     std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
     std::fs::remove_dir_all(backup_dir).expect("failed to remove path");
 }
+
+#[test]
+fn test_engine_put_with_ttl() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-ttl"),
+        data_file_size: 64 * 1024 * 1024,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+    // 1.过期之前可以正常读取
+    let res1 = engine.put_with_ttl(
+        get_test_key(1),
+        get_test_value(1),
+        Duration::from_millis(200),
+    );
+    assert!(res1.is_ok());
+    let res2 = engine.put_with_ttl(
+        get_test_key(2),
+        get_test_value(2),
+        Duration::from_secs(3600),
+    );
+    assert!(res2.is_ok());
+    // 超出范围的 ttl 不会溢出
+    let res_max = engine.put_with_ttl(get_test_key(4), get_test_value(4), Duration::MAX);
+    assert!(res_max.is_ok());
+    assert_eq!(get_test_value(4), engine.get(get_test_key(4)).unwrap());
+    assert!(engine.delete(get_test_key(4)).is_ok());
+    let res3 = engine.put(get_test_key(3), get_test_value(3));
+    assert!(res3.is_ok());
+    assert!(engine.get(get_test_key(1)).is_ok());
+    assert_eq!(engine.list_keys().unwrap().len(), 3);
+
+    // 2.过期之后不可见
+    std::thread::sleep(Duration::from_millis(300));
+    let res4 = engine.get(get_test_key(1));
+    assert_eq!(Errors::KeyNotFound, res4.err().unwrap());
+    assert_eq!(engine.list_keys().unwrap().len(), 2);
+
+    // 3.重新 Put 之后清除过期时间
+    let res5 = engine.put_with_ttl(get_test_key(3), get_test_value(33), Duration::ZERO);
+    assert!(res5.is_ok());
+    assert_eq!(
+        Errors::KeyNotFound,
+        engine.get(get_test_key(3)).err().unwrap()
+    );
+    let res6 = engine.put(get_test_key(3), get_test_value(3));
+    assert!(res6.is_ok());
+    assert!(engine.get(get_test_key(3)).is_ok());
+
+    // 4.重启之后校验
+    std::mem::drop(engine);
+    let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(
+        Errors::KeyNotFound,
+        engine2.get(get_test_key(1)).err().unwrap()
+    );
+    assert_eq!(get_test_value(2), engine2.get(get_test_key(2)).unwrap());
+    assert_eq!(get_test_value(3), engine2.get(get_test_key(3)).unwrap());
+    assert_eq!(engine2.list_keys().unwrap().len(), 2);
+
+    // 删除测试的文件夹
+    std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_compare_and_swap() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-cas"),
+        data_file_size: 64 * 1024 * 1024,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+    // 1.key 不存在时写入
+    let res1 = engine.put_if_absent(get_test_key(1), get_test_value(1));
+    assert!(res1.unwrap());
+    let res2 = engine.put_if_absent(get_test_key(1), get_test_value(2));
+    assert!(!res2.unwrap());
+    assert_eq!(get_test_value(1), engine.get(get_test_key(1)).unwrap());
+
+    // 2.期望值不匹配
+    let res3 = engine.compare_and_swap(
+        get_test_key(1),
+        Some(get_test_value(2)),
+        Some(get_test_value(3)),
+    );
+    assert!(!res3.unwrap());
+    assert_eq!(get_test_value(1), engine.get(get_test_key(1)).unwrap());
+
+    // 3.期望值匹配
+    let res4 = engine.compare_and_swap(
+        get_test_key(1),
+        Some(get_test_value(1)),
+        Some(get_test_value(3)),
+    );
+    assert!(res4.unwrap());
+    assert_eq!(get_test_value(3), engine.get(get_test_key(1)).unwrap());
+
+    // 4.期望值匹配时删除
+    let res5 = engine.compare_and_swap(get_test_key(1), Some(get_test_value(3)), None);
+    assert!(res5.unwrap());
+    assert_eq!(
+        Errors::KeyNotFound,
+        engine.get(get_test_key(1)).err().unwrap()
+    );
+
+    // 5.key 为空
+    let res6 = engine.compare_and_swap(Bytes::new(), None, Some(get_test_value(1)));
+    assert_eq!(Errors::KeyIsEmpty, res6.err().unwrap());
+
+    // 6.多个线程同时竞争同一个 key
+    let engine = Arc::new(engine);
+    let counter_key = Bytes::from("counter");
+    engine.put(counter_key.clone(), Bytes::from("0")).unwrap();
+    let mut handles = vec![];
+    for _ in 0..4 {
+        let eng = engine.clone();
+        let key = counter_key.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..100 {
+                loop {
+                    let current = eng.get(key.clone()).unwrap();
+                    let n = String::from_utf8(current.to_vec())
+                        .unwrap()
+                        .parse::<u32>()
+                        .unwrap();
+                    let next = Bytes::from((n + 1).to_string());
+                    if eng
+                        .compare_and_swap(key.clone(), Some(current), Some(next))
+                        .unwrap()
+                    {
+                        break;
+                    }
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(Bytes::from("400"), engine.get(counter_key).unwrap());
+
+    // 删除测试的文件夹
+    std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_incr_by() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-incr"),
+        data_file_size: 64 * 1024 * 1024,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+    // 1.key 不存在时从 0 开始累加
+    assert_eq!(5, engine.incr_by(Bytes::from("counter"), 5).unwrap());
+    assert_eq!(3, engine.decr_by(Bytes::from("counter"), 2).unwrap());
+    assert_eq!(
+        Bytes::from("3"),
+        engine.get(Bytes::from("counter")).unwrap()
+    );
+
+    // 2.值不是整数
+    engine.put(get_test_key(1), get_test_value(1)).unwrap();
+    let res1 = engine.incr_by(get_test_key(1), 1);
+    assert_eq!(Errors::ValueIsNotInteger, res1.err().unwrap());
+
+    // 3.溢出
+    engine
+        .put(get_test_key(2), Bytes::from(i64::MAX.to_string()))
+        .unwrap();
+    let res2 = engine.incr_by(get_test_key(2), 1);
+    assert_eq!(Errors::IntegerOverflow, res2.err().unwrap());
+    let res3 = engine.decr_by(get_test_key(2), i64::MIN);
+    assert_eq!(Errors::IntegerOverflow, res3.err().unwrap());
+
+    // 4.多个线程同时累加
+    let engine = Arc::new(engine);
+    let mut handles = vec![];
+    for _ in 0..4 {
+        let eng = engine.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..250 {
+                eng.incr_by(Bytes::from("counter"), 1).unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(1003, engine.incr_by(Bytes::from("counter"), 0).unwrap());
+
+    // 5.重启之后校验
+    std::mem::drop(engine);
+    let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(1004, engine2.incr_by(Bytes::from("counter"), 1).unwrap());
+
+    // 删除测试的文件夹
+    std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_delete_range() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-delete-range"),
+        data_file_size: 64 * 1024 * 1024,
+        data_file_merge_ratio: 0 as f32,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+    for i in 0..100 {
+        let put_res = engine.put(get_test_key(i), get_test_value(i));
+        assert!(put_res.is_ok());
+    }
+
+    // 删除 [10, 90) 范围内的数据
+    let del_res = engine.delete_range(get_test_key(10), get_test_key(90));
+    assert!(del_res.is_ok());
+    assert_eq!(20, engine.list_keys().unwrap().len());
+    assert!(engine.get(get_test_key(9)).is_ok());
+    assert_eq!(
+        Errors::KeyNotFound,
+        engine.get(get_test_key(10)).err().unwrap()
+    );
+    assert_eq!(
+        Errors::KeyNotFound,
+        engine.get(get_test_key(89)).err().unwrap()
+    );
+    assert!(engine.get(get_test_key(90)).is_ok());
+
+    // 范围删除之后再写入的数据不受影响
+    let put_res = engine.put(get_test_key(50), get_test_value(500));
+    assert!(put_res.is_ok());
+
+    // 重启之后校验
+    std::mem::drop(engine);
+    let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(21, engine2.list_keys().unwrap().len());
+    assert_eq!(get_test_value(500), engine2.get(get_test_key(50)).unwrap());
+    assert_eq!(
+        Errors::KeyNotFound,
+        engine2.get(get_test_key(51)).err().unwrap()
+    );
+
+    // merge 之后校验
+    assert!(engine2.merge().is_ok());
+    std::mem::drop(engine2);
+    let engine3 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(21, engine3.list_keys().unwrap().len());
+    assert_eq!(
+        Errors::KeyNotFound,
+        engine3.get(get_test_key(51)).err().unwrap()
+    );
+
+    // 删除测试的文件夹
+    std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_multi_get() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-multi-get"),
+        data_file_size: 64 * 1024,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+    // 数据分布在多个数据文件中
+    for i in 0..2000 {
+        let put_res = engine.put(get_test_key(i), get_test_value(i));
+        assert!(put_res.is_ok());
+    }
+    assert!(engine.delete(get_test_key(5)).is_ok());
+    let put_res = engine.put_with_ttl(get_test_key(6), get_test_value(6), Duration::ZERO);
+    assert!(put_res.is_ok());
+    assert!(engine.stat().unwrap().data_file_num > 1);
+
+    let mut keys: Vec<Bytes> = (0..2000).rev().step_by(3).map(get_test_key).collect();
+    keys.push(get_test_key(10));
+    keys.push(get_test_key(10));
+    keys.push(get_test_key(5));
+    keys.push(get_test_key(6));
+    keys.push(get_test_key(3000));
+    keys.push(Bytes::new());
+
+    let results = engine.multi_get(&keys);
+    assert_eq!(keys.len(), results.len());
+    for (key, result) in keys.iter().zip(results.iter()).take(keys.len() - 6) {
+        assert_eq!(&engine.get(key.clone()).unwrap(), result.as_ref().unwrap());
+    }
+    let n = keys.len();
+    assert_eq!(get_test_value(10), results[n - 6].clone().unwrap());
+    assert_eq!(get_test_value(10), results[n - 5].clone().unwrap());
+    assert_eq!(Errors::KeyNotFound, results[n - 4].clone().err().unwrap());
+    assert_eq!(Errors::KeyNotFound, results[n - 3].clone().err().unwrap());
+    assert_eq!(Errors::KeyNotFound, results[n - 2].clone().err().unwrap());
+    assert_eq!(Errors::KeyIsEmpty, results[n - 1].clone().err().unwrap());
+
+    // 删除测试的文件夹
+    std::mem::drop(engine);
+    std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_exists_and_metadata() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-metadata"),
+        data_file_size: 64 * 1024 * 1024,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+    assert!(engine.put(get_test_key(1), Bytes::from("value")).is_ok());
+    let put_res = engine.put_with_ttl(get_test_key(2), get_test_value(2), Duration::ZERO);
+    assert!(put_res.is_ok());
+    let wb = engine
+        .new_write_batch(WriteBatchOptions::default())
+        .expect("failed to create write batch");
+    assert!(wb.put(get_test_key(3), get_test_value(3)).is_ok());
+    assert!(wb.commit().is_ok());
+
+    assert!(engine.exists(get_test_key(1)).unwrap());
+    assert!(!engine.exists(get_test_key(2)).unwrap());
+    assert!(!engine.exists(get_test_key(4)).unwrap());
+    assert_eq!(
+        Errors::KeyIsEmpty,
+        engine.exists(Bytes::new()).err().unwrap()
+    );
+
+    let meta = engine.metadata(get_test_key(1)).unwrap();
+    assert_eq!(5, meta.value_size);
+    assert_eq!(0, meta.file_id);
+    assert_eq!(DATA_FILE_HEADER_SIZE, meta.offset);
+    assert_eq!(1, meta.write_seq);
+    let meta1 = meta;
+    let meta = engine.metadata(get_test_key(3)).unwrap();
+    assert_eq!(get_test_value(3).len() as u32, meta.value_size);
+    assert!(meta.write_seq > meta1.write_seq);
+    assert_eq!(
+        Errors::KeyNotFound,
+        engine.metadata(get_test_key(2)).err().unwrap()
+    );
+
+    // 重启之后从数据文件中重建元数据
+    std::mem::drop(wb);
+    std::mem::drop(engine);
+    let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(meta, engine2.metadata(get_test_key(3)).unwrap());
+    assert_eq!(5, engine2.metadata(get_test_key(1)).unwrap().value_size);
+
+    // 删除测试的文件夹
+    std::mem::drop(engine2);
+    std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_metadata_write_seq_bptree() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-metadata-bptree"),
+        data_file_size: 64 * 1024 * 1024,
+        index_type: IndexType::BPlusTree,
+        mmap_at_startup: false,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert!(engine.put(get_test_key(1), get_test_value(1)).is_ok());
+    assert!(engine.put(get_test_key(2), get_test_value(2)).is_ok());
+    let seq2 = engine.metadata(get_test_key(2)).unwrap().write_seq;
+    assert!(seq2 > engine.metadata(get_test_key(1)).unwrap().write_seq);
+
+    // 正常关闭之后从序列号文件中恢复写入版本号
+    std::mem::drop(engine);
+    let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(seq2, engine2.metadata(get_test_key(2)).unwrap().write_seq);
+    assert!(engine2.put(get_test_key(3), get_test_value(3)).is_ok());
+    let seq3 = engine2.metadata(get_test_key(3)).unwrap().write_seq;
+    assert!(seq3 > seq2);
+
+    // 没有序列号文件时从索引中找到最大的写入版本号
+    std::mem::drop(engine2);
+    std::fs::remove_file(opts.dir_path.join(SEQ_FILE_NAME)).unwrap();
+    let engine3 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert!(engine3.put(get_test_key(4), get_test_value(4)).is_ok());
+    assert!(engine3.metadata(get_test_key(4)).unwrap().write_seq > seq3);
+
+    // 删除测试的文件夹
+    std::mem::drop(engine3);
+    std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_compression() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-compression"),
+        data_file_size: 64 * 1024 * 1024,
+        data_file_merge_ratio: 0 as f32,
+        compression: Compression::Zstd,
+        ..Default::default()
+    };
+    let value = |i: usize| Bytes::from(std::format!("{{\"id\":{},\"tags\":[]}}", i).repeat(20));
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..100 {
+        assert!(engine.put(get_test_key(i), value(i)).is_ok());
+    }
+    let stat = engine.stat().unwrap();
+    assert!(stat.disk_size < (100 * value(0).len()) as u64);
+    assert_eq!(value(10), engine.get(get_test_key(10)).unwrap());
+    let meta = engine.metadata(get_test_key(10)).unwrap();
+    assert_eq!(value(10).len() as u32, meta.value_size);
+
+    // 切换压缩算法之后，旧的数据仍然可以读取，merge 之后按照新的算法重新压缩
+    std::mem::drop(engine);
+    let opts2 = Options {
+        compression: Compression::Lz4,
+        ..opts.clone()
+    };
+    let engine2 = Engine::open(opts2.clone()).expect("failed to open engine");
+    assert_eq!(value(20), engine2.get(get_test_key(20)).unwrap());
+    assert!(engine2.put(get_test_key(100), value(100)).is_ok());
+    assert!(engine2.merge().is_ok());
+
+    std::mem::drop(engine2);
+    let engine3 = Engine::open(opts2.clone()).expect("failed to open engine");
+    for i in 0..=100 {
+        assert_eq!(value(i), engine3.get(get_test_key(i)).unwrap());
+    }
+    let results = engine3.multi_get(&[get_test_key(1), get_test_key(2)]);
+    assert_eq!(value(2), results[1].clone().unwrap());
+
+    // 删除测试的文件夹
+    std::mem::drop(engine3);
+    std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_encryption() {
+    for (dir, index_type) in [
+        ("/tmp/bitcask-rs-encryption-btree", IndexType::BTree),
+        ("/tmp/bitcask-rs-encryption-bptree", IndexType::BPlusTree),
+    ] {
+        let opts = Options {
+            dir_path: PathBuf::from(dir),
+            data_file_size: 64 * 1024 * 1024,
+            data_file_merge_ratio: 0 as f32,
+            index_type,
+            mmap_at_startup: false,
+            compression: Compression::Lz4,
+            encryption: Encryption::ChaCha20Poly1305([7; 32]),
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..100 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        assert!(wb.put(get_test_key(100), get_test_value(100)).is_ok());
+        assert!(wb.commit().is_ok());
+        assert!(engine.merge().is_ok());
+        assert!(engine.delete(get_test_key(0)).is_ok());
+
+        // 数据文件中不包含明文，B+ 树索引文件不在加密的范围内
+        std::mem::drop(wb);
+        std::mem::drop(engine);
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.to_str().unwrap().contains("bptree-index") {
+                continue;
+            }
+            let content = std::fs::read(&path).unwrap();
+            let plain = get_test_key(1);
+            assert!(
+                !content.windows(plain.len()).any(|w| w == plain.as_ref()),
+                "{:?}",
+                path
+            );
+        }
+
+        // 使用相同的密钥重新打开
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(get_test_value(1), engine2.get(get_test_key(1)).unwrap());
+        assert_eq!(get_test_value(100), engine2.get(get_test_key(100)).unwrap());
+        assert_eq!(
+            Errors::KeyNotFound,
+            engine2.get(get_test_key(0)).err().unwrap()
+        );
+        std::mem::drop(engine2);
+
+        // 密钥错误或者没有密钥都无法打开
+        for encryption in [Encryption::ChaCha20Poly1305([8; 32]), Encryption::None] {
+            let wrong_opts = Options {
+                encryption,
+                ..opts.clone()
+            };
+            assert_eq!(
+                Errors::InvalidEncryptionKey,
+                Engine::open(wrong_opts).err().unwrap()
+            );
+        }
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+}
+
+#[test]
+fn test_engine_data_file_header() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-file-header"),
+        data_file_size: 64 * 1024 * 1024,
+        data_file_merge_ratio: 0 as f32,
+        ..Default::default()
+    };
+
+    // 旧版本没有头部的数据文件仍然可以读取
+    std::fs::create_dir_all(&opts.dir_path).unwrap();
+    let mut legacy = Vec::new();
+    for i in 0..10 {
+        let record = LogRecord {
+            key: log_record_key_with_seq(get_test_key(i).to_vec(), NON_TRANSCATION_SEQ_NO),
+            value: get_test_value(i).to_vec(),
+            rec_type: LogRecordType::Normal,
+            expire: 0,
+            keyspace: Vec::new(),
+        };
+        legacy.extend(record.encode());
+    }
+    std::fs::write(get_data_file_name(opts.dir_path.clone(), 0), &legacy).unwrap();
+
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(get_test_value(3), engine.get(get_test_key(3)).unwrap());
+    assert!(engine.put(get_test_key(10), get_test_value(10)).is_ok());
+    assert!(engine.merge().is_ok());
+    std::mem::drop(engine);
+
+    // merge 之后的数据文件带有头部
+    let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(11, engine2.list_keys().unwrap().len());
+    assert_eq!(
+        DATA_FILE_HEADER_SIZE,
+        engine2.metadata(get_test_key(0)).unwrap().offset
+    );
+    std::mem::drop(engine2);
+
+    // 头部中的文件 id 和文件名不一致
+    let file0 = get_data_file_name(opts.dir_path.clone(), 0);
+    let file9 = get_data_file_name(opts.dir_path.clone(), 9);
+    std::fs::copy(&file0, &file9).unwrap();
+    assert_eq!(
+        Errors::InvalidDataFileHeader,
+        Engine::open(opts.clone()).err().unwrap()
+    );
+
+    // 不是数据文件
+    std::fs::write(&file9, [0xFFu8; 64]).unwrap();
+    assert_eq!(
+        Errors::InvalidDataFileHeader,
+        Engine::open(opts.clone()).err().unwrap()
+    );
+
+    // 第一个字节恰好是有效的记录类型，但是第一条记录无法通过 crc 校验
+    let mut foreign = vec![0x5Au8; 64];
+    foreign[0] = LogRecordType::Normal as u8;
+    std::fs::write(&file9, &foreign).unwrap();
+    assert_eq!(
+        Errors::InvalidDataFileHeader,
+        Engine::open(opts.clone()).err().unwrap()
+    );
+
+    // 删除测试的文件夹
+    std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_torn_write_recovery() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-torn-write"),
+        data_file_size: 64 * 1024 * 1024,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..10 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    std::mem::drop(engine);
+
+    // 模拟最后一条记录只写入了一半
+    let file0 = get_data_file_name(opts.dir_path.clone(), 0);
+    let valid_size = std::fs::metadata(&file0).unwrap().len();
+    let record = LogRecord {
+        key: log_record_key_with_seq(get_test_key(10).to_vec(), NON_TRANSCATION_SEQ_NO),
+        value: get_test_value(10).to_vec(),
+        rec_type: LogRecordType::Normal,
+        expire: 0,
+        keyspace: Vec::new(),
+    };
+    let enc = record.encode();
+    let mut content = std::fs::read(&file0).unwrap();
+    content.extend_from_slice(&enc[..enc.len() / 2]);
+    std::fs::write(&file0, &content).unwrap();
+
+    let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(10, engine2.list_keys().unwrap().len());
+    assert_eq!(
+        Errors::KeyNotFound,
+        engine2.get(get_test_key(10)).err().unwrap()
+    );
+    assert_eq!(valid_size, std::fs::metadata(&file0).unwrap().len());
+    assert!(engine2.put(get_test_key(11), get_test_value(11)).is_ok());
+    std::mem::drop(engine2);
+
+    // 末尾是无法解析的数据
+    let mut content = std::fs::read(&file0).unwrap();
+    content.extend_from_slice(&[0xFFu8; 16]);
+    std::fs::write(&file0, &content).unwrap();
+
+    let engine3 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(11, engine3.list_keys().unwrap().len());
+    assert_eq!(get_test_value(11), engine3.get(get_test_key(11)).unwrap());
+    assert!(engine3.put(get_test_key(12), get_test_value(12)).is_ok());
+    std::mem::drop(engine3);
+
+    let engine4 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(12, engine4.list_keys().unwrap().len());
+    assert_eq!(get_test_value(12), engine4.get(get_test_key(12)).unwrap());
+    std::mem::drop(engine4);
+
+    // 删除测试的文件夹
+    std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_partial_header_recovery() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-partial-header"),
+        data_file_size: 64 * 1024 * 1024,
+        mmap_at_startup: false,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..10 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    std::mem::drop(engine);
+
+    // 模拟创建新的活跃文件时头部只写入了一部分
+    let file0 = get_data_file_name(opts.dir_path.clone(), 0);
+    let file1 = get_data_file_name(opts.dir_path.clone(), 1);
+    let content = std::fs::read(&file0).unwrap();
+    std::fs::write(&file1, &content[..10]).unwrap();
+
+    // 只读打开时不修改文件
+    let engine2 = Engine::open_read_only(opts.clone()).expect("failed to open engine");
+    assert_eq!(10, engine2.list_keys().unwrap().len());
+    std::mem::drop(engine2);
+    assert_eq!(10, std::fs::metadata(&file1).unwrap().len());
+
+    let engine3 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(10, engine3.list_keys().unwrap().len());
+    assert_eq!(
+        DATA_FILE_HEADER_SIZE,
+        std::fs::metadata(&file1).unwrap().len()
+    );
+    assert!(engine3.put(get_test_key(10), get_test_value(10)).is_ok());
+    std::mem::drop(engine3);
+
+    let engine4 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(11, engine4.list_keys().unwrap().len());
+    assert_eq!(get_test_value(10), engine4.get(get_test_key(10)).unwrap());
+    std::mem::drop(engine4);
+
+    // 删除测试的文件夹
+    std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_corrupted_record_in_middle() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-corrupted-middle"),
+        data_file_size: 64 * 1024 * 1024,
+        mmap_at_startup: false,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..10 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    std::mem::drop(engine);
+
+    // 修改第一条记录中 value 的一个字节，之后还有完整的记录，不能当作末尾截断
+    let file0 = get_data_file_name(opts.dir_path.clone(), 0);
+    let mut content = std::fs::read(&file0).unwrap();
+    let size = content.len() as u64;
+    let pos = DATA_FILE_HEADER_SIZE as usize + 20;
+    content[pos] ^= 0xFF;
+    std::fs::write(&file0, &content).unwrap();
+
+    let res = Engine::open(opts.clone());
+    assert_eq!(Errors::InvalidLogRecordCrc, res.err().unwrap());
+    assert_eq!(size, std::fs::metadata(&file0).unwrap().len());
+
+    // 删除测试的文件夹
+    std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_torn_write_recovery_bptree() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-torn-write-bptree"),
+        data_file_size: 64 * 1024 * 1024,
+        index_type: IndexType::BPlusTree,
+        mmap_at_startup: false,
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..10 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    std::mem::drop(engine);
+
+    // 末尾是无法解析的数据
+    let file0 = get_data_file_name(opts.dir_path.clone(), 0);
+    let valid_size = std::fs::metadata(&file0).unwrap().len();
+    let mut content = std::fs::read(&file0).unwrap();
+    content.extend_from_slice(&[0xFFu8; 16]);
+    std::fs::write(&file0, &content).unwrap();
+
+    let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(valid_size, std::fs::metadata(&file0).unwrap().len());
+    assert!(engine2.put(get_test_key(10), get_test_value(10)).is_ok());
+    std::mem::drop(engine2);
+
+    let engine3 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(11, engine3.list_keys().unwrap().len());
+    assert_eq!(get_test_value(10), engine3.get(get_test_key(10)).unwrap());
+    assert_eq!(get_test_value(0), engine3.get(get_test_key(0)).unwrap());
+    std::mem::drop(engine3);
+
+    // 删除测试的文件夹
+    std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+}
+
+#[test]
+fn test_engine_auto_merge() {
+    let opts = Options {
+        dir_path: PathBuf::from("/tmp/bitcask-rs-auto-merge"),
+        data_file_size: 64 * 1024,
+        data_file_merge_ratio: 0.3,
+        auto_merge: Some(AutoMergeOptions {
+            check_interval: Duration::from_millis(20),
+            window: None,
+        }),
+        ..Default::default()
+    };
+    let engine = Engine::open(opts.clone()).expect("failed to open engine");
+    for i in 0..1000 {
+        assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+    }
+    for i in 0..1000 {
+        assert!(engine.put(get_test_key(i), get_test_value(i + 1)).is_ok());
+    }
+
+    // 等待后台线程完成 merge，merge 之后不需要重启就可以回收空间
+    for _ in 0..200 {
+        if !engine.reach_merge_ratio() {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert!(!engine.reach_merge_ratio());
+    assert_eq!(get_test_value(11), engine.get(get_test_key(10)).unwrap());
+    std::mem::drop(engine);
+
+    let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+    assert_eq!(1000, engine2.list_keys().unwrap().len());
+    assert_eq!(get_test_value(11), engine2.get(get_test_key(10)).unwrap());
+    std::mem::drop(engine2);
+
+    // 时间窗口之外不会 merge
+    let window = AutoMergeOptions {
+        check_interval: Duration::from_secs(1),
+        window: Some((22, 4)),
+    };
+    assert!(window.in_window(23 * 3600));
+    assert!(window.in_window(86400 + 3600));
+    assert!(!window.in_window(12 * 3600));
+    let invalid = Options {
+        auto_merge: Some(AutoMergeOptions {
+            check_interval: Duration::from_secs(1),
+            window: Some((0, 24)),
+        }),
+        ..opts.clone()
+    };
+    assert_eq!(
+        Errors::InvalidAutoMergeOptions,
+        Engine::open(invalid).err().unwrap()
+    );
+
+    // 删除测试的文件夹
+    std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+}
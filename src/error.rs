@@ -84,6 +84,51 @@ pub enum Errors {
 
     #[error("failed to copy the database directory")]
     FailedToCopyDirectory,
+
+    #[error("the value is not an integer")]
+    ValueIsNotInteger,
+
+    #[error("increment or decrement would overflow")]
+    IntegerOverflow,
+
+    #[error("transaction conflict, the keys have been changed by another commit")]
+    TransactionConflict,
+
+    #[error("no savepoint has been set in the write batch")]
+    NoSavepoint,
+
+    #[error("invalid keyspace name")]
+    InvalidKeyspaceName,
+
+    #[error("the keyspace has been dropped, open it again")]
+    KeyspaceDropped,
+
+    #[error("failed to decompress the value of log record")]
+    DecompressFailed,
+
+    #[error("the encryption key is missing or incorrect")]
+    InvalidEncryptionKey,
+
+    #[error("invalid data file header, the file may be written by an incompatible version")]
+    InvalidDataFileHeader,
+
+    #[error("the log record is incomplete or corrupted")]
+    CorruptedLogRecord,
+
+    #[error("failed to truncate data file")]
+    FailedTruncateDataFile,
+
+    #[error("the repair target directory is not empty")]
+    RepairDirNotEmpty,
+
+    #[error("invalid auto merge options, the check interval must be greater than 0 and the window hours must be less than 24")]
+    InvalidAutoMergeOptions,
+
+    #[error("invalid merge rate limit, must be greater than 0")]
+    InvalidMergeRateLimit,
+
+    #[error("merge is cancelled")]
+    MergeCancelled,
 }
 
 pub type Result<T> = result::Result<T, Errors>;
@@ -70,6 +70,15 @@ impl IOManager for FileIO {
         let metadata: Metadata = read_guard.metadata().unwrap();
         metadata.len()
     }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        let write_guard = self.fd.write();
+        if let Err(e) = write_guard.set_len(size) {
+            error!("failed to truncate data file {}", e);
+            return Err(Errors::FailedTruncateDataFile);
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
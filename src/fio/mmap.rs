@@ -2,12 +2,17 @@ use crate::error::{Errors, Result};
 use log::error;
 use memmap2::Mmap;
 use parking_lot::Mutex;
-use std::{fs::OpenOptions, path::PathBuf, sync::Arc};
+use std::{
+    fs::{File, OpenOptions},
+    path::PathBuf,
+    sync::Arc,
+};
 
 use super::IOManager;
 
 pub struct MMapIO {
     map: Arc<Mutex<Mmap>>,
+    file: File,
 }
 
 impl MMapIO {
@@ -22,6 +27,7 @@ impl MMapIO {
                 let map = unsafe { Mmap::map(&file).expect("faile to map the file") };
                 Ok(Self {
                     map: Arc::new(Mutex::new(map)),
+                    file,
                 })
             }
             Err(e) => {
@@ -57,6 +63,17 @@ impl IOManager for MMapIO {
         let map_arr = self.map.lock();
         map_arr.len() as u64
     }
+
+    fn truncate(&self, size: u64) -> Result<()> {
+        // 截断之后重新映射文件
+        let mut map_arr = self.map.lock();
+        if let Err(e) = self.file.set_len(size) {
+            error!("failed to truncate data file {}", e);
+            return Err(Errors::FailedTruncateDataFile);
+        }
+        *map_arr = unsafe { Mmap::map(&self.file).expect("faile to map the file") };
+        Ok(())
+    }
 }
 
 #[cfg(test)]
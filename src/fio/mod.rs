@@ -18,6 +18,9 @@ pub trait IOManager: Sync + Send {
 
     /// 获取文件大小
     fn size(&self) -> u64;
+
+    /// 将文件截断到给定的长度
+    fn truncate(&self, size: u64) -> Result<()>;
 }
 
 /// 根据文件名称初始化 IOManager
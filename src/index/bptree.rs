@@ -1,12 +1,11 @@
-use super::Indexer;
+use super::{IndexScanner, Indexer, ScanIterator};
 use crate::{
     data::log_record::{decode_log_record_pos, LogRecordPos},
-    error::{Errors, Result},
     options::IteratorOptions,
+    util::bound::{above_lower_bound, below_upper_bound},
 };
-use bytes::Bytes;
-use jammdb::{Error, DB};
-use std::{path::PathBuf, sync::Arc};
+use jammdb::{Bucket, Error, DB};
+use std::{collections::VecDeque, ops::Bound, path::PathBuf, sync::Arc};
 
 const BPTREE_INDEX_FINE_NAME: &str = "bptree-index";
 const BPTREE_BUCKET_NAME: &str = "bitcask-index";
@@ -17,9 +16,33 @@ pub struct BPlusTree {
 
 impl BPlusTree {
     pub fn new(dir_path: PathBuf) -> Self {
+        Self::with_file_name(dir_path, BPTREE_INDEX_FINE_NAME)
+    }
+
+    /// 打开 keyspace 对应的 B+ 树索引，每个 keyspace 使用单独的索引文件
+    pub fn new_keyspace(dir_path: PathBuf, keyspace: &str) -> Self {
+        Self::with_file_name(dir_path, &index_file_name(keyspace))
+    }
+
+    /// 打开已经存在的索引文件，keyspace 为空时打开默认的索引，不会创建文件也不会写入数据
+    /// 索引文件不存在时返回 None
+    pub(crate) fn open_existing(dir_path: PathBuf, keyspace: &str) -> Option<Self> {
+        let path = dir_path.join(index_file_name(keyspace));
+        if !path.is_file() {
+            return None;
+        }
+        let tree = Arc::new(DB::open(path).expect("failed to open bptree"));
+        let tx = tree.tx(false).expect("failed to begin tx");
+        if tx.get_bucket(BPTREE_BUCKET_NAME).is_err() {
+            return None;
+        }
+        drop(tx);
+        Some(Self { tree })
+    }
+
+    fn with_file_name(dir_path: PathBuf, file_name: &str) -> Self {
         // 打开 B+ 树实例，并创建对应的 bucket
-        let bptree =
-            DB::open(dir_path.join(BPTREE_INDEX_FINE_NAME)).expect("failed to open bptree");
+        let bptree = DB::open(dir_path.join(file_name)).expect("failed to open bptree");
         let tree = Arc::new(bptree);
         let tx = tree.tx(true).expect("failed to begin tx");
         tx.get_or_create_bucket(BPTREE_BUCKET_NAME).unwrap();
@@ -71,76 +94,239 @@ impl Indexer for BPlusTree {
         result
     }
 
-    fn list_keys(&self) -> Result<Vec<bytes::Bytes>> {
-        let tx = self.tree.tx(false).expect("failed to begin tx");
-        let bucket = tx.get_bucket(BPTREE_BUCKET_NAME).unwrap();
-        let mut keys = Vec::new();
+    fn iterator(&self, options: IteratorOptions) -> Box<dyn super::IndexIterator> {
+        Box::new(ScanIterator::new(
+            BPTreeScanner {
+                tree: self.tree.clone(),
+            },
+            options,
+        ))
+    }
+}
 
-        for data in bucket.cursor() {
-            keys.push(Bytes::copy_from_slice(data.key()));
+/// 从数据目录中找出所有 keyspace 的 B+ 树索引文件，返回对应的 keyspace 名称
+pub(crate) fn list_keyspaces(dir_path: PathBuf) -> Vec<String> {
+    let prefix = std::format!("{}-", BPTREE_INDEX_FINE_NAME);
+    let mut keyspaces = Vec::new();
+    if let Ok(dir) = std::fs::read_dir(dir_path) {
+        for entry in dir.flatten() {
+            if let Some(file_name) = entry.file_name().to_str() {
+                if let Some(keyspace) = file_name.strip_prefix(&prefix) {
+                    keyspaces.push(keyspace.to_string());
+                }
+            }
         }
+    }
+    keyspaces
+}
 
-        Ok(keys)
+/// keyspace 对应的 B+ 树索引文件名，keyspace 为空时是默认的索引文件
+fn index_file_name(keyspace: &str) -> String {
+    match keyspace {
+        "" => BPTREE_INDEX_FINE_NAME.to_string(),
+        _ => std::format!("{}-{}", BPTREE_INDEX_FINE_NAME, keyspace),
     }
+}
 
-    fn iterator(&self, options: IteratorOptions) -> Box<dyn super::IndexIterator> {
-        let mut items = Vec::new();
+/// 删除 keyspace 对应的 B+ 树索引文件
+pub(crate) fn remove_keyspace_file(dir_path: PathBuf, keyspace: &str) {
+    if let Err(e) = std::fs::remove_file(dir_path.join(index_file_name(keyspace))) {
+        log::warn!("failed to remove keyspace index file: {}", e);
+    }
+}
+
+/// 反向遍历时每次查找的起点之后最多读取 limit 的多少倍的数据
+const REVERSE_CHUNK_FACTOR: usize = 4;
+/// 反向遍历时查找起点的最大次数，超过之后从已经找到的起点开始读取
+const MAX_REVERSE_SEEK_ROUNDS: usize = 64;
+
+/// 分批读取 B+ 树中的数据，每一批使用一个单独的只读事务
+struct BPTreeScanner {
+    tree: Arc<DB>,
+}
+
+impl IndexScanner for BPTreeScanner {
+    fn scan(
+        &self,
+        lower: &Bound<Vec<u8>>,
+        upper: &Bound<Vec<u8>>,
+        reverse: bool,
+        limit: usize,
+    ) -> (Vec<(Vec<u8>, LogRecordPos)>, bool) {
         let tx = self.tree.tx(false).expect("failed to begin tx");
         let bucket = tx.get_bucket(BPTREE_BUCKET_NAME).unwrap();
-
-        for data in bucket.cursor() {
-            let key = data.key().to_vec();
-            let pos = decode_log_record_pos(data.kv().value().to_vec());
-            items.push((key, pos));
-        }
-        if options.reverse {
-            items.reverse();
+        if reverse {
+            return scan_reverse(&bucket, lower, upper, limit);
         }
 
-        Box::new(BPTreeIterator {
-            items,
-            curr_index: 0,
-            options,
-        })
+        let mut items = Vec::new();
+        let from = bound_key(lower).unwrap_or_default();
+        scan_from(&bucket, &from, lower, upper, |key, value| {
+            items.push((key.to_vec(), decode_log_record_pos(value.to_vec())));
+            items.len() < limit
+        });
+        let has_more = items.len() == limit;
+        (items, has_more)
     }
 }
 
-pub struct BPTreeIterator {
-    items: Vec<(Vec<u8>, LogRecordPos)>, // 存储 key+索引
-    curr_index: usize,                   // 当前遍历的位置下标
-    options: IteratorOptions,            // 配置项
+/// jammdb 的游标只能正向移动，反向遍历时先在范围内找到一个起点，使得起点之后的数据不少于 limit 条
+/// 并且不会太多，然后从起点正向读取，只保留最后 limit 条数据
+/// 起点在 key 的取值范围内二分查找，每次查找最多读取 limit 的 REVERSE_CHUNK_FACTOR 倍的数据
+fn scan_reverse(
+    bucket: &Bucket,
+    lower: &Bound<Vec<u8>>,
+    upper: &Bound<Vec<u8>>,
+    limit: usize,
+) -> (Vec<(Vec<u8>, LogRecordPos)>, bool) {
+    // 从范围内第一个 key 开始查找，和上界有尽可能长的公共前缀
+    let start = bound_key(lower).unwrap_or_default();
+    let mut lo = None;
+    scan_from(bucket, &start, lower, upper, |key, _| {
+        lo = Some(key.to_vec());
+        false
+    });
+    let Some(mut lo) = lo else {
+        return (Vec::new(), false);
+    };
+    let mut hi = match bound_key(upper).or_else(|| last_key(bucket)) {
+        Some(key) => key,
+        None => return (Vec::new(), false),
+    };
+    let mut from = start.clone();
+    let chunk_size = limit * REVERSE_CHUNK_FACTOR;
+    for _ in 0..MAX_REVERSE_SEEK_ROUNDS {
+        let mid = middle_key(&lo, &hi);
+        if mid <= lo || mid >= hi {
+            break;
+        }
+        let mut count = 0;
+        scan_from(bucket, &mid, lower, upper, |_, _| {
+            count += 1;
+            count < chunk_size
+        });
+        if count >= chunk_size {
+            // 起点之后的数据太多，起点向后移动
+            lo = mid.clone();
+            from = mid;
+        } else if count < limit {
+            // 起点之后的数据不够，起点向前移动
+            hi = mid;
+        } else {
+            from = mid;
+            break;
+        }
+    }
+
+    let mut items = VecDeque::with_capacity(limit + 1);
+    let mut skipped = false;
+    scan_from(bucket, &from, lower, upper, |key, value| {
+        items.push_back((key.to_vec(), decode_log_record_pos(value.to_vec())));
+        if items.len() > limit {
+            items.pop_front();
+            skipped = true;
+        }
+        true
+    });
+    // 起点之后的数据不少于 limit 条，起点之前可能还有数据
+    let has_more = skipped || from != start;
+    (items.into_iter().rev().collect(), has_more)
 }
 
-impl crate::index::IndexIterator for BPTreeIterator {
-    fn rewind(&mut self) {
-        self.curr_index = 0;
+/// 从 from 开始正向读取范围内的数据，f 返回 false 时停止
+fn scan_from<F>(
+    bucket: &Bucket,
+    from: &[u8],
+    lower: &Bound<Vec<u8>>,
+    upper: &Bound<Vec<u8>>,
+    mut f: F,
+) where
+    F: FnMut(&[u8], &[u8]) -> bool,
+{
+    let mut cursor = bucket.cursor();
+    if !from.is_empty() {
+        // 定位到叶子节点末尾时当前位置没有数据，先调用一次 next，之后才会移动到下一个叶子节点
+        if !cursor.seek(from) && cursor.current().is_none() && bucket.cursor().next().is_some() {
+            cursor.next();
+        }
     }
 
-    fn seek(&mut self, key: Vec<u8>) {
-        self.curr_index = match self.items.binary_search_by(|(x, _)| {
-            if self.options.reverse {
-                x.cmp(&key).reverse()
-            } else {
-                x.cmp(&key)
-            }
-        }) {
-            Ok(equal_val) => equal_val,
-            Err(insert_val) => insert_val,
-        };
+    for data in cursor {
+        let key = data.key();
+        if !above_lower_bound(key, lower) {
+            continue;
+        }
+        if !below_upper_bound(key, upper) || !f(key, data.kv().value()) {
+            break;
+        }
     }
+}
 
-    fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
-        if self.curr_index >= self.items.len() {
-            return None;
-        }
+/// 逐个字节查找 B+ 树中最大的 key，每个字节二分查找是否存在更大的 key，不需要遍历数据
+fn last_key(bucket: &Bucket) -> Option<Vec<u8>> {
+    let exists_from = |from: &[u8]| {
+        let mut found = false;
+        scan_from(
+            bucket,
+            from,
+            &Bound::Included(from.to_vec()),
+            &Bound::Unbounded,
+            |_, _| {
+                found = true;
+                false
+            },
+        );
+        found
+    };
+    if !exists_from(&[]) {
+        return None;
+    }
 
-        while let Some(item) = self.items.get(self.curr_index) {
-            self.curr_index += 1;
-            let prefix = &self.options.prefix;
-            if prefix.is_empty() || item.0.starts_with(prefix) {
-                return Some((&item.0, &item.1));
+    let mut key = Vec::new();
+    loop {
+        key.push(0);
+        if !exists_from(&key) {
+            key.pop();
+            return Some(key);
+        }
+        let (mut low, mut high) = (0u8, u8::MAX);
+        while low < high {
+            let mid = high - (high - low) / 2;
+            *key.last_mut().unwrap() = mid;
+            match exists_from(&key) {
+                true => low = mid,
+                false => high = mid - 1,
             }
         }
-        None
+        *key.last_mut().unwrap() = low;
+    }
+}
+
+fn bound_key(bound: &Bound<Vec<u8>>) -> Option<Vec<u8>> {
+    match bound {
+        Bound::Included(key) | Bound::Excluded(key) => Some(key.clone()),
+        Bound::Unbounded => None,
+    }
+}
+
+/// 计算 a 和 b 中间的 key，两个 key 按照大端的整数补齐到相同的长度，多出一个字节用于保留除以 2 的余数
+fn middle_key(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let len = a.len().max(b.len()) + 1;
+    let digit = |key: &[u8], i: usize| key.get(i).copied().unwrap_or(0) as u32;
+    let mut sum = vec![0; len];
+    let mut carry = 0;
+    for i in (0..len).rev() {
+        let v = digit(a, i) + digit(b, i) + carry;
+        sum[i] = v & 0xFF;
+        carry = v >> 8;
+    }
+
+    let mut mid = Vec::with_capacity(len);
+    let mut rem = carry;
+    for v in sum {
+        let v = (rem << 8) + v;
+        mid.push((v / 2) as u8);
+        rem = v % 2;
     }
+    mid
 }
@@ -1,13 +1,10 @@
 pub mod bptree;
 pub mod btree;
 pub mod skiplist;
-use std::path::PathBuf;
-
-use bytes::Bytes;
+use std::{ops::Bound, path::PathBuf};
 
 use crate::{
     data::log_record::LogRecordPos,
-    error::Result,
     options::{IndexType, IteratorOptions},
 };
 
@@ -22,9 +19,6 @@ pub trait Indexer: Sync + Send {
     /// 根据 key 删除对应的索引信息信息
     fn delete(&self, key: Vec<u8>) -> Option<LogRecordPos>;
 
-    /// 获取索引存储的所有的 key
-    fn list_keys(&self) -> Result<Vec<Bytes>>;
-
     /// 返回索引迭代器
     fn iterator(&self, options: IteratorOptions) -> Box<dyn IndexIterator>;
 }
@@ -38,6 +32,19 @@ pub fn new_indexer(index_type: IndexType, dir_path: PathBuf) -> Box<dyn Indexer>
     }
 }
 
+/// 根据类型打开 keyspace 对应的内存索引
+pub fn new_keyspace_indexer(
+    index_type: IndexType,
+    dir_path: PathBuf,
+    keyspace: &str,
+) -> Box<dyn Indexer> {
+    match index_type {
+        IndexType::BTree => Box::new(btree::BTree::new()),
+        IndexType::SkipList => Box::new(skiplist::SkipList::new()),
+        IndexType::BPlusTree => Box::new(bptree::BPlusTree::new_keyspace(dir_path, keyspace)),
+    }
+}
+
 pub trait IndexIterator: Sync + Send {
     /// Rewind 重新回到迭代器的起点，即第一个数据
     fn rewind(&mut self);
@@ -48,3 +55,178 @@ pub trait IndexIterator: Sync + Send {
     /// Next 跳转到下一个 key，返回 None 则说明迭代完毕
     fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)>;
 }
+
+/// 按照范围分批读取索引数据，用于实现不需要一次性拷贝全部数据的迭代器
+pub(crate) trait IndexScanner: Sync + Send {
+    /// 读取 [lower, upper] 范围内最多 limit 条数据，reverse 为 true 时从大到小读取
+    /// 返回的 bool 表示范围内是否可能还有更多的数据
+    fn scan(
+        &self,
+        lower: &Bound<Vec<u8>>,
+        upper: &Bound<Vec<u8>>,
+        reverse: bool,
+        limit: usize,
+    ) -> (Vec<(Vec<u8>, LogRecordPos)>, bool);
+}
+
+/// 第一批读取的数据条数，之后每次翻倍，直到 MAX_SCAN_BATCH_SIZE
+const MIN_SCAN_BATCH_SIZE: usize = 16;
+const MAX_SCAN_BATCH_SIZE: usize = 4096;
+
+/// 基于 IndexScanner 的索引迭代器，每次只从索引中读取一批数据，用完之后从上一批最后的 key 继续读取
+pub(crate) struct ScanIterator<S: IndexScanner> {
+    scanner: S,
+    options: IteratorOptions,
+    /// 上下界和前缀共同确定的遍历范围
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+    /// 下一批数据的起点，正向遍历时是下界，反向遍历时是上界
+    cursor: Bound<Vec<u8>>,
+    /// 当前批次的数据
+    items: Vec<(Vec<u8>, LogRecordPos)>,
+    curr_index: usize,
+    has_more: bool,
+    batch_size: usize,
+}
+
+impl<S: IndexScanner> ScanIterator<S> {
+    pub(crate) fn new(scanner: S, options: IteratorOptions) -> Self {
+        let lower = max_lower_bound(
+            options.lower_bound.clone(),
+            Bound::Included(options.prefix.clone()),
+        );
+        let upper = min_upper_bound(
+            options.upper_bound.clone(),
+            prefix_upper_bound(&options.prefix),
+        );
+        let mut iter = Self {
+            scanner,
+            options,
+            lower,
+            upper,
+            cursor: Bound::Unbounded,
+            items: Vec::new(),
+            curr_index: 0,
+            has_more: true,
+            batch_size: MIN_SCAN_BATCH_SIZE,
+        };
+        iter.reset(None);
+        iter
+    }
+
+    /// 从 start 开始重新遍历，start 为 None 时从范围的起点开始
+    fn reset(&mut self, start: Option<Vec<u8>>) {
+        self.cursor = match (self.options.reverse, start) {
+            (false, None) => self.lower.clone(),
+            (true, None) => self.upper.clone(),
+            (false, Some(key)) => max_lower_bound(self.lower.clone(), Bound::Included(key)),
+            (true, Some(key)) => min_upper_bound(self.upper.clone(), Bound::Included(key)),
+        };
+        self.items.clear();
+        self.curr_index = 0;
+        self.has_more = true;
+        self.batch_size = MIN_SCAN_BATCH_SIZE;
+    }
+
+    /// 读取下一批数据
+    fn fill(&mut self) {
+        let (lower, upper) = match self.options.reverse {
+            false => (&self.cursor, &self.upper),
+            true => (&self.lower, &self.cursor),
+        };
+        if range_is_empty(lower, upper) {
+            self.items.clear();
+            self.has_more = false;
+            return;
+        }
+
+        let (items, has_more) =
+            self.scanner
+                .scan(lower, upper, self.options.reverse, self.batch_size);
+        if let Some((key, _)) = items.last() {
+            self.cursor = Bound::Excluded(key.clone());
+        }
+        self.items = items;
+        self.curr_index = 0;
+        self.has_more = has_more;
+        self.batch_size = (self.batch_size * 2).min(MAX_SCAN_BATCH_SIZE);
+    }
+}
+
+impl<S: IndexScanner> IndexIterator for ScanIterator<S> {
+    fn rewind(&mut self) {
+        self.reset(None);
+    }
+
+    fn seek(&mut self, key: Vec<u8>) {
+        self.reset(Some(key));
+    }
+
+    fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        if self.curr_index >= self.items.len() {
+            if !self.has_more {
+                return None;
+            }
+            self.fill();
+        }
+
+        let item = self.items.get(self.curr_index)?;
+        self.curr_index += 1;
+        Some((&item.0, &item.1))
+    }
+}
+
+/// 取两个下界中更严格的一个
+pub(crate) fn max_lower_bound(a: Bound<Vec<u8>>, b: Bound<Vec<u8>>) -> Bound<Vec<u8>> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            match x.cmp(y) {
+                std::cmp::Ordering::Greater => a,
+                std::cmp::Ordering::Less => b,
+                std::cmp::Ordering::Equal if matches!(a, Bound::Excluded(_)) => a,
+                std::cmp::Ordering::Equal => b,
+            }
+        }
+    }
+}
+
+/// 取两个上界中更严格的一个
+pub(crate) fn min_upper_bound(a: Bound<Vec<u8>>, b: Bound<Vec<u8>>) -> Bound<Vec<u8>> {
+    match (&a, &b) {
+        (Bound::Unbounded, _) => b,
+        (_, Bound::Unbounded) => a,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            match x.cmp(y) {
+                std::cmp::Ordering::Less => a,
+                std::cmp::Ordering::Greater => b,
+                std::cmp::Ordering::Equal if matches!(a, Bound::Excluded(_)) => a,
+                std::cmp::Ordering::Equal => b,
+            }
+        }
+    }
+}
+
+/// 以 prefix 为前缀的 key 的上界，即第一个大于所有以 prefix 为前缀的 key 的值
+pub(crate) fn prefix_upper_bound(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut upper = prefix.to_vec();
+    while let Some(last) = upper.pop() {
+        if last < u8::MAX {
+            upper.push(last + 1);
+            return Bound::Excluded(upper);
+        }
+    }
+    Bound::Unbounded
+}
+
+/// 判断范围内是否一定没有数据
+pub(crate) fn range_is_empty(lower: &Bound<Vec<u8>>, upper: &Bound<Vec<u8>>) -> bool {
+    match (lower, upper) {
+        (Bound::Included(l), Bound::Included(u)) => l > u,
+        (Bound::Included(l) | Bound::Excluded(l), Bound::Included(u) | Bound::Excluded(u)) => {
+            l >= u
+        }
+        _ => false,
+    }
+}
@@ -1,28 +1,61 @@
 use bytes::Bytes;
-use parking_lot::RwLock;
-use std::sync::Arc;
 
-use crate::{db::Engine, error::Result, index::IndexIterator, options::IteratorOptions};
+use crate::{
+    data::log_record::{now_nanos, LogRecordPos},
+    db::Engine,
+    error::{Errors, Result},
+    index::{IndexIterator, Indexer},
+    options::{IteratorMode, IteratorOptions},
+};
 
-/// 迭代器接口
+/// 迭代器接口，实现了标准库的 Iterator，读取数据出错时返回对应的错误
 pub struct Iterator<'a> {
     /// 索引迭代器
-    index_iter: Arc<RwLock<Box<dyn IndexIterator>>>,
+    index_iter: Box<dyn IndexIterator>,
     engine: &'a Engine,
+    /// 判断数据是否过期的时间点
+    read_time: u64,
+    mode: IteratorMode,
+    /// 最多返回多少条数据，过期和已经删除的 key 不计入
+    limit: Option<usize>,
+    /// 从 rewind 或 seek 开始已经返回的数据条数
+    returned: usize,
+    source: IndexSource<'a>,
+    /// 创建迭代器时 merge 替换数据文件的次数
+    generation: usize,
+}
+
+/// 迭代器遍历的索引的来源
+pub(crate) enum IndexSource<'a> {
+    /// 数据库当前的索引，merge 替换了数据文件之后重新从索引中查找 key 的位置
+    Live(&'a dyn Indexer),
+    /// 快照中的索引，从快照创建时的数据文件中读取
+    Snapshot(usize),
 }
 
 impl Engine {
     /// 获取迭代器
     pub fn iter(&self, options: IteratorOptions) -> Iterator {
-        Iterator {
-            index_iter: Arc::new(RwLock::new(self.index.iterator(options))),
-            engine: self,
-        }
+        Iterator::new(
+            self.index.as_ref(),
+            options,
+            self,
+            now_nanos(),
+            IndexSource::Live(self.index.as_ref()),
+        )
     }
 
-    /// 返回数据库中所有的 key
+    /// 返回数据库中所有的 key，已经过期的 key 不会返回
     pub fn list_keys(&self) -> Result<Vec<Bytes>> {
-        self.index.list_keys()
+        let now = now_nanos();
+        let mut index_iter = self.index.iterator(IteratorOptions::default());
+        let mut keys = Vec::new();
+        while let Some((key, pos)) = index_iter.next() {
+            if !pos.is_expired(now) {
+                keys.push(Bytes::copy_from_slice(key));
+            }
+        }
+        Ok(keys)
     }
 
     /// 对数据库当中的所有数据执行函数操作，函数返回 false 时终止
@@ -31,38 +64,201 @@ impl Engine {
         Self: Sized,
         F: Fn(Bytes, Bytes) -> bool,
     {
-        let iter = self.iter(IteratorOptions::default());
-        while let Some((key, value)) = iter.next() {
+        for item in self.iter(IteratorOptions::default()) {
+            let (key, value) = item?;
             if !f(key, value) {
                 break;
             }
         }
         Ok(())
     }
+
+    /// 对数据库当中的所有 key 执行函数操作，不会读取数据文件，函数返回 false 时终止
+    pub fn fold_keys<F>(&self, f: F) -> Result<()>
+    where
+        F: Fn(Bytes) -> bool,
+    {
+        let options = IteratorOptions {
+            mode: IteratorMode::KeyOnly,
+            ..Default::default()
+        };
+        for item in self.iter(options) {
+            let (key, _) = item?;
+            if !f(key) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// 获取返回 key 和 value 长度的迭代器，options 中的 mode 不生效
+    pub fn iter_value_sizes(&self, options: IteratorOptions) -> ValueSizeIterator<'_> {
+        ValueSizeIterator {
+            iter: self.iter(IteratorOptions {
+                mode: IteratorMode::KeyOnly,
+                ..options
+            }),
+        }
+    }
+
+    /// 对数据库当中的所有 key 和 value 的长度执行函数操作，函数返回 false 时终止
+    pub fn fold_value_sizes<F>(&self, f: F) -> Result<()>
+    where
+        F: Fn(Bytes, u32) -> bool,
+    {
+        for item in self.iter_value_sizes(IteratorOptions::default()) {
+            let (key, value_size) = item?;
+            if !f(key, value_size) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator<'a> {
+    pub(crate) fn new(
+        index: &dyn Indexer,
+        options: IteratorOptions,
+        engine: &'a Engine,
+        read_time: u64,
+        source: IndexSource<'a>,
+    ) -> Self {
+        let (mode, limit) = (options.mode, options.limit);
+        // 索引中过期的 key 会被跳过，limit 只能在迭代器中计算
+        let index_iter = index.iterator(IteratorOptions {
+            limit: None,
+            ..options
+        });
+        Self {
+            index_iter,
+            engine,
+            read_time,
+            mode,
+            limit,
+            returned: 0,
+            source,
+            generation: engine.merge_generation(),
+        }
+    }
+
+    /// Rewind 重新回到迭代器的起点，即第一个数据
+    pub fn rewind(&mut self) {
+        self.index_iter.rewind();
+        self.returned = 0;
+    }
+
+    /// Seek 根据传入的 key 查找第一个大于（或小于）等于的目标 key，根据从这个 key 开始的遍历
+    pub fn seek(&mut self, key: Vec<u8>) {
+        self.index_iter.seek(key);
+        self.returned = 0;
+    }
 }
 
 impl Iterator<'_> {
+    /// 是否已经返回了 limit 条数据
+    fn limit_reached(&self) -> bool {
+        self.limit.is_some_and(|limit| self.returned >= limit)
+    }
+
+    /// 跳过已经过期的 key，返回下一个 key 和它在索引中的位置
+    fn next_entry(&mut self) -> Option<(Bytes, LogRecordPos)> {
+        while let Some((key, pos)) = self.index_iter.next() {
+            if !pos.is_expired(self.read_time) {
+                return Some((Bytes::from(key.to_vec()), *pos));
+            }
+        }
+        None
+    }
+
+    /// 读取索引位置对应的 value，索引中的位置在 merge 替换数据文件之后会失效
+    fn read_value(&self, key: &[u8], pos: &LogRecordPos) -> Result<Bytes> {
+        let index = match self.source {
+            IndexSource::Snapshot(generation) => {
+                return self
+                    .engine
+                    .get_value_at(Some(pos), self.read_time, Some(generation))
+            }
+            IndexSource::Live(index) => index,
+        };
+
+        // 创建迭代器之后没有替换过数据文件，位置是有效的
+        let generation = self.engine.merge_generation();
+        if generation == self.generation {
+            let result = self.engine.get_value_at(Some(pos), self.read_time, None);
+            if generation == self.engine.merge_generation() {
+                return result;
+            }
+        }
+
+        // 重新从索引中查找 key 的位置
+        self.engine.read_consistent(|| {
+            let pos = index.get(key.to_vec());
+            self.engine.get_value_at(pos.as_ref(), self.read_time, None)
+        })
+    }
+}
+
+impl std::iter::Iterator for Iterator<'_> {
+    type Item = Result<(Bytes, Bytes)>;
+
+    /// Next 跳转到下一个 key，返回 None 则说明迭代完毕，已经过期的 key 会被跳过
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.limit_reached() {
+            return None;
+        }
+        while let Some((key, pos)) = self.next_entry() {
+            let item = match self.mode {
+                IteratorMode::KeyValue => match self.read_value(&key, &pos) {
+                    // merge 之后重新查找时 key 已经被删除
+                    Err(Errors::KeyNotFound) => continue,
+                    result => result.map(|value| (key, value)),
+                },
+                IteratorMode::KeyOnly => Ok((key, Bytes::new())),
+            };
+            self.returned += 1;
+            return Some(item);
+        }
+        None
+    }
+}
+
+/// 返回 key 和 value 长度的迭代器，索引中有 value 的长度时不读取数据文件
+pub struct ValueSizeIterator<'a> {
+    iter: Iterator<'a>,
+}
+
+impl ValueSizeIterator<'_> {
     /// Rewind 重新回到迭代器的起点，即第一个数据
-    fn rewind(&self) {
-        let mut index_iter = self.index_iter.write();
-        index_iter.rewind();
+    pub fn rewind(&mut self) {
+        self.iter.rewind();
     }
 
     /// Seek 根据传入的 key 查找第一个大于（或小于）等于的目标 key，根据从这个 key 开始的遍历
-    fn seek(&self, key: Vec<u8>) {
-        let mut index_iter = self.index_iter.write();
-        index_iter.seek(key);
+    pub fn seek(&mut self, key: Vec<u8>) {
+        self.iter.seek(key);
     }
+}
+
+impl std::iter::Iterator for ValueSizeIterator<'_> {
+    type Item = Result<(Bytes, u32)>;
 
-    /// Next 跳转到下一个 key，返回 None 则说明迭代完毕
-    fn next(&self) -> Option<(Bytes, Bytes)> {
-        let mut index_iter = self.index_iter.write();
-        if let Some(item) = index_iter.next() {
-            let value = self
-                .engine
-                .get_value_by_position(Some(item.1))
-                .expect("fail to get value from data file");
-            return Some((Bytes::from(item.0.to_vec()), value));
+    /// Next 跳转到下一个 key，返回 key 和 value 的长度
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.iter.limit_reached() {
+            return None;
+        }
+        while let Some((key, pos)) = self.iter.next_entry() {
+            let item = match pos.value_size {
+                Some(value_size) => Ok((key, value_size)),
+                // 旧版本的索引中没有 value 的长度，读取 value
+                None => match self.iter.read_value(&key, &pos) {
+                    Err(Errors::KeyNotFound) => continue,
+                    result => result.map(|value| (key, value.len() as u32)),
+                },
+            };
+            self.iter.returned += 1;
+            return Some(item);
         }
         None
     }
@@ -70,9 +266,13 @@ impl Iterator<'_> {
 
 #[cfg(test)]
 mod tests {
-    use std::path::PathBuf;
+    use std::{ops::Bound, path::PathBuf};
 
-    use crate::{options::Options, util};
+    use crate::{
+        error::Errors,
+        options::{IndexType, Options},
+        util,
+    };
 
     use super::*;
 
@@ -141,14 +341,14 @@ mod tests {
         let engine = Engine::open(opts.clone()).expect("failed to open engine");
 
         // 没有数据的情况
-        let iter1 = engine.iter(IteratorOptions::default());
+        let mut iter1 = engine.iter(IteratorOptions::default());
         iter1.seek("aa".as_bytes().to_vec());
         assert!(iter1.next().is_none());
 
         // 有一条数据的情况
         let put_res1 = engine.put(Bytes::from("aacc"), util::rand_kv::get_test_value(10));
         assert!(put_res1.is_ok());
-        let iter2 = engine.iter(IteratorOptions::default());
+        let mut iter2 = engine.iter(IteratorOptions::default());
         iter2.seek("a".as_bytes().to_vec());
         assert!(iter2.next().is_some());
 
@@ -160,9 +360,9 @@ mod tests {
         let put_res4 = engine.put(Bytes::from("ccde"), util::rand_kv::get_test_value(10));
         assert!(put_res4.is_ok());
 
-        let iter3 = engine.iter(IteratorOptions::default());
+        let mut iter3 = engine.iter(IteratorOptions::default());
         iter3.seek("a".as_bytes().to_vec());
-        assert_eq!(Bytes::from("aacc"), iter3.next().unwrap().0);
+        assert_eq!(Bytes::from("aacc"), iter3.next().unwrap().unwrap().0);
 
         // 删除测试的文件夹
         std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
@@ -179,7 +379,7 @@ mod tests {
         // 有一条数据的情况
         let put_res1 = engine.put(Bytes::from("eecc"), util::rand_kv::get_test_value(10));
         assert!(put_res1.is_ok());
-        let iter1 = engine.iter(IteratorOptions::default());
+        let mut iter1 = engine.iter(IteratorOptions::default());
         assert!(iter1.next().is_some());
         iter1.rewind();
         assert!(iter1.next().is_some());
@@ -198,8 +398,8 @@ mod tests {
             ..Default::default()
         };
         let iter2 = engine.iter(iter_opts1);
-        while let Some(item) = iter2.next() {
-            assert!(!item.0.is_empty());
+        for item in iter2 {
+            assert!(!item.unwrap().0.is_empty());
         }
 
         // 删除测试的文件夹
@@ -230,11 +430,294 @@ mod tests {
             ..Default::default()
         };
         let iter1 = engine.iter(iter_opt1);
-        while let Some(item) = iter1.next() {
-            assert!(!item.0.is_empty());
+        for item in iter1 {
+            assert!(!item.unwrap().0.is_empty());
         }
 
         // 删除测试的文件夹
         std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
     }
+
+    #[test]
+    fn test_iterator_read_error() {
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-iter-read-error"),
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let put_res = engine.put(Bytes::from("aacc"), util::rand_kv::get_test_value(10));
+        assert!(put_res.is_ok());
+
+        // 索引指向的数据文件不存在，返回错误而不是 panic
+        let mut pos = engine.index.get("aacc".as_bytes().to_vec()).unwrap();
+        pos.file_id += 100;
+        engine.index.put("aacc".as_bytes().to_vec(), pos);
+        let mut iter = engine.iter(IteratorOptions::default());
+        assert_eq!(
+            Errors::DataFileNotFound,
+            iter.next().unwrap().err().unwrap()
+        );
+        assert!(iter.next().is_none());
+        assert_eq!(
+            Errors::DataFileNotFound,
+            engine.fold(|_, _| true).err().unwrap()
+        );
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_iterator_key_only() {
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-iter-key-only"),
+            index_type: IndexType::BPlusTree,
+            mmap_at_startup: false,
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let put_res = engine.put(Bytes::from("aacc"), Bytes::from("value"));
+        assert!(put_res.is_ok());
+        let put_res = engine.put(Bytes::from("bbcc"), util::rand_kv::get_test_value(10));
+        assert!(put_res.is_ok());
+
+        // 重启之后从 B+ 树中读取的位置信息也带有 value 的长度
+        std::mem::drop(engine);
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        // 索引指向的数据文件不存在，不读取 value 的模式下不受影响
+        let mut pos = engine.index.get("aacc".as_bytes().to_vec()).unwrap();
+        pos.file_id += 100;
+        engine.index.put("aacc".as_bytes().to_vec(), pos);
+
+        let mut iter = engine.iter(IteratorOptions {
+            mode: IteratorMode::KeyOnly,
+            ..Default::default()
+        });
+        let (key, value) = iter.next().unwrap().unwrap();
+        assert_eq!(Bytes::from("aacc"), key);
+        assert!(value.is_empty());
+
+        let mut iter = engine.iter_value_sizes(IteratorOptions::default());
+        assert_eq!((Bytes::from("aacc"), 5), iter.next().unwrap().unwrap());
+        assert_eq!(
+            (
+                Bytes::from("bbcc"),
+                util::rand_kv::get_test_value(10).len() as u32
+            ),
+            iter.next().unwrap().unwrap()
+        );
+        assert!(iter.next().is_none());
+
+        let total = std::cell::Cell::new(0);
+        assert!(engine
+            .fold_value_sizes(|_, size| {
+                total.set(total.get() + size);
+                true
+            })
+            .is_ok());
+        assert_eq!(
+            5 + util::rand_kv::get_test_value(10).len() as u32,
+            total.get()
+        );
+        let count = std::cell::Cell::new(0);
+        assert!(engine
+            .fold_keys(|_| {
+                count.set(count.get() + 1);
+                true
+            })
+            .is_ok());
+        assert_eq!(2, count.get());
+
+        // 删除测试的文件夹
+        std::mem::drop(iter);
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_iterator_value_size_without_size_in_index() {
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-iter-value-size-legacy"),
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let put_res = engine.put(Bytes::from("aacc"), util::rand_kv::get_test_value(10));
+        assert!(put_res.is_ok());
+
+        // 旧版本的索引中没有 value 的长度，读取 value 得到长度
+        let mut pos = engine.index.get("aacc".as_bytes().to_vec()).unwrap();
+        pos.value_size = None;
+        engine.index.put("aacc".as_bytes().to_vec(), pos);
+
+        let mut iter = engine.iter_value_sizes(IteratorOptions::default());
+        assert_eq!(
+            (
+                Bytes::from("aacc"),
+                util::rand_kv::get_test_value(10).len() as u32
+            ),
+            iter.next().unwrap().unwrap()
+        );
+        assert!(iter.next().is_none());
+        assert_eq!(
+            util::rand_kv::get_test_value(10).len() as u32,
+            engine.metadata(Bytes::from("aacc")).unwrap().value_size
+        );
+
+        // 删除测试的文件夹
+        std::mem::drop(iter);
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_iterator_bounds() {
+        for (dir, index_type) in [
+            ("/tmp/bitcask-rs-iter-bounds-btree", IndexType::BTree),
+            ("/tmp/bitcask-rs-iter-bounds-skl", IndexType::SkipList),
+            ("/tmp/bitcask-rs-iter-bounds-bptree", IndexType::BPlusTree),
+        ] {
+            let opts = Options {
+                dir_path: PathBuf::from(dir),
+                index_type,
+                ..Default::default()
+            };
+            let engine = Engine::open(opts.clone()).expect("failed to open engine");
+            for key in ["aa", "bb", "cc", "dd", "ee"] {
+                let put_res = engine.put(Bytes::from(key), util::rand_kv::get_test_value(10));
+                assert!(put_res.is_ok());
+            }
+
+            let collect = |iter_opts: IteratorOptions| {
+                let mut keys = Vec::new();
+                for item in engine.iter(iter_opts) {
+                    let (key, _) = item.unwrap();
+                    keys.push(String::from_utf8(key.to_vec()).unwrap());
+                }
+                keys
+            };
+
+            // 包含下界，不包含上界
+            let keys = collect(IteratorOptions {
+                lower_bound: Bound::Included("bb".as_bytes().to_vec()),
+                upper_bound: Bound::Excluded("dd".as_bytes().to_vec()),
+                ..Default::default()
+            });
+            assert_eq!(vec!["bb", "cc"], keys);
+
+            // 不包含下界，包含上界，反向遍历
+            let keys = collect(IteratorOptions {
+                lower_bound: Bound::Excluded("bb".as_bytes().to_vec()),
+                upper_bound: Bound::Included("dd".as_bytes().to_vec()),
+                reverse: true,
+                ..Default::default()
+            });
+            assert_eq!(vec!["dd", "cc"], keys);
+
+            // 限制返回的条数
+            let keys = collect(IteratorOptions {
+                lower_bound: Bound::Included("b".as_bytes().to_vec()),
+                limit: Some(2),
+                ..Default::default()
+            });
+            assert_eq!(vec!["bb", "cc"], keys);
+
+            // 过期的 key 不计入返回的条数
+            let put_res = engine.put_with_ttl(
+                Bytes::from("bc"),
+                util::rand_kv::get_test_value(10),
+                std::time::Duration::ZERO,
+            );
+            assert!(put_res.is_ok());
+            let keys = collect(IteratorOptions {
+                lower_bound: Bound::Included("b".as_bytes().to_vec()),
+                limit: Some(2),
+                ..Default::default()
+            });
+            assert_eq!(vec!["bb", "cc"], keys);
+            let sizes = engine.iter_value_sizes(IteratorOptions {
+                lower_bound: Bound::Included("b".as_bytes().to_vec()),
+                limit: Some(2),
+                ..Default::default()
+            });
+            assert_eq!(2, sizes.count());
+
+            // seek 之后重新计数
+            let mut iter = engine.iter(IteratorOptions {
+                limit: Some(1),
+                ..Default::default()
+            });
+            assert!(iter.next().is_some());
+            assert!(iter.next().is_none());
+            iter.seek("dd".as_bytes().to_vec());
+            assert_eq!(Bytes::from("dd"), iter.next().unwrap().unwrap().0);
+            assert!(iter.next().is_none());
+
+            // 删除测试的文件夹
+            std::mem::drop(engine);
+            std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+        }
+    }
+
+    #[test]
+    fn test_iterator_batches() {
+        for (dir, index_type) in [
+            ("/tmp/bitcask-rs-iter-batches-btree", IndexType::BTree),
+            ("/tmp/bitcask-rs-iter-batches-skl", IndexType::SkipList),
+            ("/tmp/bitcask-rs-iter-batches-bptree", IndexType::BPlusTree),
+        ] {
+            let opts = Options {
+                dir_path: PathBuf::from(dir),
+                index_type,
+                ..Default::default()
+            };
+            let engine = Engine::open(opts.clone()).expect("failed to open engine");
+            for i in 0..5000 {
+                let put_res = engine.put(
+                    util::rand_kv::get_test_key(i),
+                    util::rand_kv::get_test_value(i),
+                );
+                assert!(put_res.is_ok());
+            }
+
+            // 数据量超过单个批次，需要分多次从索引中读取
+            let mut iter = engine.iter(IteratorOptions::default());
+            for i in 0..5000 {
+                let (key, _) = iter.next().unwrap().unwrap();
+                assert_eq!(util::rand_kv::get_test_key(i), key);
+            }
+            assert!(iter.next().is_none());
+
+            // 反向遍历全部数据，每一批数据都从范围中间的起点开始读取
+            let iter = engine.iter(IteratorOptions {
+                reverse: true,
+                ..Default::default()
+            });
+            let keys: Vec<Bytes> = iter.map(|item| item.unwrap().0).collect();
+            assert_eq!(5000, keys.len());
+            for (i, key) in keys.iter().enumerate() {
+                assert_eq!(util::rand_kv::get_test_key(4999 - i), key);
+            }
+
+            // 反向遍历前缀范围内的数据
+            let iter = engine.iter(IteratorOptions {
+                prefix: "bitcask-rs-key-0000012".as_bytes().to_vec(),
+                reverse: true,
+                ..Default::default()
+            });
+            let keys: Vec<Bytes> = iter.map(|item| item.unwrap().0).collect();
+            assert_eq!(100, keys.len());
+            assert_eq!(util::rand_kv::get_test_key(1299), keys[0]);
+            assert_eq!(util::rand_kv::get_test_key(1200), keys[99]);
+
+            // seek 之后从对应的位置继续分批读取
+            let mut iter = engine.iter(IteratorOptions::default());
+            iter.seek(util::rand_kv::get_test_key(2500).to_vec());
+            assert_eq!(2500, iter.count());
+
+            // 删除测试的文件夹
+            std::mem::drop(engine);
+            std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+        }
+    }
 }
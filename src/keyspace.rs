@@ -0,0 +1,411 @@
+use bytes::Bytes;
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    batch::{log_record_key_with_seq, NON_TRANSCATION_SEQ_NO},
+    data::log_record::{expire_after, now_nanos, LogRecord, LogRecordType},
+    db::Engine,
+    error::{Errors, Result},
+    index::Indexer,
+    iterator::{IndexSource, Iterator},
+    options::IteratorOptions,
+};
+
+/// keyspace 名称的最大长度
+const MAX_KEYSPACE_NAME_LEN: usize = 64;
+
+/// 独立的命名空间，拥有自己的内存索引，和默认的 keyspace 共享数据文件以及 merge
+pub struct Keyspace<'a> {
+    name: String,
+    index: Arc<dyn Indexer>,
+    engine: &'a Engine,
+}
+
+/// keyspace 相关统计信息
+#[derive(Debug)]
+pub struct KeyspaceStat {
+    /// key 的总数量
+    pub key_num: usize,
+    /// 有效数据在磁盘上占据的空间大小
+    pub data_size: u64,
+}
+
+impl Engine {
+    /// 打开 keyspace，不存在则创建
+    /// 名称只能由字母、数字、下划线和中划线组成
+    pub fn keyspace(&self, name: &str) -> Result<Keyspace<'_>> {
+        check_keyspace_name(name)?;
+        Ok(Keyspace {
+            name: name.to_string(),
+            index: self.keyspace_index(name),
+            engine: self,
+        })
+    }
+
+    /// 返回所有的 keyspace 名称，不包括默认的 keyspace
+    pub fn list_keyspaces(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.keyspaces.read().keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// 删除 keyspace 中的所有数据，只需要写入一条记录
+    /// 删除之后之前打开的 Keyspace 句柄不能再写入，需要重新打开
+    pub fn drop_keyspace(&self, name: &str) -> Result<()> {
+        check_keyspace_name(name)?;
+        let _lock = self.batch_commit_lock.lock();
+        if !self.keyspaces.read().contains_key(name) {
+            return Ok(());
+        }
+
+        let mut record = LogRecord {
+            key: log_record_key_with_seq(Vec::new(), NON_TRANSCATION_SEQ_NO),
+            value: Default::default(),
+            rec_type: LogRecordType::KeyspaceDropped,
+            expire: 0,
+            keyspace: name.as_bytes().to_vec(),
+        };
+        let pos = self.append_log_record(&mut record)?;
+        self.record_writes(name.as_bytes(), []);
+
+        for old_pos in self.remove_keyspace_index(name) {
+            self.add_reclaim_size(&old_pos);
+        }
+        self.add_reclaim_size(&pos);
+        Ok(())
+    }
+}
+
+impl Keyspace<'_> {
+    /// keyspace 的名称
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// 存储 key/value 数据，key 不能为空
+    pub fn put(&self, key: Bytes, value: Bytes) -> Result<()> {
+        self.put_with_expire(key, value, 0)
+    }
+
+    /// 存储 key/value 数据，并设置过期时间，过期之后数据不可见
+    pub fn put_with_ttl(&self, key: Bytes, value: Bytes, ttl: Duration) -> Result<()> {
+        let expire = expire_after(ttl);
+        self.put_with_expire(key, value, expire)
+    }
+
+    fn put_with_expire(&self, key: Bytes, value: Bytes, expire: u64) -> Result<()> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        let _lock = self.engine.batch_commit_lock.lock();
+        self.check_not_dropped()?;
+        self.engine.put_to_index(
+            self.index.as_ref(),
+            self.name.as_bytes(),
+            &key,
+            value,
+            expire,
+        )
+    }
+
+    /// 根据 key 获取对应的数据
+    pub fn get(&self, key: Bytes) -> Result<Bytes> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        self.engine.read_consistent(|| {
+            let pos = self.index.get(key.to_vec());
+            self.engine.get_value_by_position(pos.as_ref())
+        })
+    }
+
+    /// 根据 key 删除对应的数据
+    pub fn delete(&self, key: Bytes) -> Result<()> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        let _lock = self.engine.batch_commit_lock.lock();
+        self.check_not_dropped()?;
+        self.engine
+            .delete_from_index(self.index.as_ref(), self.name.as_bytes(), &key)
+    }
+
+    /// 删除 [start, end) 范围内的所有数据
+    pub fn delete_range(&self, start: Bytes, end: Bytes) -> Result<()> {
+        if start.is_empty() || end.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        let _lock = self.engine.batch_commit_lock.lock();
+        self.check_not_dropped()?;
+        self.engine
+            .delete_range_from_index(self.index.as_ref(), self.name.as_bytes(), &start, &end)
+    }
+
+    /// keyspace 被删除之后，之前打开的句柄不能再写入，调用方需要持有写锁
+    fn check_not_dropped(&self) -> Result<()> {
+        match self.engine.keyspaces.read().get(&self.name) {
+            Some(index) if Arc::ptr_eq(index, &self.index) => Ok(()),
+            _ => Err(Errors::KeyspaceDropped),
+        }
+    }
+
+    /// 获取 keyspace 上的迭代器
+    pub fn iter(&self, options: IteratorOptions) -> Iterator<'_> {
+        Iterator::new(
+            self.index.as_ref(),
+            options,
+            self.engine,
+            now_nanos(),
+            IndexSource::Live(self.index.as_ref()),
+        )
+    }
+
+    /// 返回 keyspace 中所有的 key，已经过期的 key 不会返回
+    pub fn list_keys(&self) -> Result<Vec<Bytes>> {
+        let now = now_nanos();
+        let mut index_iter = self.index.iterator(IteratorOptions::default());
+        let mut keys = Vec::new();
+        while let Some((key, pos)) = index_iter.next() {
+            if !pos.is_expired(now) {
+                keys.push(Bytes::copy_from_slice(key));
+            }
+        }
+        Ok(keys)
+    }
+
+    /// 对 keyspace 当中的所有数据执行函数操作，函数返回 false 时终止
+    pub fn fold<F>(&self, f: F) -> Result<()>
+    where
+        F: Fn(Bytes, Bytes) -> bool,
+    {
+        for item in self.iter(IteratorOptions::default()) {
+            let (key, value) = item?;
+            if !f(key, value) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// 获取 keyspace 统计信息
+    pub fn stat(&self) -> Result<KeyspaceStat> {
+        let now = now_nanos();
+        let mut index_iter = self.index.iterator(IteratorOptions::default());
+        let mut stat = KeyspaceStat {
+            key_num: 0,
+            data_size: 0,
+        };
+        while let Some((_, pos)) = index_iter.next() {
+            if !pos.is_expired(now) {
+                stat.key_num += 1;
+                stat.data_size += pos.size as u64;
+            }
+        }
+        Ok(stat)
+    }
+}
+
+/// 校验 keyspace 名称，名称会作为 B+ 树索引文件名的一部分
+fn check_keyspace_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_KEYSPACE_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        return Err(Errors::InvalidKeyspaceName);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        options::{IndexType, Options},
+        util::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    fn test_keyspace(dir: &str, index_type: IndexType) {
+        let opts = Options {
+            dir_path: PathBuf::from(dir),
+            index_type,
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(
+            Errors::InvalidKeyspaceName,
+            engine.keyspace("a/b").err().unwrap()
+        );
+
+        let users = engine.keyspace("users").expect("failed to open keyspace");
+        let orders = engine.keyspace("orders").expect("failed to open keyspace");
+        for i in 0..10 {
+            assert!(users.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        assert!(orders.put(get_test_key(1), get_test_value(100)).is_ok());
+        assert!(engine.put(get_test_key(1), get_test_value(200)).is_ok());
+        assert!(users.delete(get_test_key(9)).is_ok());
+        assert!(users.delete_range(get_test_key(7), get_test_key(9)).is_ok());
+
+        // 不同 keyspace 之间的数据互相独立
+        assert_eq!(get_test_value(1), users.get(get_test_key(1)).unwrap());
+        assert_eq!(get_test_value(100), orders.get(get_test_key(1)).unwrap());
+        assert_eq!(get_test_value(200), engine.get(get_test_key(1)).unwrap());
+        assert_eq!(
+            Errors::KeyNotFound,
+            orders.get(get_test_key(2)).err().unwrap()
+        );
+        assert_eq!(7, users.list_keys().unwrap().len());
+        assert_eq!(1, orders.stat().unwrap().key_num);
+        assert_eq!(1, engine.list_keys().unwrap().len());
+        assert_eq!(
+            vec!["orders".to_string(), "users".to_string()],
+            engine.list_keyspaces()
+        );
+
+        // 删除整个 keyspace，之前打开的句柄不能再写入
+        assert!(engine.drop_keyspace("orders").is_ok());
+        assert_eq!(0, orders.list_keys().unwrap().len());
+        assert_eq!(vec!["users".to_string()], engine.list_keyspaces());
+        assert!(!opts.dir_path.join("bptree-index-orders").exists());
+        assert_eq!(
+            Errors::KeyspaceDropped,
+            orders
+                .put(get_test_key(2), get_test_value(2))
+                .err()
+                .unwrap()
+        );
+        std::mem::drop(orders);
+        let orders = engine.keyspace("orders").expect("failed to open keyspace");
+        assert!(orders.put(get_test_key(2), get_test_value(2)).is_ok());
+
+        // 重启之后校验
+        std::mem::drop(users);
+        std::mem::drop(orders);
+        std::mem::drop(engine);
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        let users = engine2.keyspace("users").expect("failed to open keyspace");
+        let orders = engine2.keyspace("orders").expect("failed to open keyspace");
+        assert_eq!(7, users.list_keys().unwrap().len());
+        assert_eq!(
+            Errors::KeyNotFound,
+            users.get(get_test_key(8)).err().unwrap()
+        );
+        assert_eq!(
+            Errors::KeyNotFound,
+            orders.get(get_test_key(1)).err().unwrap()
+        );
+        assert_eq!(get_test_value(2), orders.get(get_test_key(2)).unwrap());
+        assert_eq!(get_test_value(200), engine2.get(get_test_key(1)).unwrap());
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_keyspace_btree() {
+        test_keyspace("/tmp/bitcask-rs-keyspace-btree", IndexType::BTree);
+    }
+
+    #[test]
+    fn test_keyspace_bptree() {
+        test_keyspace("/tmp/bitcask-rs-keyspace-bptree", IndexType::BPlusTree);
+    }
+
+    #[test]
+    fn test_keyspace_merge() {
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-keyspace-merge"),
+            data_file_size: 32 * 1024 * 1024,
+            data_file_merge_ratio: 0 as f32,
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let users = engine.keyspace("users").expect("failed to open keyspace");
+        for i in 0..1000 {
+            assert!(users.put(get_test_key(i), get_test_value(i)).is_ok());
+            assert!(engine.put(get_test_key(i), get_test_value(i + 1)).is_ok());
+        }
+        for i in 0..500 {
+            assert!(users.delete(get_test_key(i)).is_ok());
+        }
+        assert!(engine.merge().is_ok());
+
+        // 重启之后从 hint 文件中加载 keyspace 的索引
+        std::mem::drop(users);
+        std::mem::drop(engine);
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        let users = engine2.keyspace("users").expect("failed to open keyspace");
+        assert_eq!(500, users.list_keys().unwrap().len());
+        assert_eq!(1000, engine2.list_keys().unwrap().len());
+        assert_eq!(get_test_value(600), users.get(get_test_key(600)).unwrap());
+        assert_eq!(get_test_value(601), engine2.get(get_test_key(600)).unwrap());
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    fn test_keyspace_drop_merge(dir: &str, index_type: IndexType) {
+        let opts = Options {
+            dir_path: PathBuf::from(dir),
+            data_file_merge_ratio: 0 as f32,
+            index_type,
+            mmap_at_startup: false,
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let users = engine.keyspace("users").expect("failed to open keyspace");
+        let orders = engine.keyspace("orders").expect("failed to open keyspace");
+        for i in 0..100 {
+            assert!(users.put(get_test_key(i), get_test_value(i)).is_ok());
+            assert!(orders.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        std::mem::drop(orders);
+        assert!(engine.drop_keyspace("orders").is_ok());
+
+        // merge 不会重新创建已经删除的 keyspace
+        assert!(engine.merge().is_ok());
+        assert_eq!(vec!["users".to_string()], engine.list_keyspaces());
+        assert!(!opts.dir_path.join("bptree-index-orders").exists());
+
+        // 重启之后仍然是删除的状态
+        std::mem::drop(users);
+        std::mem::drop(engine);
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(vec!["users".to_string()], engine2.list_keyspaces());
+        let users = engine2.keyspace("users").expect("failed to open keyspace");
+        assert_eq!(100, users.list_keys().unwrap().len());
+        let orders = engine2.keyspace("orders").expect("failed to open keyspace");
+        assert_eq!(0, orders.list_keys().unwrap().len());
+
+        // 删除测试的文件夹
+        std::mem::drop(users);
+        std::mem::drop(orders);
+        std::mem::drop(engine2);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_keyspace_drop_merge_btree() {
+        test_keyspace_drop_merge(
+            "/tmp/bitcask-rs-keyspace-drop-merge-btree",
+            IndexType::BTree,
+        );
+    }
+
+    #[test]
+    fn test_keyspace_drop_merge_bptree() {
+        test_keyspace_drop_merge(
+            "/tmp/bitcask-rs-keyspace-drop-merge-bptree",
+            IndexType::BPlusTree,
+        );
+    }
+}
@@ -1,4 +1,5 @@
 #![feature(file_lock)]
+mod auto_merge;
 mod batch;
 mod data;
 pub mod db;
@@ -6,9 +7,14 @@ pub mod error;
 mod fio;
 mod index;
 pub mod iterator;
-mod merge;
+pub mod keyspace;
+pub mod merge;
 pub mod options;
+pub mod repair;
+pub mod snapshot;
+pub mod transaction;
 mod util;
+pub mod verify;
 
 pub fn add(left: usize, right: usize) -> usize {
     left + right
@@ -1,26 +1,174 @@
 use log::error;
-use std::{fs, path::PathBuf};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
 use crate::{
     batch::{log_record_key_with_seq, parse_log_record_key, NON_TRANSCATION_SEQ_NO},
     data::{
+        cipher::Cipher,
         data_file::{
-            get_data_file_name, DataFile, DATA_FILE_NAME_SUFFIX, HINT_FILE_NAME,
-            MERGE_FINISHED_FILE_NAME, SEQ_FILE_NAME,
+            get_data_file_name, DataFile, DATA_FILE_HEADER_SIZE, DATA_FILE_NAME_SUFFIX,
+            HINT_FILE_NAME, MERGE_FINISHED_FILE_NAME, SEQ_FILE_NAME,
         },
-        log_record::{decode_log_record_pos, LogRecord, LogRecordType},
+        log_record::{decode_log_record_pos, now_nanos, LogRecord, LogRecordPos, LogRecordType},
     },
     db::{Engine, FILE_LOCK_NAME},
     error::{Errors, Result},
-    options::Options,
+    options::{Compression, IOType},
+    repair::list_data_file_ids,
 };
 
 const MERGE_FIR_NAME: &str = "merge";
 const MERGE_FIN_KEY: &[u8] = "merge.finished".as_bytes();
+pub(crate) const MERGE_FILES_KEY: &[u8] = "merge.files".as_bytes();
+
+/// merge 的进度，可以在其他线程中查询进度或者取消 merge
+#[derive(Debug, Clone, Default)]
+pub struct MergeProgress {
+    inner: Arc<MergeProgressInner>,
+}
+
+#[derive(Debug, Default)]
+struct MergeProgressInner {
+    files_total: AtomicUsize,
+    files_done: AtomicUsize,
+    bytes_rewritten: AtomicU64,
+    bytes_reclaimed: AtomicU64,
+    cancelled: AtomicBool,
+}
+
+impl MergeProgress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 参与 merge 的数据文件数量
+    pub fn files_total(&self) -> usize {
+        self.inner.files_total.load(Ordering::SeqCst)
+    }
+
+    /// 已经重写完成的数据文件数量
+    pub fn files_done(&self) -> usize {
+        self.inner.files_done.load(Ordering::SeqCst)
+    }
+
+    /// 已经写入 merge 之后的文件的字节数
+    pub fn bytes_rewritten(&self) -> u64 {
+        self.inner.bytes_rewritten.load(Ordering::SeqCst)
+    }
+
+    /// 已经重写完成的文件预计可以回收的字节数，替换时 merge 期间被覆盖的数据不会计算在内
+    pub fn estimated_bytes_reclaimed(&self) -> u64 {
+        self.inner.bytes_reclaimed.load(Ordering::SeqCst)
+    }
+
+    /// 取消 merge，已经重写完成的文件仍然会替换，数据目录保持有效
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// 是否已经取消
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// 开始新的 merge 时清空进度，取消的标识保留
+    fn reset(&self, files_total: usize) {
+        self.inner.files_total.store(files_total, Ordering::SeqCst);
+        self.inner.files_done.store(0, Ordering::SeqCst);
+        self.inner.bytes_rewritten.store(0, Ordering::SeqCst);
+        self.inner.bytes_reclaimed.store(0, Ordering::SeqCst);
+    }
+}
+
+/// 限制 merge 读写数据的速度
+struct RateLimiter {
+    bytes_per_sec: Option<u64>,
+    start: Instant,
+    bytes: u64,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_sec: Option<u64>) -> Self {
+        Self {
+            bytes_per_sec,
+            start: Instant::now(),
+            bytes: 0,
+        }
+    }
+
+    /// 记录读写的字节数，超过速度限制时等待
+    fn consume(&mut self, size: u64) {
+        let bytes_per_sec = match self.bytes_per_sec {
+            Some(bytes_per_sec) => bytes_per_sec,
+            None => return,
+        };
+        self.bytes += size;
+        let expected = Duration::from_secs_f64(self.bytes as f64 / bytes_per_sec as f64);
+        let elapsed = self.start.elapsed();
+        if expected > elapsed {
+            thread::sleep(expected - elapsed);
+        }
+    }
+}
+
+/// merge 替换掉的旧数据文件
+pub(crate) struct RetiredFile {
+    /// 替换之后 merge 替换数据文件的次数，在这之前创建的快照需要读取这个文件
+    pub(crate) generation: usize,
+    pub(crate) file_id: u32,
+    pub(crate) file: DataFile,
+}
+
+/// merge 时原样保留的删除和事务完成的标识，更早的文件都 merge 之后才可以回收
+pub(crate) struct KeptTombstones {
+    /// 保留时还没有参与 merge 的更早的文件 id
+    shadowed_file_ids: Vec<u32>,
+    size: usize,
+}
+
+/// merge 重写之后的数据文件
+struct MergedFile {
+    file_id: u32,
+    /// 没有任何数据，替换时直接删除
+    is_empty: bool,
+    /// 参与 merge 时有效的数据在新的文件中的位置
+    relocations: Vec<Relocation>,
+    /// 原样保留的删除和事务完成的标识占据的空间
+    kept_size: usize,
+    /// 保留的标识可能覆盖的还没有参与 merge 的更早的文件 id
+    shadowed_file_ids: Vec<u32>,
+}
+
+/// 一条数据在旧的文件中的偏移和在新的文件中的位置，已经过期而被丢弃的数据没有新的位置
+struct Relocation {
+    keyspace: Vec<u8>,
+    key: Vec<u8>,
+    offset: u64,
+    pos: Option<LogRecordPos>,
+}
 
 impl Engine {
-    /// merge 数据目录，处理无效数据，并生成 hint 索引文件
+    /// merge 数据目录，处理无效数据
+    /// 只有可以回收的空间比例达到 data_file_merge_ratio 的数据文件才会参与 merge，每个文件单独重写
+    /// 所有更早的数据文件都参与了 merge 时会生成 hint 索引文件
+    /// merge 完成之后在线替换数据文件并更新索引中的位置，不需要重启就可以回收空间
     pub fn merge(&self) -> Result<()> {
+        self.merge_with_progress(&MergeProgress::new())
+    }
+
+    /// merge 数据目录，可以通过 progress 在其他线程中查询进度或者取消 merge
+    /// 取消时已经重写完成的文件仍然会替换，返回 MergeCancelled
+    pub fn merge_with_progress(&self, progress: &MergeProgress) -> Result<()> {
         // 如果是空的数据库则直接返回
         if self.is_empty_engine() {
             return Ok(());
@@ -31,48 +179,94 @@ impl Engine {
         if lock.is_none() {
             return Err(Errors::MergeInProgress);
         }
-        // 判断是否达到了 merge 的比例阈值
-        let reclaim_size = self.reclaim_size.load(std::sync::atomic::Ordering::SeqCst);
-        let total_size = crate::util::file::dir_disk_size(self.options.dir_path.clone());
-        if (reclaim_size as f32 / total_size as f32) < self.options.data_file_merge_ratio {
+
+        let merge_path = get_merge_path(self.options.dir_path.clone());
+        // 如果目录已经存在，则先删除
+        if merge_path.is_dir() {
+            fs::remove_dir_all(merge_path.clone()).unwrap();
+        }
+
+        // 获取所有需要进行 merge 的数据文件，没有达到比例阈值的文件则直接返回
+        let merge_files = self.rotate_merge_file()?;
+        if merge_files.is_empty() {
             return Err(Errors::MergeRatioUnreached);
         }
+        progress.reset(merge_files.len());
 
         // 判断磁盘剩余空间是否足够容纳 merge 之后的数据
+        let live_size: u64 = {
+            let file_reclaim_sizes = self.file_reclaim_sizes.read();
+            merge_files
+                .iter()
+                .map(|file| {
+                    let reclaim = file_reclaim_sizes.get(&file.get_file_id()).copied();
+                    file.file_size().saturating_sub(reclaim.unwrap_or(0) as u64)
+                })
+                .sum()
+        };
         let available_size = crate::util::file::available_disk_size();
-        if total_size - reclaim_size as u64 >= available_size {
+        if live_size >= available_size {
             return Err(Errors::MeregeNoEnoughSpace);
         }
 
-        let merge_path = get_merge_path(self.options.dir_path.clone());
-        // 如果目录已经存在，则先删除
-        if merge_path.is_dir() {
-            fs::remove_dir_all(merge_path.clone()).unwrap();
-        }
         // 创建 merge 数据目录
         if let Err(e) = fs::create_dir_all(merge_path.clone()) {
             error!("failed to create merge path {}", e);
             return Err(Errors::FailedCreateDatabaseDir);
         }
 
-        // 获取所有需要进行 merge 的数据文件
-        let merge_files = self.rotate_merge_file()?;
-
-        // 打开临时用于 merge 的 bitcask 实例
-        let merge_db_opts = Options {
-            dir_path: merge_path.clone(),
-            data_file_size: self.options.data_file_size,
-            ..Default::default()
+        // 比参与 merge 的文件更早，但是没有参与 merge 的文件中可能有被删除的数据
+        // 这时需要保留删除和事务完成的标识，否则重启之后旧的数据会重新生效
+        let merge_file_ids: Vec<u32> = merge_files.iter().map(|f| f.get_file_id()).collect();
+        let mut unmerged_file_ids: Vec<u32> = self
+            .older_files
+            .read()
+            .keys()
+            .filter(|fid| !merge_file_ids.contains(fid))
+            .copied()
+            .collect();
+        unmerged_file_ids.sort();
+        let first_unmerged_id = unmerged_file_ids.first().copied().unwrap_or(u32::MAX);
+        let is_full_merge = first_unmerged_id > *merge_file_ids.last().unwrap();
+
+        // 完整的 merge 才生成 hint 文件，hint 文件中的数据在加载时不会按照文件的顺序处理
+        let hint_file = match is_full_merge {
+            true => Some(DataFile::new_hint_file(
+                merge_path.clone(),
+                self.cipher.clone(),
+            )?),
+            false => None,
         };
-        let merge_db = Engine::open(merge_db_opts)?;
 
-        // 打开 hint 文件存储索引
-        let hint_file = DataFile::new_hint_file(merge_path.clone())?;
-
-        // 依次处理每个数据文件，重写有效的数据
+        // 依次处理每个数据文件，重写到 merge 目录中相同 id 的文件，已经过期的数据直接丢弃
+        // 取消时只保留已经重写完成的文件，文件按照 id 从小到大处理，完整的 merge 仍然是完整的
+        let now = now_nanos();
+        let mut rate_limiter = RateLimiter::new(self.options.merge_rate_limit);
+        let mut merged_files = Vec::new();
+        let mut cancelled = false;
         for data_file in merge_files.iter() {
-            let mut offset = 0;
+            if progress.is_cancelled() {
+                cancelled = true;
+                break;
+            }
+
+            let file_id = data_file.get_file_id();
+            let keep_tombstones = first_unmerged_id < file_id;
+            let merged_file = DataFile::new(
+                merge_path.clone(),
+                file_id,
+                IOType::StandardFIO,
+                self.cipher.clone(),
+            )?;
+
+            let mut relocations = Vec::new();
+            let mut kept_size = 0;
+            let mut offset = data_file.first_record_offset();
             loop {
+                if progress.is_cancelled() {
+                    cancelled = true;
+                    break;
+                }
                 let (mut log_record, size) = match data_file.read_log_record(offset) {
                     Ok(result) => (result.record, result.size),
                     Err(e) => {
@@ -83,79 +277,371 @@ impl Engine {
                         }
                     }
                 };
+                rate_limiter.consume(size);
+
+                // 删除和事务完成的标识原样保留
+                if keep_tombstones && log_record.rec_type != LogRecordType::Normal {
+                    merged_file.write(&data_file.read_raw(offset, size)?)?;
+                    rate_limiter.consume(size);
+                    progress
+                        .inner
+                        .bytes_rewritten
+                        .fetch_add(size, Ordering::SeqCst);
+                    kept_size += size as usize;
+                    offset += size;
+                    continue;
+                }
 
-                // 解码拿到实际的 key
+                // 解码拿到实际的 key，已经被删除的 keyspace 中的数据都是无效的
                 let (real_key, _) = parse_log_record_key(log_record.key.clone());
-                if let Some(index_pos) = self.index.get(real_key.clone()) {
+                let index_pos = self
+                    .with_existing_index(&log_record.keyspace, |index| index.get(real_key.clone()))
+                    .flatten();
+                if let Some(index_pos) = index_pos {
                     // 如果文件 id 和 偏移 offset 均相等，则说明是有一条有效的数据
-                    if index_pos.file_id == data_file.get_file_id() && index_pos.offset == offset {
+                    if index_pos.file_id == file_id
+                        && index_pos.offset == offset
+                        && index_pos.is_expired(now)
+                    {
+                        // 过期的数据在加载时当作删除处理，同样需要保留，否则更早的旧数据会重新生效
+                        if keep_tombstones {
+                            merged_file.write(&data_file.read_raw(offset, size)?)?;
+                            rate_limiter.consume(size);
+                            progress
+                                .inner
+                                .bytes_rewritten
+                                .fetch_add(size, Ordering::SeqCst);
+                            kept_size += size as usize;
+                        }
+                        relocations.push(Relocation {
+                            keyspace: log_record.keyspace.clone(),
+                            key: real_key.clone(),
+                            offset,
+                            pos: None,
+                        });
+                    } else if index_pos.file_id == file_id && index_pos.offset == offset {
                         // 去除事务的标识
                         log_record.key =
                             log_record_key_with_seq(real_key.clone(), NON_TRANSCATION_SEQ_NO);
-                        let log_record_pos = merge_db.append_log_record(&mut log_record)?;
-                        // 写 hint 索引
-                        hint_file.write_hint_record(real_key.clone(), log_record_pos)?;
+                        let enc_record =
+                            log_record.encode_with(self.options.compression, self.cipher.as_ref());
+                        let log_record_pos = LogRecordPos {
+                            file_id,
+                            offset: merged_file.get_write_off(),
+                            size: enc_record.len() as u32,
+                            expire: log_record.expire,
+                            value_size: Some(log_record.value.len() as u32),
+                            version: index_pos.version,
+                        };
+                        merged_file.write(&enc_record)?;
+                        rate_limiter.consume(enc_record.len() as u64);
+                        progress
+                            .inner
+                            .bytes_rewritten
+                            .fetch_add(enc_record.len() as u64, Ordering::SeqCst);
+                        relocations.push(Relocation {
+                            keyspace: log_record.keyspace.clone(),
+                            key: real_key.clone(),
+                            offset,
+                            pos: Some(log_record_pos),
+                        });
                     }
                 }
                 offset += size;
             }
+
+            // 没有处理完的文件直接删除
+            if cancelled {
+                std::mem::drop(merged_file);
+                fs::remove_file(get_data_file_name(merge_path.clone(), file_id)).unwrap();
+                break;
+            }
+
+            // 写 hint 索引
+            if let Some(hint_file) = hint_file.as_ref() {
+                for relocation in relocations.iter() {
+                    if let Some(pos) = relocation.pos {
+                        hint_file.write_hint_record(
+                            relocation.keyspace.clone(),
+                            relocation.key.clone(),
+                            pos,
+                        )?;
+                    }
+                }
+            }
+
+            // sync 保证持久化
+            merged_file.sync()?;
+            progress.inner.files_done.fetch_add(1, Ordering::SeqCst);
+            progress.inner.bytes_reclaimed.fetch_add(
+                data_file
+                    .file_size()
+                    .saturating_sub(merged_file.get_write_off()),
+                Ordering::SeqCst,
+            );
+            merged_files.push(MergedFile {
+                file_id,
+                is_empty: merged_file.get_write_off() == merged_file.first_record_offset(),
+                relocations,
+                kept_size,
+                shadowed_file_ids: unmerged_file_ids
+                    .iter()
+                    .filter(|fid| **fid < file_id)
+                    .copied()
+                    .collect(),
+            });
         }
 
-        // sync 保证持久化
-        merge_db.sync()?;
-        hint_file.sync()?;
+        // 还没有重写完任何文件就被取消
+        if merged_files.is_empty() {
+            fs::remove_dir_all(merge_path).unwrap();
+            return Err(Errors::MergeCancelled);
+        }
 
-        // 拿到最近未参与 merge 的文件 id
-        let non_merge_file_id = merge_files.last().unwrap().get_file_id() + 1;
-        let merge_fin_file = DataFile::new_merge_fin_file(merge_path.clone())?;
-        let merge_fin_record = LogRecord {
-            key: MERGE_FIN_KEY.to_vec(),
-            value: non_merge_file_id.to_string().into_bytes(),
-            rec_type: LogRecordType::Normal,
+        // 完整的 merge 记录最近未参与 merge 的文件 id，否则记录参与 merge 的文件 id
+        let merge_file_ids: Vec<u32> = merged_files.iter().map(|f| f.file_id).collect();
+        let last_merge_id = *merge_file_ids.last().unwrap();
+        let merge_fin_record = match hint_file {
+            Some(hint_file) => {
+                hint_file.sync()?;
+                LogRecord {
+                    key: MERGE_FIN_KEY.to_vec(),
+                    value: (last_merge_id + 1).to_string().into_bytes(),
+                    rec_type: LogRecordType::Normal,
+                    expire: 0,
+                    keyspace: Default::default(),
+                }
+            }
+            None => {
+                let ids: Vec<String> = merge_file_ids.iter().map(|fid| fid.to_string()).collect();
+                LogRecord {
+                    key: MERGE_FILES_KEY.to_vec(),
+                    value: ids.join(",").into_bytes(),
+                    rec_type: LogRecordType::Normal,
+                    expire: 0,
+                    keyspace: Default::default(),
+                }
+            }
         };
-        let enc_record = merge_fin_record.encode();
+        let merge_fin_file = DataFile::new_merge_fin_file(merge_path.clone(), self.cipher.clone())?;
+        let enc_record = merge_fin_record.encode_with(Compression::None, self.cipher.as_ref());
         merge_fin_file.write(&enc_record)?;
         merge_fin_file.sync()?;
 
+        // 替换数据文件，中途失败的话下次启动时会继续替换剩下的文件
+        let dir_path = self.options.dir_path.clone();
+        if !is_full_merge {
+            remove_hint_files(&dir_path);
+        }
+        for merged in merged_files {
+            self.swap_merged_file(&merge_path, merged)?;
+        }
+        if is_full_merge {
+            move_hint_files(&merge_path, &dir_path);
+        }
+
+        // 最后删除临时 merge 的目录
+        fs::remove_dir_all(merge_path).unwrap();
+
+        if cancelled {
+            return Err(Errors::MergeCancelled);
+        }
         Ok(())
     }
 
+    /// 用 merge 目录中的文件替换数据目录中相同 id 的文件，并更新索引中的位置
+    /// 替换期间阻塞写入，读取时发现 merge 次数变化会重新查找索引
+    fn swap_merged_file(&self, merge_path: &Path, merged: MergedFile) -> Result<()> {
+        let _lock = self.batch_commit_lock.lock();
+        let mut older_files = self.older_files.write();
+
+        let file_id = merged.file_id;
+        let src_path = get_data_file_name(merge_path.to_path_buf(), file_id);
+        let dest_path = get_data_file_name(self.options.dir_path.clone(), file_id);
+        fs::rename(src_path, &dest_path).unwrap();
+
+        // 没有数据的文件直接删除
+        let old_file = if merged.is_empty {
+            fs::remove_file(&dest_path).unwrap();
+            older_files.remove(&file_id)
+        } else {
+            let data_file = DataFile::new(
+                self.options.dir_path.clone(),
+                file_id,
+                IOType::StandardFIO,
+                self.cipher.clone(),
+            )?;
+            older_files.insert(file_id, data_file)
+        };
+
+        // 更新索引中仍然指向旧文件中这条数据的位置，merge 期间被覆盖的数据在新的文件中也是无效的
+        // 保留的标识在更早的文件 merge 之前仍然有效，不计入可以回收的空间
+        let mut reclaim = 0;
+        // merge 期间被删除的 keyspace 中的数据也是无效的
+        for relocation in merged.relocations {
+            let new_pos = relocation.pos;
+            let relocated = self.with_existing_index(&relocation.keyspace, |index| {
+                match index.get(relocation.key.clone()) {
+                    Some(pos) if pos.file_id == file_id && pos.offset == relocation.offset => {
+                        self.save_snapshot_versions(
+                            &relocation.keyspace,
+                            [relocation.key.as_slice()],
+                        );
+                        match new_pos {
+                            Some(new_pos) => index.put(relocation.key, new_pos),
+                            // 已经过期的数据没有写入新的文件
+                            None => index.delete(relocation.key),
+                        };
+                        true
+                    }
+                    _ => false,
+                }
+            });
+            if relocated != Some(true) {
+                reclaim += new_pos.map_or(0, |pos| pos.size as usize);
+            }
+        }
+
+        let mut file_reclaim_sizes = self.file_reclaim_sizes.write();
+        let old_reclaim = file_reclaim_sizes.remove(&file_id).unwrap_or(0);
+        if !merged.is_empty && reclaim > 0 {
+            file_reclaim_sizes.insert(file_id, reclaim);
+        }
+        self.reclaim_size
+            .fetch_add(reclaim, std::sync::atomic::Ordering::SeqCst);
+        self.reclaim_size
+            .fetch_sub(old_reclaim, std::sync::atomic::Ordering::SeqCst);
+
+        // 这个文件中被覆盖的旧数据已经丢弃，更新的文件中只覆盖了这些数据的标识可以回收了
+        let mut kept_tombstones = self.kept_tombstones.lock();
+        kept_tombstones.remove(&file_id);
+        for (fid, kept) in kept_tombstones.iter_mut() {
+            kept.shadowed_file_ids
+                .retain(|shadowed| *shadowed != file_id);
+            if kept.shadowed_file_ids.is_empty() {
+                *file_reclaim_sizes.entry(*fid).or_default() += kept.size;
+                self.reclaim_size
+                    .fetch_add(kept.size, std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+        kept_tombstones.retain(|_, kept| !kept.shadowed_file_ids.is_empty());
+        if merged.kept_size > 0 && !merged.shadowed_file_ids.is_empty() {
+            kept_tombstones.insert(
+                file_id,
+                KeptTombstones {
+                    shadowed_file_ids: merged.shadowed_file_ids,
+                    size: merged.kept_size,
+                },
+            );
+        }
+
+        // 读取期间发现次数变化的话重新查找索引，存活的快照仍然需要读取旧的文件
+        let generation = self
+            .merge_generation
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+            + 1;
+        if let Some(old_file) = old_file {
+            if !self.snapshot_generations.lock().is_empty() {
+                self.retired_files.write().push(RetiredFile {
+                    generation,
+                    file_id,
+                    file: old_file,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 释放快照，不再被任何快照使用的旧数据文件会被关闭
+    pub(crate) fn release_snapshot(&self, generation: usize) {
+        let mut snapshot_generations = self.snapshot_generations.lock();
+        if let Some(count) = snapshot_generations.get_mut(&generation) {
+            *count -= 1;
+            if *count == 0 {
+                snapshot_generations.remove(&generation);
+            }
+        }
+
+        // 旧的文件只有替换之前创建的快照需要读取
+        let oldest = snapshot_generations.keys().next().copied();
+        self.retired_files
+            .write()
+            .retain(|retired| oldest.is_some_and(|oldest| oldest < retired.generation));
+    }
+
+    /// 可以回收的空间比例是否达到了阈值
+    pub(crate) fn reach_merge_ratio(&self) -> bool {
+        let reclaim_size = self.reclaim_size.load(std::sync::atomic::Ordering::SeqCst);
+        let total_size = crate::util::file::dir_disk_size(self.options.dir_path.clone());
+        total_size > 0
+            && reclaim_size as f32 / total_size as f32 >= self.options.data_file_merge_ratio
+    }
+
     fn is_empty_engine(&self) -> bool {
         let active_file = self.active_file.read();
         let older_files = self.older_files.read();
-        active_file.get_write_off() == 0 && older_files.len() == 0
+        active_file.get_write_off() == active_file.first_record_offset() && older_files.len() == 0
     }
 
+    /// 选出需要 merge 的数据文件，活跃文件需要 merge 时设置一个新的活跃文件
     pub fn rotate_merge_file(&self) -> Result<Vec<DataFile>> {
-        // 取出旧的数据文件的 id
-        let mut merge_file_ids = Vec::new();
+        // 和写入时的加锁顺序保持一致，先锁活跃文件
+        let mut active_file = self.active_file.write();
         let mut older_files = self.older_files.write();
-        for fid in older_files.keys() {
-            merge_file_ids.push(*fid);
+        let ratio = self.options.data_file_merge_ratio;
+
+        // 可以回收的空间比例达到阈值的文件参与 merge
+        let reach_ratio = |file: &DataFile, size: u64| {
+            let data_size = size - file.first_record_offset();
+            let reclaim = self
+                .file_reclaim_sizes
+                .read()
+                .get(&file.get_file_id())
+                .copied();
+            data_size > 0 && reclaim.unwrap_or(0) as f32 >= ratio * data_size as f32
+        };
+        let mut merge_file_ids: Vec<u32> = older_files
+            .values()
+            .filter(|file| reach_ratio(file, file.file_size()))
+            .map(|file| file.get_file_id())
+            .collect();
+        let merge_active_file = reach_ratio(&active_file, active_file.get_write_off());
+        if merge_file_ids.is_empty() && !merge_active_file {
+            return Ok(Vec::new());
         }
 
-        // 设置一个新的活跃文件用于写入
-        let mut active_file = self.active_file.write();
-        // sync 数据文件保证持久性
-        active_file.sync()?;
-        let active_file_id = active_file.get_file_id();
-        let new_active_file = DataFile::new(
-            self.options.dir_path.clone(),
-            active_file_id + 1,
-            crate::options::IOType::StandardFIO,
-        )?;
-        *active_file = new_active_file;
-
-        // 加到旧的数据文件当中
-        let old_file = DataFile::new(
-            self.options.dir_path.clone(),
-            active_file_id,
-            crate::options::IOType::StandardFIO,
-        )?;
-        older_files.insert(active_file_id, old_file);
-
-        // 加载到 merge 的文件 id 列表中
-        merge_file_ids.push(active_file_id);
+        // 没有数据的旧文件一起清理掉
+        for file in older_files.values() {
+            if file.file_size() == file.first_record_offset() {
+                merge_file_ids.push(file.get_file_id());
+            }
+        }
+
+        if merge_active_file {
+            // sync 数据文件保证持久性
+            active_file.sync()?;
+            let active_file_id = active_file.get_file_id();
+            let new_active_file = DataFile::new(
+                self.options.dir_path.clone(),
+                active_file_id + 1,
+                IOType::StandardFIO,
+                self.cipher.clone(),
+            )?;
+            *active_file = new_active_file;
+
+            // 加到旧的数据文件当中
+            let old_file = DataFile::new(
+                self.options.dir_path.clone(),
+                active_file_id,
+                IOType::StandardFIO,
+                self.cipher.clone(),
+            )?;
+            older_files.insert(active_file_id, old_file);
+
+            // 加载到 merge 的文件 id 列表中
+            merge_file_ids.push(active_file_id);
+        }
 
         // 从小到大排序，依次 merge
         merge_file_ids.sort();
@@ -166,7 +652,8 @@ impl Engine {
             let data_file = DataFile::new(
                 self.options.dir_path.clone(),
                 *file_id,
-                crate::options::IOType::StandardFIO,
+                IOType::StandardFIO,
+                self.cipher.clone(),
             )?;
             merge_files.push(data_file);
         }
@@ -182,7 +669,8 @@ impl Engine {
             return Ok(());
         }
 
-        let hint_file = DataFile::new_hint_file(self.options.dir_path.clone())?;
+        let hint_file =
+            DataFile::new_hint_file(self.options.dir_path.clone(), self.cipher.clone())?;
         let mut offset = 0;
         loop {
             let (log_record, size) = match hint_file.read_log_record(offset) {
@@ -198,8 +686,13 @@ impl Engine {
 
             // 解码 value, 拿到位置索引信息
             let log_record_pos = decode_log_record_pos(log_record.value);
-            // 存储到内存索引中
-            self.index.put(log_record.key, log_record_pos);
+            // 之后从数据文件中加载的数据分配的写入版本号需要更大
+            self.txn_tracker.lock().advance_to(log_record_pos.version);
+            // 存储到内存索引中，hint 文件中只有 merge 时仍然存在的 keyspace 的数据
+            // 之后被删除的 keyspace 在加载数据文件中的删除记录时会被清除
+            self.with_index(&log_record.keyspace, |index| {
+                index.put(log_record.key, log_record_pos)
+            });
             offset += size;
         }
         Ok(())
@@ -215,13 +708,138 @@ fn get_merge_path(dir_path: PathBuf) -> PathBuf {
 }
 
 /// 加载 merge 数据目录
-pub(crate) fn load_merge_files(dir_path: PathBuf) -> Result<()> {
+pub(crate) fn load_merge_files(dir_path: PathBuf, cipher: Option<Cipher>) -> Result<()> {
     let merge_path = get_merge_path(dir_path.clone());
     // 没有发生过 merge 则直接返回
     if !merge_path.is_dir() {
         return Ok(());
     }
 
+    // merge 没有完成，直接返回
+    if !merge_path.join(MERGE_FINISHED_FILE_NAME).is_file() {
+        fs::remove_dir_all(merge_path.clone()).unwrap();
+        return Ok(());
+    }
+
+    // 旧版本的 merge 目录由临时的引擎实例生成，带有文件锁
+    if merge_path.join(FILE_LOCK_NAME).is_file() {
+        return load_legacy_merge_files(dir_path, merge_path, cipher);
+    }
+
+    // 打开标识 merge 完成过的文件，取出参与了 merge 的文件 id
+    let merge_fin_file = DataFile::new_merge_fin_file(merge_path.clone(), cipher)?;
+    let merge_fin_record = merge_fin_file.read_log_record(0)?.record;
+    let v = String::from_utf8(merge_fin_record.value).unwrap();
+    let is_full_merge = merge_fin_record.key == MERGE_FIN_KEY;
+    let file_ids: Vec<u32> = match is_full_merge {
+        true => (0..v.parse::<u32>().unwrap()).collect(),
+        false => v.split(',').map(|fid| fid.parse().unwrap()).collect(),
+    };
+
+    // 只有部分文件参与 merge 时，hint 文件中可能有被替换的文件中的位置，直接删除，从数据文件中加载索引
+    if !is_full_merge {
+        remove_hint_files(&dir_path);
+    }
+
+    // 用 merge 目录中的文件逐个替换参与了 merge 的数据文件，每个文件的替换都是原子的
+    // merge 目录中不存在的文件已经替换过了，中途失败的话下次启动时会继续替换剩下的文件
+    for file_id in file_ids.iter() {
+        let src_path = get_data_file_name(merge_path.clone(), *file_id);
+        if src_path.is_file() {
+            let dest_path = get_data_file_name(dir_path.clone(), *file_id);
+            fs::rename(src_path, dest_path).unwrap();
+        }
+    }
+
+    if is_full_merge {
+        move_hint_files(&merge_path, &dir_path);
+    }
+
+    // 删除没有数据的文件
+    for file_id in file_ids.iter() {
+        let file = get_data_file_name(dir_path.clone(), *file_id);
+        if let Ok(meta) = fs::metadata(&file) {
+            if meta.len() <= DATA_FILE_HEADER_SIZE {
+                fs::remove_file(file).unwrap();
+            }
+        }
+    }
+
+    // 最后删除临时 merge 的目录
+    fs::remove_dir_all(merge_path.clone()).unwrap();
+
+    Ok(())
+}
+
+/// 不修改任何文件，计算应用已经完成的 merge 之后每个数据文件所在的目录，用于离线修复
+/// 和打开数据库时应用 merge 的结果一致，没有完成的 merge 直接忽略
+pub(crate) fn merged_data_file_sources(
+    dir_path: &Path,
+    cipher: Option<Cipher>,
+) -> Result<BTreeMap<u32, PathBuf>> {
+    let mut sources: BTreeMap<u32, PathBuf> = list_data_file_ids(dir_path)?
+        .into_iter()
+        .map(|file_id| (file_id, dir_path.to_path_buf()))
+        .collect();
+    let merge_path = get_merge_path(dir_path.to_path_buf());
+    if !merge_path.join(MERGE_FINISHED_FILE_NAME).is_file() {
+        return Ok(sources);
+    }
+
+    let merge_fin_file = DataFile::new_merge_fin_file(merge_path.clone(), cipher)?;
+    let merge_fin_record = merge_fin_file.read_log_record(0)?.record;
+    let v = String::from_utf8(merge_fin_record.value).unwrap();
+    if merge_path.join(FILE_LOCK_NAME).is_file() {
+        // 旧版本的 merge 删除全部参与了 merge 的文件，再移动 merge 目录中不为空的数据文件
+        let non_merge_fid = v.parse::<u32>().unwrap();
+        sources.retain(|file_id, _| *file_id >= non_merge_fid);
+        for file_id in list_data_file_ids(&merge_path)? {
+            let file = get_data_file_name(merge_path.clone(), file_id);
+            if fs::metadata(file).is_ok_and(|meta| meta.len() > 0) {
+                sources.insert(file_id, merge_path.clone());
+            }
+        }
+    } else {
+        // merge 目录中不存在的文件已经替换过了
+        let file_ids: Vec<u32> = match merge_fin_record.key == MERGE_FIN_KEY {
+            true => (0..v.parse::<u32>().unwrap()).collect(),
+            false => v.split(',').map(|fid| fid.parse().unwrap()).collect(),
+        };
+        for file_id in file_ids {
+            if get_data_file_name(merge_path.clone(), file_id).is_file() {
+                sources.insert(file_id, merge_path.clone());
+            }
+        }
+    }
+    Ok(sources)
+}
+
+/// 删除数据目录中的 hint 文件和标识 merge 完成的文件
+fn remove_hint_files(dir_path: &Path) {
+    for file_name in [HINT_FILE_NAME, MERGE_FINISHED_FILE_NAME] {
+        let file = dir_path.join(file_name);
+        if file.is_file() {
+            fs::remove_file(file).unwrap();
+        }
+    }
+}
+
+/// 完整的 merge 将 hint 文件移动到数据目录中，标识 merge 完成的文件最后移动
+fn move_hint_files(merge_path: &Path, dir_path: &Path) {
+    for file_name in [HINT_FILE_NAME, MERGE_FINISHED_FILE_NAME] {
+        let src_path = merge_path.join(file_name);
+        if src_path.is_file() {
+            fs::rename(src_path, dir_path.join(file_name)).unwrap();
+        }
+    }
+}
+
+/// 加载旧版本的 merge 目录，merge 之后的数据文件从 0 开始重新编号
+fn load_legacy_merge_files(
+    dir_path: PathBuf,
+    merge_path: PathBuf,
+    cipher: Option<Cipher>,
+) -> Result<()> {
     let dir = match fs::read_dir(merge_path.clone()) {
         Ok(dir) => dir,
         Err(e) => {
@@ -230,17 +848,11 @@ pub(crate) fn load_merge_files(dir_path: PathBuf) -> Result<()> {
         }
     };
 
-    // 查找是否有标识 merge 完成的文件
     let mut merge_file_names = Vec::new();
-    let mut merge_finished = false;
     for entry in dir.into_iter().flatten() {
         let file_os_str = entry.file_name();
         let file_name = file_os_str.to_str().unwrap();
 
-        if file_name.ends_with(MERGE_FINISHED_FILE_NAME) {
-            merge_finished = true;
-        }
-
         if file_name.ends_with(SEQ_FILE_NAME) {
             continue;
         }
@@ -258,14 +870,8 @@ pub(crate) fn load_merge_files(dir_path: PathBuf) -> Result<()> {
         merge_file_names.push(entry.file_name());
     }
 
-    // merge 没有完成，直接返回
-    if !merge_finished {
-        fs::remove_dir_all(merge_path.clone()).unwrap();
-        return Ok(());
-    }
-
     // 打开标识 merge 完成过的文件，取出未参与 merge 的文件 id
-    let merge_fin_file = DataFile::new_merge_fin_file(merge_path.clone())?;
+    let merge_fin_file = DataFile::new_merge_fin_file(merge_path.clone(), cipher)?;
     let merge_fin_record = merge_fin_file.read_log_record(0)?;
     let v = String::from_utf8(merge_fin_record.record.value).unwrap();
     let non_merge_fid = v.parse::<u32>().unwrap();
@@ -294,7 +900,10 @@ pub(crate) fn load_merge_files(dir_path: PathBuf) -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::util::rand_kv::{get_test_key, get_test_value};
+    use crate::{
+        options::Options,
+        util::rand_kv::{get_test_key, get_test_value},
+    };
     use bytes::Bytes;
     use std::{sync::Arc, thread};
 
@@ -477,4 +1086,356 @@ mod tests {
         // 删除测试的文件夹
         std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
     }
+
+    #[test]
+    fn test_merge_selective() {
+        // 只有可以回收的空间比例达到阈值的文件参与 merge
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-merge-selective"),
+            data_file_size: 64 * 1024,
+            data_file_merge_ratio: 0.5,
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..700 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        // 删除标识写在文件 1 中，文件 0 中的旧数据不参与 merge
+        assert!(engine.delete(get_test_key(0)).is_ok());
+        for i in 700..1200 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 600..1200 {
+            assert!(engine
+                .put(get_test_key(i), Bytes::from("new value in merge"))
+                .is_ok());
+        }
+
+        let file_reclaim_sizes = engine.file_reclaim_sizes.read().clone();
+        let reclaim: usize = file_reclaim_sizes.values().sum();
+        assert_eq!(
+            reclaim,
+            engine
+                .reclaim_size
+                .load(std::sync::atomic::Ordering::SeqCst)
+        );
+        assert!(file_reclaim_sizes[&0] < file_reclaim_sizes[&1]);
+
+        let file0 = get_data_file_name(opts.dir_path.clone(), 0);
+        let file1 = get_data_file_name(opts.dir_path.clone(), 1);
+        let file0_size = fs::metadata(&file0).unwrap().len();
+        let file1_size = fs::metadata(&file1).unwrap().len();
+        assert!(engine.merge().is_ok());
+        std::mem::drop(engine);
+
+        // 重启之后只有文件 1 被重写
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(file0_size, fs::metadata(&file0).unwrap().len());
+        assert!(fs::metadata(&file1).unwrap().len() < file1_size);
+        assert_eq!(1199, engine2.list_keys().unwrap().len());
+        assert_eq!(
+            Errors::KeyNotFound,
+            engine2.get(get_test_key(0)).err().unwrap()
+        );
+        assert_eq!(get_test_value(1), engine2.get(get_test_key(1)).unwrap());
+        assert_eq!(
+            Bytes::from("new value in merge"),
+            engine2.get(get_test_key(1000)).unwrap()
+        );
+
+        // 没有达到比例阈值的文件
+        assert_eq!(Errors::MergeRatioUnreached, engine2.merge().err().unwrap());
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_merge_kept_tombstones_reclaim() {
+        // 保留的删除标识在更早的文件 merge 之前不计入可以回收的空间
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-merge-kept-tombstones"),
+            data_file_size: 64 * 1024,
+            data_file_merge_ratio: 0.5,
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..700 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        assert!(engine.delete(get_test_key(0)).is_ok());
+        for i in 700..1200 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 600..1200 {
+            assert!(engine
+                .put(get_test_key(i), Bytes::from("new value in merge"))
+                .is_ok());
+        }
+
+        // 只有文件 1 参与 merge，删除标识保留之后文件 1 中没有可以回收的空间
+        assert!(engine.merge().is_ok());
+        assert!(engine.file_reclaim_sizes.read().get(&1).is_none());
+        let kept_size = engine.kept_tombstones.lock()[&1].size;
+        assert!(kept_size > 0);
+        assert_eq!(Errors::MergeRatioUnreached, engine.merge().err().unwrap());
+
+        // 文件 0 merge 之后删除标识可以回收
+        for i in 1..600 {
+            assert!(engine
+                .put(get_test_key(i), Bytes::from("new value in merge"))
+                .is_ok());
+        }
+        let file1_reclaim = engine
+            .file_reclaim_sizes
+            .read()
+            .get(&1)
+            .copied()
+            .unwrap_or(0);
+        assert!(engine.merge().is_ok());
+        let file_reclaim_sizes = engine.file_reclaim_sizes.read().clone();
+        assert_eq!(file1_reclaim + kept_size, file_reclaim_sizes[&1]);
+        assert!(engine.kept_tombstones.lock().is_empty());
+        assert_eq!(
+            file_reclaim_sizes.values().sum::<usize>(),
+            engine
+                .reclaim_size
+                .load(std::sync::atomic::Ordering::SeqCst)
+        );
+        assert_eq!(
+            Errors::KeyNotFound,
+            engine.get(get_test_key(0)).err().unwrap()
+        );
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_merge_selective_expired() {
+        // 参与 merge 的文件中过期的数据需要保留，否则没有参与 merge 的文件中的旧数据会重新生效
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-merge-selective-expired"),
+            data_file_size: 64 * 1024,
+            data_file_merge_ratio: 0.5,
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..700 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        // 过期的数据写在文件 1 中，文件 0 中的旧数据不参与 merge
+        assert!(engine
+            .put_with_ttl(
+                get_test_key(0),
+                Bytes::from("new value with ttl"),
+                Duration::from_millis(50)
+            )
+            .is_ok());
+        for i in 700..1200 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 600..1200 {
+            assert!(engine
+                .put(get_test_key(i), Bytes::from("new value in merge"))
+                .is_ok());
+        }
+        std::thread::sleep(Duration::from_millis(100));
+
+        let file0 = get_data_file_name(opts.dir_path.clone(), 0);
+        let file1 = get_data_file_name(opts.dir_path.clone(), 1);
+        let file0_size = fs::metadata(&file0).unwrap().len();
+        let file1_size = fs::metadata(&file1).unwrap().len();
+        assert!(engine.merge().is_ok());
+        assert_eq!(
+            Errors::KeyNotFound,
+            engine.get(get_test_key(0)).err().unwrap()
+        );
+        std::mem::drop(engine);
+
+        // 重启之后过期的数据仍然不可见
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(file0_size, fs::metadata(&file0).unwrap().len());
+        assert!(fs::metadata(&file1).unwrap().len() < file1_size);
+        assert_eq!(
+            Errors::KeyNotFound,
+            engine2.get(get_test_key(0)).err().unwrap()
+        );
+        assert_eq!(get_test_value(1), engine2.get(get_test_key(1)).unwrap());
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_merge_online() {
+        // merge 之后不需要重启，读取和写入不受影响
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-merge-online"),
+            data_file_size: 64 * 1024,
+            data_file_merge_ratio: 0 as f32,
+            mmap_at_startup: false,
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..3000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..2000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i + 1)).is_ok());
+        }
+        for i in 2000..2500 {
+            assert!(engine.delete(get_test_key(i)).is_ok());
+        }
+        let users = engine.keyspace("users").expect("failed to open keyspace");
+        assert!(users.put(get_test_key(1), Bytes::from("user")).is_ok());
+
+        // 快照创建之后被覆盖的数据不会写入 merge 之后的文件
+        let snapshot = engine.snapshot();
+        assert!(engine.put(get_test_key(2600), get_test_value(0)).is_ok());
+
+        let disk_size = crate::util::file::dir_disk_size(opts.dir_path.clone());
+        thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    for _ in 0..5 {
+                        for i in 0..2000 {
+                            assert_eq!(get_test_value(i + 1), engine.get(get_test_key(i)).unwrap());
+                        }
+                    }
+                });
+            }
+            scope.spawn(|| {
+                for i in 3000..4000 {
+                    assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+                }
+            });
+            assert!(engine.merge().is_ok());
+        });
+
+        // 旧的文件已经被替换，merge 目录已经删除
+        assert!(crate::util::file::dir_disk_size(opts.dir_path.clone()) < disk_size);
+        assert!(!get_merge_path(opts.dir_path.clone()).is_dir());
+        assert_eq!(3500, engine.list_keys().unwrap().len());
+        assert_eq!(get_test_value(1), engine.get(get_test_key(0)).unwrap());
+        assert_eq!(
+            get_test_value(2999),
+            engine.get(get_test_key(2999)).unwrap()
+        );
+        assert_eq!(get_test_value(0), engine.get(get_test_key(2600)).unwrap());
+        assert_eq!(
+            Errors::KeyNotFound,
+            engine.get(get_test_key(2000)).err().unwrap()
+        );
+        assert_eq!(Bytes::from("user"), users.get(get_test_key(1)).unwrap());
+        assert_eq!(3500, engine.iter(Default::default()).count());
+        let report = engine.verify().expect("failed to verify");
+        assert!(report.is_ok(), "{:?}", report.issues);
+
+        // 快照从替换之前的文件中读取
+        assert_eq!(
+            get_test_value(2600),
+            snapshot.get(get_test_key(2600)).unwrap()
+        );
+        assert_eq!(
+            2500,
+            snapshot
+                .iter(Default::default())
+                .filter(|item| item.is_ok())
+                .count()
+        );
+        assert!(!engine.retired_files.read().is_empty());
+        std::mem::drop(snapshot);
+        assert!(engine.retired_files.read().is_empty());
+
+        // 重启校验
+        std::mem::drop(users);
+        std::mem::drop(engine);
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(3500, engine2.list_keys().unwrap().len());
+        assert_eq!(get_test_value(1), engine2.get(get_test_key(0)).unwrap());
+        assert_eq!(
+            get_test_value(3999),
+            engine2.get(get_test_key(3999)).unwrap()
+        );
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_merge_progress() {
+        // 限制 merge 的读写速度，查询进度以及取消 merge
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-merge-progress"),
+            data_file_size: 64 * 1024,
+            data_file_merge_ratio: 0 as f32,
+            mmap_at_startup: false,
+            merge_rate_limit: Some(2 * 1024 * 1024),
+            ..Default::default()
+        };
+        assert_eq!(
+            Errors::InvalidMergeRateLimit,
+            Engine::open(Options {
+                merge_rate_limit: Some(0),
+                ..opts.clone()
+            })
+            .err()
+            .unwrap()
+        );
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..3000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..3000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i + 1)).is_ok());
+        }
+
+        // 重写完第一个文件之后取消，已经重写完成的文件仍然生效
+        let progress = MergeProgress::new();
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                while progress.files_done() == 0 {
+                    thread::sleep(std::time::Duration::from_millis(1));
+                }
+                progress.cancel();
+            });
+            assert_eq!(
+                Errors::MergeCancelled,
+                engine.merge_with_progress(&progress).err().unwrap()
+            );
+        });
+        assert!(progress.files_done() >= 1);
+        assert!(progress.files_done() < progress.files_total());
+        assert!(progress.estimated_bytes_reclaimed() > 0);
+        assert!(!get_merge_path(opts.dir_path.clone()).is_dir());
+        assert_eq!(get_test_value(1), engine.get(get_test_key(0)).unwrap());
+        let report = engine.verify().expect("failed to verify");
+        assert!(report.is_ok(), "{:?}", report.issues);
+
+        // 完整地执行 merge，读写的速度不超过限制
+        let progress = MergeProgress::new();
+        let start = std::time::Instant::now();
+        assert!(engine.merge_with_progress(&progress).is_ok());
+        let limit = opts.merge_rate_limit.unwrap() as f64;
+        assert!(start.elapsed().as_secs_f64() >= progress.bytes_rewritten() as f64 / limit);
+        assert_eq!(progress.files_total(), progress.files_done());
+        assert!(progress.bytes_rewritten() > 0);
+
+        // 重启校验
+        std::mem::drop(engine);
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(3000, engine2.list_keys().unwrap().len());
+        assert_eq!(
+            get_test_value(3000),
+            engine2.get(get_test_key(2999)).unwrap()
+        );
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
 }
@@ -1,4 +1,6 @@
-use std::path::PathBuf;
+use std::{ops::Bound, path::PathBuf, time::Duration};
+
+use crate::util::bound::{above_lower_bound, below_upper_bound};
 
 #[derive(Clone, Debug)]
 pub struct Options {
@@ -22,6 +24,90 @@ pub struct Options {
 
     // 执行数据文件 merge 的阈值
     pub data_file_merge_ratio: f32,
+
+    /// value 的压缩算法，只对新写入的数据生效，merge 时会按照当前的设置重新压缩
+    pub compression: Compression,
+
+    /// 数据加密的算法和密钥，覆盖数据文件、hint 文件、merge 完成文件以及事务序列号文件
+    /// B+ 树索引文件中的 key 不会加密
+    pub encryption: Encryption,
+
+    /// 后台自动 merge 的配置，None 表示不开启
+    pub auto_merge: Option<AutoMergeOptions>,
+
+    /// merge 读写数据的速度限制，单位为字节每秒，读取和写入的字节数一起计算，None 表示不限制
+    pub merge_rate_limit: Option<u64>,
+}
+
+/// 后台自动 merge 的配置
+/// 后台线程定期检查可以回收的空间比例，达到 data_file_merge_ratio 并且在时间窗口内时执行 merge
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutoMergeOptions {
+    /// 检查是否需要 merge 的时间间隔
+    pub check_interval: Duration,
+
+    /// 允许 merge 的时间窗口，为一天中 [start, end) 的小时数（UTC），start 大于 end 时跨越零点
+    /// None 表示任何时间都可以 merge
+    pub window: Option<(u8, u8)>,
+}
+
+impl Default for AutoMergeOptions {
+    fn default() -> Self {
+        Self {
+            check_interval: Duration::from_secs(60),
+            window: None,
+        }
+    }
+}
+
+impl AutoMergeOptions {
+    /// 给定的时间是否在允许 merge 的时间窗口内，secs 为 UNIX 时间戳
+    pub(crate) fn in_window(&self, secs: u64) -> bool {
+        let (start, end) = match self.window {
+            Some(window) => window,
+            None => return true,
+        };
+        let hour = ((secs % 86400) / 3600) as u8;
+        match start <= end {
+            true => start <= hour && hour < end,
+            false => hour >= start || hour < end,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    /// 不压缩
+    None,
+
+    /// lz4 压缩，速度更快
+    Lz4,
+
+    /// zstd 压缩，压缩率更高
+    Zstd,
+}
+
+#[derive(Clone, PartialEq)]
+pub enum Encryption {
+    /// 不加密
+    None,
+
+    /// AES-256-GCM 加密，使用 32 字节的密钥
+    Aes256Gcm([u8; 32]),
+
+    /// ChaCha20-Poly1305 加密，使用 32 字节的密钥
+    ChaCha20Poly1305([u8; 32]),
+}
+
+/// 不输出密钥
+impl std::fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Encryption::None => write!(f, "None"),
+            Encryption::Aes256Gcm(_) => write!(f, "Aes256Gcm"),
+            Encryption::ChaCha20Poly1305(_) => write!(f, "ChaCha20Poly1305"),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -46,14 +132,55 @@ impl Default for Options {
             bytes_per_sync: 0,
             mmap_at_startup: true,
             data_file_merge_ratio: 0.5,
+            compression: Compression::None,
+            encryption: Encryption::None,
+            auto_merge: None,
+            merge_rate_limit: None,
         }
     }
 }
 
-#[derive(Default)]
+#[derive(Clone)]
 pub struct IteratorOptions {
     pub prefix: Vec<u8>,
     pub reverse: bool,
+    /// 遍历范围的下界
+    pub lower_bound: Bound<Vec<u8>>,
+    /// 遍历范围的上界
+    pub upper_bound: Bound<Vec<u8>>,
+    /// 最多返回多少条数据，从 rewind 或 seek 开始计数，过期的 key 不计入，None 表示不限制
+    pub limit: Option<usize>,
+    /// 迭代器返回的内容，只需要 key 或者 value 长度时不会读取数据文件
+    pub mode: IteratorMode,
+}
+
+impl Default for IteratorOptions {
+    fn default() -> Self {
+        Self {
+            prefix: Default::default(),
+            reverse: false,
+            lower_bound: Bound::Unbounded,
+            upper_bound: Bound::Unbounded,
+            limit: None,
+            mode: IteratorMode::KeyValue,
+        }
+    }
+}
+
+impl IteratorOptions {
+    /// 判断 key 是否在上下界的范围之内
+    pub(crate) fn in_bounds(&self, key: &[u8]) -> bool {
+        above_lower_bound(key, &self.lower_bound) && below_upper_bound(key, &self.upper_bound)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum IteratorMode {
+    /// 返回 key 和 value
+    KeyValue,
+
+    /// 只返回 key，value 为空
+    KeyOnly,
 }
 
 /// 批量写数据配置项
@@ -0,0 +1,462 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+use prost::decode_length_delimiter;
+
+use crate::{
+    data::log_record::LogRecordType,
+    data::{
+        cipher::Cipher,
+        data_file::{DataFile, DATA_FILE_NAME_SUFFIX},
+    },
+    db::{lock_data_dir, Engine},
+    error::{Errors, Result},
+    index::{self, Indexer},
+    merge::merged_data_file_sources,
+    options::{IOType, IndexType, IteratorOptions, Options},
+};
+
+/// 修复的结果统计
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RepairReport {
+    /// 扫描的数据文件数量
+    pub data_files: usize,
+    /// 拷贝到新目录中的有效记录数量
+    pub records: usize,
+    /// 跳过的连续损坏区域的数量，损坏的数据无法解析，一段区域中可能包含多条记录
+    pub corrupted_regions: usize,
+    /// 丢失的字节数
+    pub lost_bytes: u64,
+    /// 包含损坏数据的文件 id
+    pub corrupted_files: Vec<u32>,
+    /// 修复之后丢失的 key 的数量，包括损坏区域中能够解析出 header 的写入记录的 key
+    /// 以及修复之前 B+ 树索引中有的 key，在修复之后的数据中不存在
+    pub lost_keys: usize,
+}
+
+/// 离线修复 options.dir_path 中的数据，将有效的记录拷贝到 dest_dir 中
+/// 遇到损坏的数据时逐字节向后查找，直到找到下一条 crc 校验通过的记录
+/// hint 文件和 B+ 树索引文件中的位置信息会失效，不会拷贝，索引根据新的数据文件重新构建
+/// 数据目录旁边有已经完成的 merge 目录时，从 merge 目录中读取被替换的数据文件，不会修改原来的数据目录
+pub fn repair(options: Options, dest_dir: PathBuf) -> Result<RepairReport> {
+    let dir_path = options.dir_path.clone();
+    if !dir_path.is_dir() {
+        return Err(Errors::FailedReadDatabaseDir);
+    }
+
+    // 修复期间数据目录不能被其他实例使用
+    let lock_file = lock_data_dir(&dir_path, true)?;
+
+    // 目标目录必须为空
+    if dest_dir.is_dir() {
+        match fs::read_dir(&dest_dir) {
+            Ok(mut entries) => {
+                if entries.next().is_some() {
+                    return Err(Errors::RepairDirNotEmpty);
+                }
+            }
+            Err(_) => return Err(Errors::FailedReadDatabaseDir),
+        }
+    } else if let Err(e) = fs::create_dir_all(&dest_dir) {
+        warn!("create repair directory err: {}", e);
+        return Err(Errors::FailedCreateDatabaseDir);
+    }
+
+    // 已经完成的 merge 替换的数据文件从 merge 目录中读取
+    let cipher = Cipher::new(&options.encryption);
+    let mut report = RepairReport::default();
+    let mut damaged_keys = HashSet::new();
+    for (file_id, src_dir) in merged_data_file_sources(&dir_path, cipher.clone())? {
+        repair_data_file(
+            file_id,
+            &src_dir,
+            &dest_dir,
+            cipher.clone(),
+            &mut report,
+            &mut damaged_keys,
+        )?;
+    }
+
+    // 修复之前 B+ 树索引中的 key
+    if options.index_type == IndexType::BPlusTree {
+        collect_bptree_keys(&dir_path, &mut damaged_keys);
+    }
+
+    // 打开新的目录重新构建索引
+    let mut dest_options = options.clone();
+    dest_options.dir_path = dest_dir.clone();
+    dest_options.mmap_at_startup = false;
+    dest_options.auto_merge = None;
+    if options.index_type == IndexType::BPlusTree {
+        dest_options.index_type = IndexType::BTree;
+    }
+    let engine = Engine::open(dest_options)?;
+    report.lost_keys = damaged_keys
+        .into_iter()
+        .filter(|(keyspace, key)| {
+            engine
+                .with_existing_index(keyspace, |index| index.get(key.clone()))
+                .flatten()
+                .is_none()
+        })
+        .count();
+    if options.index_type == IndexType::BPlusTree {
+        rebuild_bptree_index(&engine, dest_dir)?;
+    }
+    engine.close()?;
+    if let Some(lock_file) = lock_file {
+        lock_file.unlock().unwrap();
+    }
+
+    Ok(report)
+}
+
+/// 扫描一个数据文件，将其中的有效记录写入到目标目录中相同 id 的文件
+/// 损坏区域中能够解析出 header 的写入记录的 key 记录到 damaged_keys 中
+fn repair_data_file(
+    file_id: u32,
+    dir_path: &Path,
+    dest_dir: &Path,
+    cipher: Option<Cipher>,
+    report: &mut RepairReport,
+    damaged_keys: &mut HashSet<(Vec<u8>, Vec<u8>)>,
+) -> Result<()> {
+    let (src_file, _) = open_data_file(dir_path, file_id, cipher.clone())?;
+    let dest_file = DataFile::new(dest_dir.to_path_buf(), file_id, IOType::StandardFIO, cipher)?;
+    report.data_files += 1;
+
+    let mut corruptions = Vec::new();
+    scan_data_file(
+        &src_file,
+        src_file.file_size(),
+        |offset, size| {
+            dest_file.write(&src_file.read_raw(offset, size)?)?;
+            report.records += 1;
+            Ok(())
+        },
+        |offset, size| corruptions.push((offset, size)),
+    )?;
+    for (offset, lost_bytes) in corruptions {
+        record_corruption(file_id, lost_bytes, report);
+        collect_damaged_keys(&src_file, offset, offset + lost_bytes, damaged_keys);
+    }
+
+    dest_file.sync()
+}
+
+/// 打开需要扫描的数据文件，头部损坏或者和文件 id 不一致时按照没有头部的格式打开
+/// 返回的 bool 表示头部是否有效
+pub(crate) fn open_data_file(
+    dir_path: &Path,
+    file_id: u32,
+    cipher: Option<Cipher>,
+) -> Result<(DataFile, bool)> {
+    match DataFile::new(
+        dir_path.to_path_buf(),
+        file_id,
+        IOType::StandardFIO,
+        cipher.clone(),
+    ) {
+        Ok(file) => match file.header() {
+            Some(header) if header.file_id != file_id => Ok((file, false)),
+            _ => Ok((file, true)),
+        },
+        Err(Errors::InvalidDataFileHeader) => Ok((
+            DataFile::new_headerless(dir_path.to_path_buf(), file_id, cipher)?,
+            false,
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+/// 逐条扫描数据文件中 file_size 之前的记录，on_record 和 on_corruption 的参数为 offset 和长度
+/// 遇到损坏的数据时逐字节向后查找，直到找到下一条 crc 校验通过的记录
+pub(crate) fn scan_data_file<R, C>(
+    data_file: &DataFile,
+    file_size: u64,
+    mut on_record: R,
+    mut on_corruption: C,
+) -> Result<()>
+where
+    R: FnMut(u64, u64) -> Result<()>,
+    C: FnMut(u64, u64),
+{
+    let mut offset = data_file.first_record_offset();
+    let mut corrupted_from = None;
+    while offset < file_size {
+        let size = match data_file.read_log_record(offset) {
+            Ok(result) => result.size,
+            // 密钥错误时所有的记录都无法读取，直接返回
+            Err(Errors::InvalidEncryptionKey) => return Err(Errors::InvalidEncryptionKey),
+            Err(Errors::DecompressFailed) => return Err(Errors::DecompressFailed),
+            Err(_) => {
+                if corrupted_from.is_none() {
+                    corrupted_from = Some(offset);
+                }
+                offset += 1;
+                continue;
+            }
+        };
+
+        if let Some(start) = corrupted_from.take() {
+            on_corruption(start, offset - start);
+        }
+        on_record(offset, size)?;
+        offset += size;
+    }
+    if let Some(start) = corrupted_from {
+        on_corruption(start, file_size - start);
+    }
+    Ok(())
+}
+
+fn record_corruption(file_id: u32, lost_bytes: u64, report: &mut RepairReport) {
+    warn!(
+        "skip {} bytes of corrupted data in data file {}",
+        lost_bytes, file_id
+    );
+    report.corrupted_regions += 1;
+    report.lost_bytes += lost_bytes;
+    if !report.corrupted_files.contains(&file_id) {
+        report.corrupted_files.push(file_id);
+    }
+}
+
+/// 从损坏区域的起点开始逐条解析记录的 header，直到无法解析或者超出区域
+fn collect_damaged_keys(
+    data_file: &DataFile,
+    mut offset: u64,
+    end: u64,
+    damaged_keys: &mut HashSet<(Vec<u8>, Vec<u8>)>,
+) {
+    while let Some(result) = data_file.read_damaged_record(offset, end) {
+        // key 的前面是事务序列号
+        let mut key = result.record.key.as_slice();
+        if result.record.rec_type == LogRecordType::Normal
+            && decode_length_delimiter(&mut key).is_ok()
+        {
+            damaged_keys.insert((result.record.keyspace.clone(), key.to_vec()));
+        }
+        offset += result.size;
+    }
+}
+
+/// 读取数据目录中已经存在的 B+ 树索引的全部 key，不创建任何文件
+fn collect_bptree_keys(dir_path: &Path, keys: &mut HashSet<(Vec<u8>, Vec<u8>)>) {
+    let mut names = vec![String::new()];
+    names.extend(index::bptree::list_keyspaces(dir_path.to_path_buf()));
+    for name in names {
+        if let Some(bptree) = index::bptree::BPlusTree::open_existing(dir_path.to_path_buf(), &name)
+        {
+            let mut index_iter = bptree.iterator(IteratorOptions::default());
+            while let Some((key, _)) = index_iter.next() {
+                keys.insert((name.as_bytes().to_vec(), key.clone()));
+            }
+        }
+    }
+}
+
+/// 根据内存索引重新构建 B+ 树索引文件
+fn rebuild_bptree_index(engine: &Engine, dest_dir: PathBuf) -> Result<()> {
+    let bptree = index::new_indexer(IndexType::BPlusTree, dest_dir.clone());
+    copy_index(engine.index.as_ref(), bptree.as_ref());
+
+    for (name, keyspace_index) in engine.keyspaces.read().iter() {
+        let bptree = index::new_keyspace_indexer(IndexType::BPlusTree, dest_dir.clone(), name);
+        copy_index(keyspace_index.as_ref(), bptree.as_ref());
+    }
+    Ok(())
+}
+
+fn copy_index(src: &dyn index::Indexer, dest: &dyn index::Indexer) {
+    let mut index_iter = src.iterator(IteratorOptions::default());
+    while let Some((key, pos)) = index_iter.next() {
+        dest.put(key.clone(), *pos);
+    }
+}
+
+/// 获取目录中所有数据文件的 id，从小到大排序
+pub(crate) fn list_data_file_ids(dir_path: &Path) -> Result<Vec<u32>> {
+    let dir = match fs::read_dir(dir_path) {
+        Ok(dir) => dir,
+        Err(_) => return Err(Errors::FailedReadDatabaseDir),
+    };
+
+    let mut file_ids = Vec::new();
+    for entry in dir {
+        let entry = entry.map_err(|_| Errors::DirEntryError)?;
+        let file_os_str = entry.file_name();
+        let file_name = file_os_str.to_str().ok_or(Errors::OsStringInvalidUTF8)?;
+        if let Some(name) = file_name.strip_suffix(DATA_FILE_NAME_SUFFIX) {
+            match name.parse::<u32>() {
+                Ok(fid) => file_ids.push(fid),
+                Err(_) => return Err(Errors::DataDirtoryCorrupted),
+            }
+        }
+    }
+    file_ids.sort();
+    Ok(file_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::{
+        data::{
+            data_file::get_data_file_name,
+            log_record::{LogRecord, LogRecordType},
+        },
+        merge::MERGE_FILES_KEY,
+        util::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    fn test_repair(dir: &str, index_type: IndexType) {
+        let opts = Options {
+            dir_path: PathBuf::from(dir),
+            data_file_size: 64 * 1024,
+            index_type,
+            mmap_at_startup: false,
+            ..Default::default()
+        };
+        let dest_dir = PathBuf::from(format!("{}-repaired", dir));
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..2000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        let users = engine.keyspace("users").expect("failed to open keyspace");
+        assert!(users.put(get_test_key(1), Bytes::from("user")).is_ok());
+        std::mem::drop(users);
+        std::mem::drop(engine);
+
+        // 损坏一个旧的数据文件中间一条记录的 value
+        let file0 = get_data_file_name(opts.dir_path.clone(), 0);
+        let mut content = std::fs::read(&file0).unwrap();
+        let key = get_test_key(9);
+        let key_at = content.windows(key.len()).position(|w| w == key).unwrap();
+        for b in content[key_at + key.len() + 10..key_at + key.len() + 20].iter_mut() {
+            *b = 0xFF;
+        }
+        std::fs::write(&file0, &content).unwrap();
+        if opts.index_type != IndexType::BPlusTree {
+            assert_eq!(
+                Errors::InvalidLogRecordCrc,
+                Engine::open(opts.clone()).err().unwrap()
+            );
+        }
+
+        let report = repair(opts.clone(), dest_dir.clone()).expect("failed to repair");
+        assert_eq!(vec![0], report.corrupted_files);
+        assert_eq!(1, report.corrupted_regions);
+        assert!(report.lost_bytes >= 10);
+        assert_eq!(2000, report.records);
+        assert_eq!(1, report.lost_keys);
+
+        let repaired_opts = Options {
+            dir_path: dest_dir.clone(),
+            ..opts.clone()
+        };
+        let engine2 = Engine::open(repaired_opts).expect("failed to open engine");
+        assert_eq!(1999, engine2.list_keys().unwrap().len());
+        assert_eq!(Errors::KeyNotFound, engine2.get(key).err().unwrap());
+        assert_eq!(
+            get_test_value(1999),
+            engine2.get(get_test_key(1999)).unwrap()
+        );
+        let users = engine2.keyspace("users").expect("failed to open keyspace");
+        assert_eq!(Bytes::from("user"), users.get(get_test_key(1)).unwrap());
+
+        // 目标目录不为空
+        assert_eq!(
+            Errors::RepairDirNotEmpty,
+            repair(opts.clone(), dest_dir.clone()).err().unwrap()
+        );
+
+        // 删除测试的文件夹
+        std::mem::drop(users);
+        std::mem::drop(engine2);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+        std::fs::remove_dir_all(dest_dir).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_repair_btree() {
+        test_repair("/tmp/bitcask-rs-repair-btree", IndexType::BTree);
+    }
+
+    #[test]
+    fn test_repair_bptree() {
+        test_repair("/tmp/bitcask-rs-repair-bptree", IndexType::BPlusTree);
+    }
+
+    #[test]
+    fn test_repair_pending_merge() {
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-repair-merge"),
+            data_file_size: 64 * 1024,
+            mmap_at_startup: false,
+            ..Default::default()
+        };
+        let dest_dir = PathBuf::from("/tmp/bitcask-rs-repair-merge-repaired");
+        let merge_path = PathBuf::from("/tmp/bitcask-rs-repair-merge-merge");
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..2000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        std::mem::drop(engine);
+
+        // 已经完成但还没有应用的 merge，其中的文件 0 是完整的
+        let file0 = get_data_file_name(opts.dir_path.clone(), 0);
+        std::fs::create_dir_all(&merge_path).unwrap();
+        std::fs::copy(&file0, get_data_file_name(merge_path.clone(), 0)).unwrap();
+        let merge_fin_file =
+            DataFile::new_merge_fin_file(merge_path.clone(), None).expect("failed to open file");
+        let record = LogRecord {
+            key: MERGE_FILES_KEY.to_vec(),
+            value: "0".as_bytes().to_vec(),
+            rec_type: LogRecordType::Normal,
+            expire: 0,
+            keyspace: Default::default(),
+        };
+        assert!(merge_fin_file.write(&record.encode()).is_ok());
+        assert!(merge_fin_file.sync().is_ok());
+        std::mem::drop(merge_fin_file);
+
+        // 数据目录中的文件 0 已经损坏
+        let mut content = std::fs::read(&file0).unwrap();
+        for b in content[1000..1010].iter_mut() {
+            *b = 0xFF;
+        }
+        std::fs::write(&file0, &content).unwrap();
+
+        let report = repair(opts.clone(), dest_dir.clone()).expect("failed to repair");
+        assert!(report.corrupted_files.is_empty());
+        assert_eq!(0, report.corrupted_regions);
+        assert_eq!(2000, report.records);
+        assert_eq!(0, report.lost_keys);
+
+        // 原来的数据目录和 merge 目录不会被修改
+        assert!(merge_path.is_dir());
+        assert_eq!(content, std::fs::read(&file0).unwrap());
+        let repaired_opts = Options {
+            dir_path: dest_dir.clone(),
+            ..opts.clone()
+        };
+        let engine = Engine::open(repaired_opts).expect("failed to open engine");
+        assert_eq!(2000, engine.list_keys().unwrap().len());
+        std::mem::drop(engine);
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+        std::fs::remove_dir_all(merge_path).expect("failed to remove path");
+        std::fs::remove_dir_all(dest_dir).expect("failed to remove path");
+    }
+}
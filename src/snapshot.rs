@@ -0,0 +1,487 @@
+use std::{
+    collections::BTreeMap,
+    ops::Bound,
+    sync::{atomic::Ordering, Arc},
+};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use crate::{
+    data::log_record::{now_nanos, LogRecordPos},
+    db::Engine,
+    error::{Errors, Result},
+    index::{
+        max_lower_bound, min_upper_bound, prefix_upper_bound, range_is_empty, IndexIterator,
+        Indexer,
+    },
+    iterator::{IndexSource, Iterator},
+    options::IteratorOptions,
+};
+
+/// 只读快照，固定在创建时的事务序列号上，之后的写入不会影响快照中读取到的数据
+/// 快照不拷贝索引，写入修改索引之前会把 key 原来的位置保存到存活的快照中
+pub struct Snapshot<'a> {
+    /// 快照创建之后被修改过的 key 在创建快照时的位置
+    versions: Arc<SnapshotVersions>,
+    engine: &'a Engine,
+    /// 创建快照时的事务序列号
+    seq_no: usize,
+    /// 创建快照的时间点，用于判断数据是否过期
+    read_time: u64,
+    /// 创建快照时 merge 替换数据文件的次数
+    generation: usize,
+}
+
+/// 快照创建之后被修改过的 key 在创建快照时的位置，None 表示创建快照时 key 不存在
+#[derive(Default)]
+pub(crate) struct SnapshotVersions {
+    positions: Mutex<BTreeMap<Vec<u8>, Option<LogRecordPos>>>,
+}
+
+impl SnapshotVersions {
+    /// 查找 key 在创建快照时的位置，外层的 None 表示快照创建之后 key 没有被修改过
+    fn get(&self, key: &[u8]) -> Option<Option<LogRecordPos>> {
+        self.positions.lock().get(key).copied()
+    }
+
+    /// 按照遍历的顺序，返回范围内第一个被修改过的 key
+    fn first_in(
+        &self,
+        lower: &Bound<Vec<u8>>,
+        upper: &Bound<Vec<u8>>,
+        reverse: bool,
+    ) -> Option<(Vec<u8>, Option<LogRecordPos>)> {
+        if range_is_empty(lower, upper) {
+            return None;
+        }
+        let positions = self.positions.lock();
+        let mut range = positions.range((lower.clone(), upper.clone()));
+        let item = match reverse {
+            false => range.next(),
+            true => range.next_back(),
+        };
+        item.map(|(key, pos)| (key.clone(), *pos))
+    }
+}
+
+impl Engine {
+    /// 创建只读快照，只在登记快照时短暂持有写锁，不会拷贝索引
+    /// 快照存活期间，每个 key 第一次被修改时会在快照中保存原来的位置，占用的内存和修改的 key 的数量成正比
+    pub fn snapshot(&self) -> Snapshot<'_> {
+        // 持有写锁，保证不会读取到提交了一半的事务
+        let _lock = self.batch_commit_lock.lock();
+
+        let versions = Arc::new(SnapshotVersions::default());
+        self.snapshots.write().push(versions.clone());
+
+        // 登记快照，merge 替换掉的旧数据文件在快照释放之前不会关闭
+        let generation = self.merge_generation();
+        *self
+            .snapshot_generations
+            .lock()
+            .entry(generation)
+            .or_insert(0) += 1;
+
+        Snapshot {
+            versions,
+            engine: self,
+            seq_no: self.seq_no.load(Ordering::SeqCst),
+            read_time: now_nanos(),
+            generation,
+        }
+    }
+
+    /// 修改默认 keyspace 的索引之前调用，调用方需要持有写锁
+    /// 把 key 在索引中当前的位置保存到存活的快照中，每个快照只保存 key 第一次被修改之前的位置
+    pub(crate) fn save_snapshot_versions<'k>(
+        &self,
+        keyspace: &[u8],
+        keys: impl IntoIterator<Item = &'k [u8]>,
+    ) {
+        if !keyspace.is_empty() {
+            return;
+        }
+        let snapshots = self.snapshots.read();
+        if snapshots.is_empty() {
+            return;
+        }
+        for key in keys {
+            let mut current = None;
+            for versions in snapshots.iter() {
+                let mut positions = versions.positions.lock();
+                if !positions.contains_key(key) {
+                    let pos = *current.get_or_insert_with(|| self.index.get(key.to_vec()));
+                    positions.insert(key.to_vec(), pos);
+                }
+            }
+        }
+    }
+}
+
+impl Snapshot<'_> {
+    /// 快照固定的事务序列号
+    pub fn seq_no(&self) -> usize {
+        self.seq_no
+    }
+
+    /// 根据 key 获取快照中对应的数据
+    pub fn get(&self, key: Bytes) -> Result<Bytes> {
+        // 判断 key 的有效性
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        // 先读取索引再查找修改记录，索引被修改之前原来的位置已经保存到了快照中
+        let pos = self.engine.index.get(key.to_vec());
+        let pos = self.versions.get(&key).unwrap_or(pos);
+        self.engine
+            .get_value_at(pos.as_ref(), self.read_time, Some(self.generation))
+    }
+
+    /// 获取快照上的迭代器
+    pub fn iter(&self, options: IteratorOptions) -> Iterator<'_> {
+        let index = SnapshotIndex {
+            index: self.engine.index.as_ref(),
+            versions: self.versions.clone(),
+        };
+        Iterator::new(
+            &index,
+            options,
+            self.engine,
+            self.read_time,
+            IndexSource::Snapshot(self.generation),
+        )
+    }
+
+    /// 返回快照中所有的 key
+    pub fn list_keys(&self) -> Result<Vec<Bytes>> {
+        let options = IteratorOptions {
+            mode: crate::options::IteratorMode::KeyOnly,
+            ..Default::default()
+        };
+        self.iter(options)
+            .map(|item| item.map(|(key, _)| key))
+            .collect()
+    }
+
+    /// 对快照当中的所有数据执行函数操作，函数返回 false 时终止
+    pub fn fold<F>(&self, f: F) -> Result<()>
+    where
+        F: Fn(Bytes, Bytes) -> bool,
+    {
+        for item in self.iter(IteratorOptions::default()) {
+            let (key, value) = item?;
+            if !f(key, value) {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Snapshot<'_> {
+    fn drop(&mut self) {
+        self.engine
+            .snapshots
+            .write()
+            .retain(|versions| !Arc::ptr_eq(versions, &self.versions));
+        self.engine.release_snapshot(self.generation);
+    }
+}
+
+/// 快照看到的索引，当前的索引加上快照创建之后被修改过的 key 原来的位置
+struct SnapshotIndex<'a> {
+    index: &'a dyn Indexer,
+    versions: Arc<SnapshotVersions>,
+}
+
+impl Indexer for SnapshotIndex<'_> {
+    fn put(&self, _key: Vec<u8>, _pos: LogRecordPos) -> Option<LogRecordPos> {
+        unreachable!("snapshot index is read only")
+    }
+
+    fn get(&self, key: Vec<u8>) -> Option<LogRecordPos> {
+        let pos = self.index.get(key.clone());
+        self.versions.get(&key).unwrap_or(pos)
+    }
+
+    fn delete(&self, _key: Vec<u8>) -> Option<LogRecordPos> {
+        unreachable!("snapshot index is read only")
+    }
+
+    fn iterator(&self, options: IteratorOptions) -> Box<dyn IndexIterator> {
+        let lower = max_lower_bound(
+            options.lower_bound.clone(),
+            Bound::Included(options.prefix.clone()),
+        );
+        let upper = min_upper_bound(
+            options.upper_bound.clone(),
+            prefix_upper_bound(&options.prefix),
+        );
+        let mut iter = SnapshotIndexIterator {
+            index_iter: self.index.iterator(options.clone()),
+            versions: self.versions.clone(),
+            reverse: options.reverse,
+            lower,
+            upper,
+            cursor: Bound::Unbounded,
+            pending: None,
+            current: None,
+        };
+        iter.rewind();
+        Box::new(iter)
+    }
+}
+
+/// 快照上的索引迭代器，按照 key 的顺序合并当前的索引和快照中保存的修改之前的位置
+/// 索引中的数据先于修改记录读取，读取之后被修改的 key 在修改记录中也是原来的位置
+struct SnapshotIndexIterator {
+    index_iter: Box<dyn IndexIterator>,
+    versions: Arc<SnapshotVersions>,
+    reverse: bool,
+    /// 上下界和前缀共同确定的遍历范围
+    lower: Bound<Vec<u8>>,
+    upper: Bound<Vec<u8>>,
+    /// 还没有处理的 key 的起点，正向遍历时是下界，反向遍历时是上界
+    cursor: Bound<Vec<u8>>,
+    /// 从当前的索引中预读的数据
+    pending: Option<(Vec<u8>, LogRecordPos)>,
+    /// 上一次返回的数据
+    current: Option<(Vec<u8>, LogRecordPos)>,
+}
+
+impl IndexIterator for SnapshotIndexIterator {
+    fn rewind(&mut self) {
+        self.index_iter.rewind();
+        self.cursor = match self.reverse {
+            false => self.lower.clone(),
+            true => self.upper.clone(),
+        };
+        self.pending = None;
+    }
+
+    fn seek(&mut self, key: Vec<u8>) {
+        self.index_iter.seek(key.clone());
+        self.cursor = match self.reverse {
+            false => max_lower_bound(self.lower.clone(), Bound::Included(key)),
+            true => min_upper_bound(self.upper.clone(), Bound::Included(key)),
+        };
+        self.pending = None;
+    }
+
+    fn next(&mut self) -> Option<(&Vec<u8>, &LogRecordPos)> {
+        loop {
+            if self.pending.is_none() {
+                self.pending = self.index_iter.next().map(|(key, pos)| (key.clone(), *pos));
+            }
+            let saved = match self.reverse {
+                false => self.versions.first_in(&self.cursor, &self.upper, false),
+                true => self.versions.first_in(&self.lower, &self.cursor, true),
+            };
+
+            // 按照遍历的顺序取较小的 key，相同的 key 以修改之前的位置为准
+            let (key, pos) = match (self.pending.take(), saved) {
+                (None, None) => return None,
+                (Some((key, pos)), None) => (key, Some(pos)),
+                (None, Some(saved)) => saved,
+                (Some(live), Some(saved)) => {
+                    let order = match self.reverse {
+                        false => live.0.cmp(&saved.0),
+                        true => saved.0.cmp(&live.0),
+                    };
+                    match order {
+                        std::cmp::Ordering::Less => (live.0, Some(live.1)),
+                        std::cmp::Ordering::Equal => saved,
+                        std::cmp::Ordering::Greater => {
+                            self.pending = Some(live);
+                            saved
+                        }
+                    }
+                }
+            };
+            self.cursor = Bound::Excluded(key.clone());
+
+            // 创建快照时不存在的 key
+            if let Some(pos) = pos {
+                let item = self.current.insert((key, pos));
+                return Some((&item.0, &item.1));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Mutex};
+
+    use crate::{
+        options::{Options, WriteBatchOptions},
+        util::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_snapshot_get() {
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-snapshot-get"),
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        for i in 0..100 {
+            let put_res = engine.put(get_test_key(i), get_test_value(i));
+            assert!(put_res.is_ok());
+        }
+        let snapshot = engine.snapshot();
+
+        // 创建快照之后的写入对快照不可见
+        for i in 0..50 {
+            let put_res = engine.put(get_test_key(i), Bytes::from("new value"));
+            assert!(put_res.is_ok());
+        }
+        for i in 50..100 {
+            let del_res = engine.delete(get_test_key(i));
+            assert!(del_res.is_ok());
+        }
+        let put_res = engine.put(get_test_key(200), get_test_value(200));
+        assert!(put_res.is_ok());
+
+        for i in 0..100 {
+            assert_eq!(get_test_value(i), snapshot.get(get_test_key(i)).unwrap());
+        }
+        assert_eq!(
+            Errors::KeyNotFound,
+            snapshot.get(get_test_key(200)).err().unwrap()
+        );
+        assert_eq!(100, snapshot.list_keys().unwrap().len());
+
+        // 引擎中读取到的是最新的数据
+        assert_eq!(
+            Bytes::from("new value"),
+            engine.get(get_test_key(0)).unwrap()
+        );
+        assert_eq!(51, engine.list_keys().unwrap().len());
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_snapshot_fold() {
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-snapshot-fold"),
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        for i in 0..10 {
+            let put_res = wb.put(get_test_key(i), get_test_value(i));
+            assert!(put_res.is_ok());
+        }
+        assert!(wb.commit().is_ok());
+
+        let snapshot = engine.snapshot();
+        let seq_no = snapshot.seq_no();
+
+        // 快照之后提交新的事务
+        for i in 0..10 {
+            let put_res = wb.put(get_test_key(i), Bytes::from("new value"));
+            assert!(put_res.is_ok());
+        }
+        assert!(wb.commit().is_ok());
+        assert_eq!(seq_no, snapshot.seq_no());
+
+        let keys = Mutex::new(Vec::new());
+        snapshot
+            .fold(|key, value| {
+                assert_ne!(Bytes::from("new value"), value);
+                keys.lock().unwrap().push(key);
+                true
+            })
+            .unwrap();
+        assert_eq!(10, keys.lock().unwrap().len());
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_snapshot_iter() {
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-snapshot-iter"),
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..10 {
+            let put_res = engine.put(get_test_key(i * 2), get_test_value(i));
+            assert!(put_res.is_ok());
+        }
+        let snapshot = engine.snapshot();
+        assert!(snapshot.versions.positions.lock().is_empty());
+
+        // 创建快照之后修改、删除和新增的 key
+        assert!(engine
+            .put(get_test_key(0), Bytes::from("new value"))
+            .is_ok());
+        assert!(engine.delete(get_test_key(2)).is_ok());
+        assert!(engine.put(get_test_key(3), get_test_value(3)).is_ok());
+        assert!(engine
+            .delete_range(get_test_key(10), get_test_key(14))
+            .is_ok());
+        assert!(engine.put(get_test_key(19), get_test_value(19)).is_ok());
+        assert_eq!(6, snapshot.versions.positions.lock().len());
+
+        let expected: Vec<Bytes> = (0..10).map(|i| get_test_key(i * 2)).collect();
+        let collect = |options: IteratorOptions| {
+            let mut items = Vec::new();
+            for item in snapshot.iter(options) {
+                items.push(item.unwrap());
+            }
+            items
+        };
+        let items = collect(IteratorOptions::default());
+        assert_eq!(
+            expected,
+            items.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>()
+        );
+        for (i, (_, value)) in items.iter().enumerate() {
+            assert_eq!(get_test_value(i), value);
+        }
+
+        // 反向遍历
+        let items = collect(IteratorOptions {
+            reverse: true,
+            ..Default::default()
+        });
+        let mut reversed = expected.clone();
+        reversed.reverse();
+        assert_eq!(
+            reversed,
+            items.iter().map(|(key, _)| key.clone()).collect::<Vec<_>>()
+        );
+
+        // 范围和 seek
+        let items = collect(IteratorOptions {
+            lower_bound: Bound::Included(get_test_key(1).to_vec()),
+            upper_bound: Bound::Excluded(get_test_key(12).to_vec()),
+            ..Default::default()
+        });
+        assert_eq!(5, items.len());
+        let mut iter = snapshot.iter(IteratorOptions::default());
+        iter.seek(get_test_key(11).to_vec());
+        assert_eq!(get_test_key(12), iter.next().unwrap().unwrap().0);
+
+        // 快照释放之后不再保存修改之前的位置
+        std::mem::drop(iter);
+        std::mem::drop(snapshot);
+        assert!(engine.snapshots.read().is_empty());
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+}
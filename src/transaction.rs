@@ -0,0 +1,422 @@
+use bytes::Bytes;
+use parking_lot::Mutex;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
+
+use crate::{
+    data::log_record::{LogRecord, LogRecordType},
+    db::Engine,
+    error::{Errors, Result},
+    options::WriteBatchOptions,
+};
+
+/// 乐观事务，提交时检查读写过的 key 是否被其他的写入修改过
+pub struct Transaction<'a> {
+    /// 暂存用户写入的数据
+    pending_writes: Arc<Mutex<HashMap<Vec<u8>, LogRecord>>>,
+    /// 事务读写过的 key
+    accessed_keys: Arc<Mutex<HashSet<Vec<u8>>>>,
+    /// 事务开始时的写入版本号，在这之后被修改过的 key 都有更大的版本号
+    start_version: u64,
+    engine: &'a Engine,
+    options: WriteBatchOptions,
+}
+
+/// 记录进行中的事务，以及事务开始之后被修改过的 key
+/// 只依赖写入的先后顺序，和数据在文件中的位置无关，merge 替换数据文件不影响冲突检测
+#[derive(Default)]
+pub(crate) struct TxnTracker {
+    /// 写入版本号，每次写入递增
+    version: u64,
+    /// 进行中的事务开始时的版本号，以及对应的事务数量
+    active: BTreeMap<u64, usize>,
+    /// 有进行中的事务时，每个 key 最近一次被修改的版本号
+    written: HashMap<Vec<u8>, u64>,
+}
+
+impl TxnTracker {
+    /// 开始一个事务，返回当前的版本号
+    fn begin(&mut self) -> u64 {
+        *self.active.entry(self.version).or_insert(0) += 1;
+        self.version
+    }
+
+    /// 结束一个事务，清理不再需要的修改记录
+    fn finish(&mut self, start_version: u64) {
+        if let Some(count) = self.active.get_mut(&start_version) {
+            *count -= 1;
+            if *count == 0 {
+                self.active.remove(&start_version);
+            }
+        }
+        match self.active.keys().next() {
+            Some(oldest) => {
+                let oldest = *oldest;
+                self.written.retain(|_, version| *version > oldest);
+            }
+            None => self.written.clear(),
+        }
+    }
+
+    /// 记录一次写入修改的 key，返回这次写入的版本号
+    fn record<'k>(&mut self, keys: impl IntoIterator<Item = &'k [u8]>) -> u64 {
+        self.version += 1;
+        if !self.active.is_empty() {
+            for key in keys {
+                self.written.insert(key.to_vec(), self.version);
+            }
+        }
+        self.version
+    }
+
+    /// 加载索引时按照数据文件中的顺序为每次写入分配版本号
+    pub(crate) fn next_version(&mut self) -> u64 {
+        self.version += 1;
+        self.version
+    }
+
+    /// 已经分配出去的最大版本号
+    pub(crate) fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// 从 hint 文件或者 B+ 树索引中加载了位置信息之后，之后分配的版本号需要更大
+    pub(crate) fn advance_to(&mut self, version: u64) {
+        self.version = self.version.max(version);
+    }
+
+    /// 判断 key 在 start_version 之后是否被修改过
+    fn is_modified_since(&self, key: &[u8], start_version: u64) -> bool {
+        self.written
+            .get(key)
+            .is_some_and(|version| *version > start_version)
+    }
+}
+
+impl Engine {
+    /// 开启一个乐观事务
+    pub fn begin_transaction(&self, options: WriteBatchOptions) -> Result<Transaction<'_>> {
+        self.check_seq_no_available()?;
+
+        // 持有写锁，保证没有正在进行中的写入
+        let start_version = {
+            let _lock = self.batch_commit_lock.lock();
+            self.txn_tracker.lock().begin()
+        };
+
+        Ok(Transaction {
+            pending_writes: Arc::new(Mutex::new(HashMap::new())),
+            accessed_keys: Arc::new(Mutex::new(HashSet::new())),
+            start_version,
+            engine: self,
+            options,
+        })
+    }
+
+    /// 记录一次写入，返回写入版本号，调用方需要持有写锁
+    /// 默认 keyspace 中被修改的 key 用于检测事务冲突
+    pub(crate) fn record_writes<'k>(
+        &self,
+        keyspace: &[u8],
+        keys: impl IntoIterator<Item = &'k [u8]>,
+    ) -> u64 {
+        let mut txn_tracker = self.txn_tracker.lock();
+        match keyspace.is_empty() {
+            true => txn_tracker.record(keys),
+            false => txn_tracker.next_version(),
+        }
+    }
+}
+
+impl Transaction<'_> {
+    /// 读取数据，优先读取事务中暂存的数据
+    pub fn get(&self, key: Bytes) -> Result<Bytes> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+
+        let pending_writes = self.pending_writes.lock();
+        if let Some(record) = pending_writes.get(&key.to_vec()) {
+            if record.rec_type == LogRecordType::Deleted {
+                return Err(Errors::KeyNotFound);
+            }
+            return Ok(Bytes::from(record.value.clone()));
+        }
+
+        self.track_key(&key);
+        self.engine.get(key)
+    }
+
+    /// 事务中写数据
+    pub fn put(&self, key: Bytes, value: Bytes) -> Result<()> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        self.track_key(&key);
+
+        // 暂存数据
+        let record = LogRecord {
+            key: key.to_vec(),
+            value: value.to_vec(),
+            rec_type: LogRecordType::Normal,
+            expire: 0,
+            keyspace: Default::default(),
+        };
+
+        let mut pending_writes = self.pending_writes.lock();
+        pending_writes.insert(key.to_vec(), record);
+        Ok(())
+    }
+
+    /// 事务中删除数据
+    pub fn delete(&self, key: Bytes) -> Result<()> {
+        if key.is_empty() {
+            return Err(Errors::KeyIsEmpty);
+        }
+        self.track_key(&key);
+
+        let mut pending_writes = self.pending_writes.lock();
+        // 如果数据不存在，只需要删除暂存的数据
+        if self.engine.index.get(key.to_vec()).is_none() {
+            pending_writes.remove(&key.to_vec());
+            return Ok(());
+        }
+
+        // 暂存数据
+        let record = LogRecord {
+            key: key.to_vec(),
+            value: Default::default(),
+            rec_type: LogRecordType::Deleted,
+            expire: 0,
+            keyspace: Default::default(),
+        };
+
+        pending_writes.insert(key.to_vec(), record);
+        Ok(())
+    }
+
+    /// 提交事务，如果读写过的 key 被其他的写入修改过，则返回 TransactionConflict
+    pub fn commit(&self) -> Result<()> {
+        let mut pending_writes = self.pending_writes.lock();
+        if pending_writes.len() > self.options.max_batch_num {
+            return Err(Errors::ExceedMaxBatchNum);
+        }
+
+        // 加锁保证事务提交串行化
+        let _lock = self.engine.batch_commit_lock.lock();
+
+        // 检查读写过的 key 是否被修改过
+        let mut accessed_keys = self.accessed_keys.lock();
+        {
+            let txn_tracker = self.engine.txn_tracker.lock();
+            let conflict = accessed_keys
+                .iter()
+                .any(|key| txn_tracker.is_modified_since(key, self.start_version));
+            if conflict {
+                return Err(Errors::TransactionConflict);
+            }
+        }
+
+        if !pending_writes.is_empty() {
+            self.engine
+                .commit_pending_writes(&pending_writes, self.options.sync_writes)?;
+        }
+
+        // 清空暂存数据
+        pending_writes.clear();
+        accessed_keys.clear();
+
+        Ok(())
+    }
+
+    /// 记录事务访问过的 key
+    fn track_key(&self, key: &Bytes) {
+        self.accessed_keys.lock().insert(key.to_vec());
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        self.engine.txn_tracker.lock().finish(self.start_version);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{
+        options::Options,
+        util::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_transaction_commit() {
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-txn-commit"),
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let put_res = engine.put(get_test_key(1), get_test_value(1));
+        assert!(put_res.is_ok());
+
+        let txn = engine
+            .begin_transaction(WriteBatchOptions::default())
+            .expect("failed to begin transaction");
+        assert_eq!(get_test_value(1), txn.get(get_test_key(1)).unwrap());
+
+        // 读取事务中暂存的数据
+        assert!(txn.put(get_test_key(2), get_test_value(2)).is_ok());
+        assert!(txn.delete(get_test_key(1)).is_ok());
+        assert_eq!(get_test_value(2), txn.get(get_test_key(2)).unwrap());
+        assert_eq!(Errors::KeyNotFound, txn.get(get_test_key(1)).err().unwrap());
+
+        // 提交之前不可见
+        assert_eq!(get_test_value(1), engine.get(get_test_key(1)).unwrap());
+        assert_eq!(
+            Errors::KeyNotFound,
+            engine.get(get_test_key(2)).err().unwrap()
+        );
+
+        assert!(txn.commit().is_ok());
+        assert_eq!(
+            Errors::KeyNotFound,
+            engine.get(get_test_key(1)).err().unwrap()
+        );
+        assert_eq!(get_test_value(2), engine.get(get_test_key(2)).unwrap());
+
+        // 重启之后校验
+        std::mem::drop(txn);
+        std::mem::drop(engine);
+        let engine2 = Engine::open(opts.clone()).expect("failed to open engine");
+        assert_eq!(
+            Errors::KeyNotFound,
+            engine2.get(get_test_key(1)).err().unwrap()
+        );
+        assert_eq!(get_test_value(2), engine2.get(get_test_key(2)).unwrap());
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_transaction_conflict() {
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-txn-conflict"),
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        let put_res = engine.put(get_test_key(1), get_test_value(1));
+        assert!(put_res.is_ok());
+
+        // 读过的 key 被修改
+        let txn1 = engine
+            .begin_transaction(WriteBatchOptions::default())
+            .expect("failed to begin transaction");
+        assert!(txn1.get(get_test_key(1)).is_ok());
+        assert!(txn1.put(get_test_key(2), get_test_value(2)).is_ok());
+        assert!(engine.put(get_test_key(1), Bytes::from("changed")).is_ok());
+        assert_eq!(Errors::TransactionConflict, txn1.commit().err().unwrap());
+        assert_eq!(
+            Errors::KeyNotFound,
+            engine.get(get_test_key(2)).err().unwrap()
+        );
+
+        // 事务开始之后、访问之前被修改
+        let txn2 = engine
+            .begin_transaction(WriteBatchOptions::default())
+            .expect("failed to begin transaction");
+        assert!(engine.put(get_test_key(3), get_test_value(3)).is_ok());
+        assert!(txn2.put(get_test_key(3), get_test_value(33)).is_ok());
+        assert_eq!(Errors::TransactionConflict, txn2.commit().err().unwrap());
+
+        // 事务开始时存在的 key 在访问之前被删除
+        let txn6 = engine
+            .begin_transaction(WriteBatchOptions::default())
+            .expect("failed to begin transaction");
+        assert!(engine.delete(get_test_key(3)).is_ok());
+        assert_eq!(
+            Errors::KeyNotFound,
+            txn6.get(get_test_key(3)).err().unwrap()
+        );
+        assert!(txn6.put(get_test_key(6), get_test_value(6)).is_ok());
+        assert_eq!(Errors::TransactionConflict, txn6.commit().err().unwrap());
+
+        // 被另一个事务修改
+        let txn3 = engine
+            .begin_transaction(WriteBatchOptions::default())
+            .expect("failed to begin transaction");
+        let txn4 = engine
+            .begin_transaction(WriteBatchOptions::default())
+            .expect("failed to begin transaction");
+        assert!(txn3.get(get_test_key(1)).is_ok());
+        assert!(txn4.get(get_test_key(1)).is_ok());
+        assert!(txn3.put(get_test_key(1), Bytes::from("txn3")).is_ok());
+        assert!(txn4.put(get_test_key(1), Bytes::from("txn4")).is_ok());
+        assert!(txn3.commit().is_ok());
+        assert_eq!(Errors::TransactionConflict, txn4.commit().err().unwrap());
+        assert_eq!(Bytes::from("txn3"), engine.get(get_test_key(1)).unwrap());
+
+        // 没有冲突的 key 可以正常提交
+        let txn5 = engine
+            .begin_transaction(WriteBatchOptions::default())
+            .expect("failed to begin transaction");
+        assert!(engine.put(get_test_key(4), get_test_value(4)).is_ok());
+        assert!(txn5.get(get_test_key(1)).is_ok());
+        assert!(txn5.put(get_test_key(5), get_test_value(5)).is_ok());
+        assert!(txn5.commit().is_ok());
+        assert_eq!(get_test_value(5), engine.get(get_test_key(5)).unwrap());
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_transaction_merge() {
+        // merge 替换数据文件之后，冲突检测不受影响
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-txn-merge"),
+            data_file_size: 64 * 1024,
+            data_file_merge_ratio: 0 as f32,
+            mmap_at_startup: false,
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..2000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..1000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i + 1)).is_ok());
+        }
+
+        let txn1 = engine
+            .begin_transaction(WriteBatchOptions::default())
+            .expect("failed to begin transaction");
+        let txn2 = engine
+            .begin_transaction(WriteBatchOptions::default())
+            .expect("failed to begin transaction");
+        assert_eq!(get_test_value(2), txn1.get(get_test_key(1)).unwrap());
+        assert_eq!(get_test_value(3), txn2.get(get_test_key(2)).unwrap());
+        assert!(engine.put(get_test_key(2), Bytes::from("changed")).is_ok());
+        assert!(engine.merge().is_ok());
+
+        // 没有被修改过的 key 不会冲突
+        assert!(txn1.put(get_test_key(1), Bytes::from("txn1")).is_ok());
+        assert!(txn1.commit().is_ok());
+        assert_eq!(Bytes::from("txn1"), engine.get(get_test_key(1)).unwrap());
+
+        // merge 之前被修改过的 key 仍然冲突
+        assert!(txn2.put(get_test_key(2), Bytes::from("txn2")).is_ok());
+        assert_eq!(Errors::TransactionConflict, txn2.commit().err().unwrap());
+        assert_eq!(Bytes::from("changed"), engine.get(get_test_key(2)).unwrap());
+
+        // 删除测试的文件夹
+        std::mem::drop(txn1);
+        std::mem::drop(txn2);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+}
@@ -0,0 +1,19 @@
+use std::ops::Bound;
+
+/// 判断 key 是否不小于下界
+pub(crate) fn above_lower_bound(key: &[u8], lower: &Bound<Vec<u8>>) -> bool {
+    match lower {
+        Bound::Included(lower) => key >= lower.as_slice(),
+        Bound::Excluded(lower) => key > lower.as_slice(),
+        Bound::Unbounded => true,
+    }
+}
+
+/// 判断 key 是否不大于上界
+pub(crate) fn below_upper_bound(key: &[u8], upper: &Bound<Vec<u8>>) -> bool {
+    match upper {
+        Bound::Included(upper) => key <= upper.as_slice(),
+        Bound::Excluded(upper) => key < upper.as_slice(),
+        Bound::Unbounded => true,
+    }
+}
@@ -1,2 +1,3 @@
+pub mod bound;
 pub mod file;
 pub mod rand_kv;
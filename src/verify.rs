@@ -0,0 +1,487 @@
+use std::collections::HashMap;
+
+use crate::{
+    batch::parse_log_record_key,
+    data::{
+        cipher::Cipher,
+        data_file::{DataFile, HINT_FILE_NAME},
+        log_record::{decode_log_record_pos, LogRecordPos, LogRecordType, ReadLogRecord},
+    },
+    db::{lock_data_dir, Engine},
+    error::{Errors, Result},
+    index::Indexer,
+    options::{IOType, IndexType, IteratorOptions, Options},
+    repair::{list_data_file_ids, open_data_file, scan_data_file},
+};
+
+/// 校验过程中发现的问题
+#[derive(Debug, Clone, PartialEq)]
+pub enum VerifyIssue {
+    /// 数据文件的头部无效，或者头部中的文件 id 和文件名不一致
+    InvalidDataFileHeader { file_id: u32 },
+    /// 无法通过 crc 校验的数据区域
+    CorruptedRecord {
+        file_id: u32,
+        offset: u64,
+        size: u64,
+    },
+    /// 索引指向的位置不是一条有效的记录，或者记录的 key 不一致
+    InvalidIndexEntry {
+        keyspace: String,
+        key: Vec<u8>,
+        file_id: u32,
+        offset: u64,
+    },
+    /// hint 文件中的位置信息和数据文件不一致
+    HintMismatch {
+        keyspace: String,
+        key: Vec<u8>,
+        file_id: u32,
+        offset: u64,
+    },
+    /// 引擎中累计的可回收空间和重新计算的结果不一致
+    ReclaimSizeMismatch { recorded: usize, computed: usize },
+}
+
+/// 数据完整性校验的结果
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct VerifyReport {
+    /// 校验的数据文件数量
+    pub data_files: usize,
+    /// 通过 crc 校验的记录数量
+    pub records: usize,
+    /// 校验的索引条目数量，包括所有的 keyspace
+    pub index_entries: usize,
+    /// 校验的 hint 文件条目数量
+    pub hint_entries: usize,
+    /// 引擎中累计的可回收空间
+    pub reclaim_size: usize,
+    /// 根据数据文件和索引重新计算的可回收空间
+    pub computed_reclaim_size: usize,
+    /// 发现的问题，为空表示数据完整
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl VerifyReport {
+    /// 是否没有发现任何问题
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl Engine {
+    /// 校验数据的完整性
+    /// 检查所有记录的 crc、索引和 hint 文件指向的记录，以及累计的可回收空间
+    /// 旧的数据文件不会再被修改，只有记录活跃文件写到的位置和索引中的位置信息时会阻塞写入
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let _merge_lock = match self.merging_lock.try_lock() {
+            Some(lock) => lock,
+            None => return Err(Errors::MergeInProgress),
+        };
+        let mut report = VerifyReport::default();
+        let mut total_size = 0;
+
+        // 持有 merge 锁时旧的数据文件不会被替换，单独打开进行校验
+        let mut sealed_ids: Vec<u32> = self.older_files.read().keys().copied().collect();
+        sealed_ids.sort();
+        let mut files = HashMap::new();
+        for file_id in sealed_ids.iter().copied() {
+            let data_file = self.open_verify_file(file_id)?;
+            scan_file(
+                &data_file,
+                file_id,
+                data_file.file_size(),
+                &mut report,
+                &mut total_size,
+            )?;
+            files.insert(file_id, data_file);
+        }
+
+        // 只在持有锁时记录活跃文件写到的位置以及索引中的位置信息，之后不阻塞写入
+        let (mut file_sizes, entries, reclaim_size) = {
+            let _lock = self.batch_commit_lock.lock();
+            let active_file = self.active_file.read();
+            let mut file_sizes: Vec<(u32, u64)> = self
+                .older_files
+                .read()
+                .iter()
+                .filter(|(id, _)| !sealed_ids.contains(id))
+                .map(|(id, file)| (*id, file.file_size()))
+                .collect();
+            file_sizes.push((active_file.get_file_id(), active_file.get_write_off()));
+
+            let mut entries = index_entries("", self.index.as_ref());
+            for (name, index) in self.keyspaces.read().iter() {
+                entries.extend(index_entries(name, index.as_ref()));
+            }
+            let reclaim_size = self.reclaim_size.load(std::sync::atomic::Ordering::SeqCst);
+            (file_sizes, entries, reclaim_size)
+        };
+
+        // 校验之前的活跃文件，以及校验期间新切换出来的旧文件，之后追加写入的数据不校验
+        file_sizes.sort();
+        for (file_id, file_size) in file_sizes {
+            if file_size == 0 {
+                continue;
+            }
+            let data_file = self.open_verify_file(file_id)?;
+            scan_file(&data_file, file_id, file_size, &mut report, &mut total_size)?;
+            files.insert(file_id, data_file);
+        }
+
+        // 校验索引指向的记录
+        let live_size = verify_index(&files, &entries, &mut report);
+
+        // 校验 hint 文件指向的记录
+        if self.options.dir_path.join(HINT_FILE_NAME).is_file() {
+            verify_hint_file(&files, &self.options, self.cipher.clone(), &mut report)?;
+        }
+
+        // B+ 树索引重启之后不会重新计算可回收空间，不做比较
+        report.reclaim_size = reclaim_size;
+        report.computed_reclaim_size = total_size.saturating_sub(live_size) as usize;
+        if self.options.index_type != IndexType::BPlusTree
+            && report.reclaim_size != report.computed_reclaim_size
+        {
+            report.issues.push(VerifyIssue::ReclaimSizeMismatch {
+                recorded: report.reclaim_size,
+                computed: report.computed_reclaim_size,
+            });
+        }
+
+        Ok(report)
+    }
+
+    /// 单独打开一个数据文件用于校验，不影响引擎中正在使用的文件
+    fn open_verify_file(&self, file_id: u32) -> Result<DataFile> {
+        DataFile::new(
+            self.options.dir_path.clone(),
+            file_id,
+            IOType::StandardFIO,
+            self.cipher.clone(),
+        )
+    }
+}
+
+/// 离线校验 options.dir_path 中的数据
+/// 先检查所有数据文件的 crc，数据有损坏时无法打开数据库，只返回数据文件的校验结果
+pub fn verify(options: Options) -> Result<VerifyReport> {
+    let dir_path = options.dir_path.clone();
+    if !dir_path.is_dir() {
+        return Err(Errors::FailedReadDatabaseDir);
+    }
+
+    let lock_file = lock_data_dir(&dir_path, true)?;
+    let cipher = Cipher::new(&options.encryption);
+    let mut report = VerifyReport::default();
+    let file_ids = list_data_file_ids(&dir_path)?;
+    for file_id in file_ids.iter().copied() {
+        let (data_file, header_ok) = open_data_file(&dir_path, file_id, cipher.clone())?;
+        if !header_ok {
+            report
+                .issues
+                .push(VerifyIssue::InvalidDataFileHeader { file_id });
+        }
+
+        let file_size = data_file.file_size();
+        scan_file(&data_file, file_id, file_size, &mut report, &mut 0)?;
+    }
+    if let Some(lock_file) = lock_file {
+        lock_file.unlock().unwrap();
+    }
+    if !report.is_ok() || file_ids.is_empty() {
+        return Ok(report);
+    }
+
+    // 只读打开，不应用 merge 的结果也不启动自动 merge，不修改数据目录中的文件
+    let engine = Engine::open_read_only(options)?;
+    engine.verify()
+}
+
+/// 校验数据文件中 file_size 之前所有记录的 crc，并累计记录占据的空间大小
+fn scan_file(
+    data_file: &DataFile,
+    file_id: u32,
+    file_size: u64,
+    report: &mut VerifyReport,
+    total_size: &mut u64,
+) -> Result<()> {
+    report.data_files += 1;
+    scan_data_file(
+        data_file,
+        file_size,
+        |_, size| {
+            report.records += 1;
+            *total_size += size;
+            Ok(())
+        },
+        |offset, size| {
+            report.issues.push(VerifyIssue::CorruptedRecord {
+                file_id,
+                offset,
+                size,
+            })
+        },
+    )
+}
+
+/// 取出索引中全部的位置信息
+fn index_entries(keyspace: &str, index: &dyn Indexer) -> Vec<(String, Vec<u8>, LogRecordPos)> {
+    let mut entries = Vec::new();
+    let mut index_iter = index.iterator(IteratorOptions::default());
+    while let Some((key, pos)) = index_iter.next() {
+        entries.push((keyspace.to_string(), key.clone(), *pos));
+    }
+    entries
+}
+
+/// 校验索引中的每个条目，返回有效数据占据的空间大小
+fn verify_index(
+    files: &HashMap<u32, DataFile>,
+    entries: &[(String, Vec<u8>, LogRecordPos)],
+    report: &mut VerifyReport,
+) -> u64 {
+    let mut live_size = 0;
+    for (keyspace, key, pos) in entries {
+        report.index_entries += 1;
+        live_size += pos.size as u64;
+        if !is_record_at(files, keyspace, key, pos) {
+            report.issues.push(VerifyIssue::InvalidIndexEntry {
+                keyspace: keyspace.clone(),
+                key: key.clone(),
+                file_id: pos.file_id,
+                offset: pos.offset,
+            });
+        }
+    }
+    live_size
+}
+
+/// 校验 hint 文件中的每个条目
+fn verify_hint_file(
+    files: &HashMap<u32, DataFile>,
+    options: &Options,
+    cipher: Option<Cipher>,
+    report: &mut VerifyReport,
+) -> Result<()> {
+    let hint_file = DataFile::new_hint_file(options.dir_path.clone(), cipher)?;
+    let mut offset = 0;
+    loop {
+        let (record, size) = match hint_file.read_log_record(offset) {
+            Ok(result) => (result.record, result.size),
+            Err(Errors::ReadDataFileEOF) => break,
+            Err(e) => return Err(e),
+        };
+
+        report.hint_entries += 1;
+        let keyspace = String::from_utf8_lossy(&record.keyspace).to_string();
+        let pos = decode_log_record_pos(record.value);
+        if !is_record_at(files, &keyspace, &record.key, &pos) {
+            report.issues.push(VerifyIssue::HintMismatch {
+                keyspace,
+                key: record.key,
+                file_id: pos.file_id,
+                offset: pos.offset,
+            });
+        }
+        offset += size;
+    }
+    Ok(())
+}
+
+/// 判断位置信息指向的是否是 keyspace 中 key 对应的有效数据
+fn is_record_at(
+    files: &HashMap<u32, DataFile>,
+    keyspace: &str,
+    key: &[u8],
+    pos: &LogRecordPos,
+) -> bool {
+    let result = match files.get(&pos.file_id) {
+        Some(file) => file.read_log_record(pos.offset),
+        None => return false,
+    };
+    match result {
+        Ok(ReadLogRecord { record, size }) => {
+            size == pos.size as u64
+                && record.rec_type == LogRecordType::Normal
+                && record.keyspace == keyspace.as_bytes()
+                && parse_log_record_key(record.key).0 == key
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use bytes::Bytes;
+
+    use crate::{
+        data::data_file::get_data_file_name,
+        options::WriteBatchOptions,
+        util::rand_kv::{get_test_key, get_test_value},
+    };
+
+    use super::*;
+
+    /// 目录中每个文件的名称、大小和修改时间
+    fn dir_state(dir_path: &PathBuf) -> Vec<(std::ffi::OsString, u64, std::time::SystemTime)> {
+        let mut state: Vec<_> = std::fs::read_dir(dir_path)
+            .unwrap()
+            .map(|entry| {
+                let meta = entry.as_ref().unwrap().metadata().unwrap();
+                (
+                    entry.unwrap().file_name(),
+                    meta.len(),
+                    meta.modified().unwrap(),
+                )
+            })
+            .collect();
+        state.sort();
+        state
+    }
+
+    #[test]
+    fn test_engine_verify() {
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-verify"),
+            data_file_size: 64 * 1024,
+            data_file_merge_ratio: 0 as f32,
+            mmap_at_startup: false,
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..1000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        for i in 0..100 {
+            assert!(engine.put(get_test_key(i), get_test_value(i + 1)).is_ok());
+            assert!(engine.delete(get_test_key(i + 100)).is_ok());
+        }
+        let users = engine.keyspace("users").expect("failed to open keyspace");
+        assert!(users.put(get_test_key(1), Bytes::from("user")).is_ok());
+        let wb = engine
+            .new_write_batch(WriteBatchOptions::default())
+            .expect("failed to create write batch");
+        assert!(wb.put(get_test_key(2000), get_test_value(2000)).is_ok());
+        assert!(wb.delete(get_test_key(300)).is_ok());
+        assert!(wb.delete(get_test_key(5000)).is_ok());
+        assert!(wb.commit().is_ok());
+
+        let report = engine.verify().expect("failed to verify");
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(901, report.index_entries);
+        assert!(report.reclaim_size > 0);
+        std::mem::drop(users);
+
+        // merge 之后校验 hint 文件
+        assert!(engine.merge().is_ok());
+        std::mem::drop(engine);
+
+        // 离线校验不修改数据目录，也不处理 merge 目录
+        let merge_path = PathBuf::from("/tmp/bitcask-rs-verify-merge");
+        std::fs::create_dir_all(&merge_path).unwrap();
+        let state = dir_state(&opts.dir_path);
+        let report = verify(opts.clone()).expect("failed to verify");
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(901, report.hint_entries);
+        assert_eq!(901, report.index_entries);
+        assert_eq!(state, dir_state(&opts.dir_path));
+        assert!(merge_path.is_dir());
+        std::fs::remove_dir_all(&merge_path).unwrap();
+
+        // 损坏数据文件
+        let file0 = get_data_file_name(opts.dir_path.clone(), 0);
+        let mut content = std::fs::read(&file0).unwrap();
+        content[1000] ^= 0xFF;
+        std::fs::write(&file0, &content).unwrap();
+        let report = verify(opts.clone()).expect("failed to verify");
+        assert!(!report.is_ok());
+        assert!(matches!(
+            report.issues[0],
+            VerifyIssue::CorruptedRecord { file_id: 0, .. }
+        ));
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_engine_verify_concurrent_writes() {
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-verify-concurrent"),
+            data_file_size: 64 * 1024,
+            mmap_at_startup: false,
+            ..Default::default()
+        };
+        let engine =
+            std::sync::Arc::new(Engine::open(opts.clone()).expect("failed to open engine"));
+        for i in 0..1000 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+
+        // 校验期间的写入不影响校验的结果
+        let writer = {
+            let engine = engine.clone();
+            std::thread::spawn(move || {
+                for i in 0..2000 {
+                    assert!(engine.put(get_test_key(i), get_test_value(i + 1)).is_ok());
+                }
+            })
+        };
+        for _ in 0..5 {
+            let report = engine.verify().expect("failed to verify");
+            assert!(report.is_ok(), "{:?}", report.issues);
+        }
+        writer.join().unwrap();
+        let report = engine.verify().expect("failed to verify");
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(2000, report.index_entries);
+
+        // 删除测试的文件夹
+        std::mem::drop(engine);
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+
+    #[test]
+    fn test_verify_bptree_read_only() {
+        let opts = Options {
+            dir_path: PathBuf::from("/tmp/bitcask-rs-verify-bptree"),
+            index_type: IndexType::BPlusTree,
+            mmap_at_startup: false,
+            ..Default::default()
+        };
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        for i in 0..100 {
+            assert!(engine.put(get_test_key(i), get_test_value(i)).is_ok());
+        }
+        let users = engine.keyspace("users").expect("failed to open keyspace");
+        assert!(users.put(get_test_key(1), Bytes::from("user")).is_ok());
+        std::mem::drop(users);
+        std::mem::drop(engine);
+
+        // 离线校验不会删除事务序列号文件，之后仍然可以使用 WriteBatch
+        let state = dir_state(&opts.dir_path);
+        let report = verify(opts.clone()).expect("failed to verify");
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(101, report.index_entries);
+        assert_eq!(state, dir_state(&opts.dir_path));
+
+        let engine = Engine::open(opts.clone()).expect("failed to open engine");
+        assert!(engine.new_write_batch(WriteBatchOptions::default()).is_ok());
+        std::mem::drop(engine);
+
+        // 不存在的锁文件和 B+ 树索引文件不会被创建
+        std::fs::remove_file(opts.dir_path.join(crate::db::FILE_LOCK_NAME)).unwrap();
+        std::fs::remove_file(opts.dir_path.join("bptree-index")).unwrap();
+        let state = dir_state(&opts.dir_path);
+        let report = verify(opts.clone()).expect("failed to verify");
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(1, report.index_entries);
+        assert_eq!(state, dir_state(&opts.dir_path));
+
+        // 删除测试的文件夹
+        std::fs::remove_dir_all(opts.clone().dir_path).expect("failed to remove path");
+    }
+}